@@ -185,7 +185,6 @@ impl OnDisk {
     fn list(path: &Path, suffix: &str) -> io::Result<impl Iterator<Item = PathBuf>> {
         let owned_suffix = suffix.to_owned();
         Ok(fs::read_dir(path)?
-            .into_iter()
             .filter_map(|r| r.ok().map(|d| d.path()))
             .filter(move |p| {
                 p.is_file()
@@ -256,10 +255,10 @@ impl Storage for OnDisk {
 
     fn put(&mut self, val: &[u8]) -> io::Result<Location> {
         if val.len() > u16::MAX as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("value too long (max length is {} bytes)", u16::MAX),
-            ));
+            return Err(io::Error::other(format!(
+                "value too long (max length is {} bytes)",
+                u16::MAX
+            )));
         }
 
         let file_id = FileId(0);
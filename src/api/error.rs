@@ -2,11 +2,52 @@ use std::fmt::Formatter;
 use std::io;
 use std::result;
 
+/// Errors of database operations. Variants are specific enough for a caller to decide whether
+/// an operation may be retried (`IO`, `Busy`), rejected (`KeyTooLarge`, `ReadOnly`) or the
+/// database requires attention (`CorruptPage`, `CycleDetected`, `BadMagic`...).
 #[derive(Debug)]
 pub enum Error {
+    /// Underlying storage failed.
     IO(io::Error),
-    Tree(u32, String),
-    Other(String),
+    /// Entry (key and value) is larger than the maximum entry size for the page size.
+    KeyTooLarge { size: u32, max: u32 },
+    /// Page is referenced but is not present in the file.
+    PageNotFound(u32),
+    /// Page content is inconsistent or failed authentication.
+    CorruptPage { id: u32, reason: String },
+    /// Page references form a cycle (reported for the page holding the reference).
+    CycleDetected(u32),
+    /// File is not a database (header does not start with the magic bytes).
+    BadMagic,
+    /// Database file was written in another (unsupported) format version.
+    UnsupportedVersion(String),
+    /// Setting or feature (codec, cipher, page size, memory mapping) is not supported.
+    Unsupported(String),
+    /// File is encrypted, but no key was provided.
+    KeyRequired,
+    /// Key was provided, but the file is not encrypted.
+    NotEncrypted,
+    /// Provided key does not match the one the file is encrypted with.
+    WrongKey,
+    /// Keys of the database are ordered by another comparator (the recorded name is reported).
+    WrongOrder(String),
+    /// Modification of a database opened in read-only mode.
+    ReadOnly,
+    /// Database is locked by another writer (or by readers, when opening for writing).
+    Locked,
+    /// Bucket of given name does not exist.
+    BucketNotFound(String),
+    /// Bucket of given name already exists.
+    BucketExists(String),
+    /// Index of given name is stored, but its extractor was not registered since the database
+    /// was opened: writes to the indexed tree would leave the index stale.
+    IndexNotRegistered(String),
+    /// Merge operand (or the stored value) is not accepted by the merge operator of given name.
+    BadOperand(String),
+    /// Versions before given sequence number are not retained (beyond the history horizon).
+    Pruned(u64),
+    /// Page cache is borrowed by a reference (e.g. returned by `lookup`) still held by the caller.
+    Busy,
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -15,22 +56,46 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::IO(io) => write!(f, "IO error: '{}'.", io),
-            Error::Tree(id, msg) => write!(f, "Tree error (page: {}): '{}'.", id, msg),
-            Error::Other(msg) => write!(f, "Other error: '{}'.", msg),
+            Error::KeyTooLarge { size, max } => {
+                write!(f, "Entry too large: size={} max={}.", size, max)
+            }
+            Error::PageNotFound(id) => write!(f, "Page not found: {}.", id),
+            Error::CorruptPage { id, reason } => {
+                write!(f, "Corrupt page (page: {}): '{}'.", id, reason)
+            }
+            Error::CycleDetected(id) => write!(f, "Cyclic reference detected (page: {}).", id),
+            Error::BadMagic => write!(f, "Not a database file."),
+            Error::UnsupportedVersion(v) => write!(f, "Unsupported format version: '{}'.", v),
+            Error::Unsupported(what) => write!(f, "Not supported: '{}'.", what),
+            Error::KeyRequired => write!(f, "File is encrypted, key is required."),
+            Error::NotEncrypted => write!(f, "File is not encrypted."),
+            Error::WrongKey => write!(f, "Wrong encryption key."),
+            Error::WrongOrder(name) => write!(f, "Keys are ordered by comparator '{}'.", name),
+            Error::ReadOnly => write!(f, "Database is read-only."),
+            Error::Locked => write!(f, "Database is locked."),
+            Error::BucketNotFound(name) => write!(f, "Bucket not found: '{}'.", name),
+            Error::BucketExists(name) => write!(f, "Bucket already exists: '{}'.", name),
+            Error::IndexNotRegistered(name) => write!(f, "Index not registered: '{}'.", name),
+            Error::BadOperand(name) => {
+                write!(f, "Operand rejected by merge operator '{}'.", name)
+            }
+            Error::Pruned(seq) => write!(f, "Versions before {} are not retained.", seq),
+            Error::Busy => write!(f, "Page cache is busy."),
         }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(io) => Some(io),
+            _ => None,
+        }
+    }
+}
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         Error::IO(e)
     }
 }
-
-impl From<String> for Error {
-    fn from(str: String) -> Self {
-        Error::Other(str)
-    }
-}
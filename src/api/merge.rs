@@ -0,0 +1,123 @@
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// Combination of the value stored under a key with an operand (see `File::merge_value`), applied
+/// within the leaf page during the insert: no lookup is needed on the caller side. Operators
+/// are not persisted, the one to use is provided when the database is opened.
+pub trait MergeOperator {
+    /// Name of the operator (reported in errors).
+    fn name(&self) -> &str;
+
+    /// New value of the key from the current one (none if the key is absent) and an operand,
+    /// or none if the operand (or the current value) is not accepted.
+    fn merge(&self, key: &[u8], current: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Big-endian `u64` counter: the operand is added to the current value (0 if absent),
+/// wrapping around on overflow.
+pub struct U64Add;
+
+/// Operand is appended to the current value (empty if absent).
+pub struct Append;
+
+/// Greater (bytewise) of the current value and the operand.
+pub struct Max;
+
+fn u64_of(val: &[u8]) -> Option<u64> {
+    <[u8; 8]>::try_from(val).ok().map(u64::from_be_bytes)
+}
+
+impl MergeOperator for U64Add {
+    fn name(&self) -> &str {
+        "u64add"
+    }
+
+    fn merge(&self, _: &[u8], current: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        let current = match current {
+            Some(val) => u64_of(val)?,
+            None => 0,
+        };
+        let sum = current.wrapping_add(u64_of(operand)?);
+        Some(sum.to_be_bytes().to_vec())
+    }
+}
+
+impl MergeOperator for Append {
+    fn name(&self) -> &str {
+        "append"
+    }
+
+    fn merge(&self, _: &[u8], current: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        let current = current.unwrap_or_default();
+        let mut val = Vec::with_capacity(current.len() + operand.len());
+        val.extend_from_slice(current);
+        val.extend_from_slice(operand);
+        Some(val)
+    }
+}
+
+impl MergeOperator for Max {
+    fn name(&self) -> &str {
+        "max"
+    }
+
+    fn merge(&self, _: &[u8], current: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        Some(current.unwrap_or_default().max(operand).to_vec())
+    }
+}
+
+/// Built-in merge operator of given name (if any).
+pub fn builtin(name: &str) -> Option<Arc<dyn MergeOperator>> {
+    let all: Vec<Arc<dyn MergeOperator>> = vec![Arc::new(U64Add), Arc::new(Append), Arc::new(Max)];
+    all.into_iter().find(|op| op.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    #[test]
+    fn test_builtin() {
+        for name in ["u64add", "append", "max"] {
+            assert_eq!(builtin(name).unwrap().name(), name);
+        }
+        assert!(builtin("add").is_none());
+    }
+
+    #[test]
+    fn test_u64_add() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut val = None;
+        let mut sum = 0u64;
+        for _ in 0..1000 {
+            let n = rng.gen::<u32>() as u64;
+            val = U64Add.merge(b"key", val.as_deref(), &n.to_be_bytes());
+            sum += n;
+        }
+        assert_eq!(val, Some(sum.to_be_bytes().to_vec()));
+
+        let max = u64::MAX.to_be_bytes();
+        let one = 1u64.to_be_bytes();
+        assert_eq!(U64Add.merge(b"", Some(&max), &one), Some(vec![0u8; 8]));
+        assert_eq!(U64Add.merge(b"", Some(b"x"), &one), None);
+        assert_eq!(U64Add.merge(b"", None, b"x"), None);
+    }
+
+    #[test]
+    fn test_append() {
+        assert_eq!(Append.merge(b"", None, b"a"), Some(b"a".to_vec()));
+        assert_eq!(
+            Append.merge(b"", Some(b"ab"), b"cd"),
+            Some(b"abcd".to_vec())
+        );
+        assert_eq!(Append.merge(b"", Some(b"ab"), b""), Some(b"ab".to_vec()));
+    }
+
+    #[test]
+    fn test_max() {
+        assert_eq!(Max.merge(b"", None, b"a"), Some(b"a".to_vec()));
+        assert_eq!(Max.merge(b"", Some(b"b"), b"a"), Some(b"b".to_vec()));
+        assert_eq!(Max.merge(b"", Some(b"b"), b"ba"), Some(b"ba".to_vec()));
+    }
+}
@@ -1,3 +1,6 @@
-pub(crate) mod error;
+pub mod error;
+pub mod merge;
+pub mod order;
+// Pages are internal: `Page` bounds public types (thus it is `pub`), but cannot be named outside.
 pub(crate) mod page;
-pub(crate) mod tree;
+pub mod tree;
@@ -0,0 +1,189 @@
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// Max length of a comparator name (as recorded in the file header).
+pub const NAME_LEN: usize = 32;
+
+/// Order of keys in a database, selected on creation and recorded in the file header by name:
+/// a database can be opened only with the comparator of the same name. The order must be
+/// total, keys comparing as `Equal` are considered the same key (even if bytes differ).
+pub trait Comparator {
+    /// Unique name of the order (non-empty ASCII, at most `NAME_LEN` bytes).
+    fn name(&self) -> &str;
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Raw byte comparison (default).
+pub struct Bytewise;
+
+/// Big-endian two's complement signed integers of any width (shorter keys are sign-extended),
+/// an empty key is zero.
+pub struct Signed;
+
+/// Big-endian IEEE 754 `f64` in total order (`f64::total_cmp`): negative NaN, -inf, ..., -0.0,
+/// 0.0, ..., inf, NaN. Keys of other length go after all floats, in raw byte order.
+pub struct Float;
+
+/// ASCII case-insensitive order: keys differing only in case of ASCII letters are the same key.
+pub struct NoCase;
+
+impl Comparator for Bytewise {
+    fn name(&self) -> &str {
+        "bytewise"
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+impl Comparator for Signed {
+    fn name(&self) -> &str {
+        "signed"
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let neg = |x: &[u8]| x.first().map(|b| b & 0x80 > 0).unwrap_or_default();
+        match (neg(a), neg(b)) {
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (sign, _) => {
+                let fill = if sign { 0xFF } else { 0x00 };
+                let len = a.len().max(b.len());
+                let extend = |x: &[u8], i: usize| {
+                    let pad = len - x.len();
+                    if i < pad {
+                        fill
+                    } else {
+                        x[i - pad]
+                    }
+                };
+                (0..len)
+                    .map(|i| extend(a, i).cmp(&extend(b, i)))
+                    .find(|o| *o != Ordering::Equal)
+                    .unwrap_or(Ordering::Equal)
+            }
+        }
+    }
+}
+
+impl Comparator for Float {
+    fn name(&self) -> &str {
+        "float"
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let float = |x: &[u8]| <[u8; 8]>::try_from(x).ok().map(f64::from_be_bytes);
+        match (float(a), float(b)) {
+            (Some(x), Some(y)) => x.total_cmp(&y),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => a.cmp(b),
+        }
+    }
+}
+
+impl Comparator for NoCase {
+    fn name(&self) -> &str {
+        "nocase"
+    }
+
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.iter()
+            .map(u8::to_ascii_lowercase)
+            .cmp(b.iter().map(u8::to_ascii_lowercase))
+    }
+}
+
+/// Built-in comparator of given name (if any).
+pub fn builtin(name: &str) -> Option<Arc<dyn Comparator>> {
+    let all: Vec<Arc<dyn Comparator>> = vec![
+        Arc::new(Bytewise),
+        Arc::new(Signed),
+        Arc::new(Float),
+        Arc::new(NoCase),
+    ];
+    all.into_iter().find(|cmp| cmp.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn sorted_by(cmp: &dyn Comparator, mut keys: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        keys.sort_by(|a, b| cmp.cmp(a, b));
+        keys
+    }
+
+    #[test]
+    fn test_builtin() {
+        for name in ["bytewise", "signed", "float", "nocase"] {
+            assert_eq!(builtin(name).unwrap().name(), name);
+        }
+        assert!(builtin("").is_none());
+        assert!(builtin("unknown").is_none());
+    }
+
+    #[test]
+    fn test_signed() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut ints = (0..1000).map(|_| rng.gen::<i64>()).collect::<Vec<_>>();
+        ints.extend([i64::MIN, -1, 0, 1, i64::MAX]);
+        let keys = ints.iter().map(|x| x.to_be_bytes().to_vec()).collect();
+        ints.sort_unstable();
+        let expected = ints
+            .iter()
+            .map(|x| x.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(sorted_by(&Signed, keys), expected);
+
+        // Keys of different width are compared by value.
+        assert_eq!(
+            Signed.cmp(&(-1i16).to_be_bytes(), &(-1i64).to_be_bytes()),
+            Ordering::Equal
+        );
+        assert_eq!(
+            Signed.cmp(&(-2i32).to_be_bytes(), &(1i8).to_be_bytes()),
+            Ordering::Less
+        );
+        assert_eq!(
+            Signed.cmp(&(300i16).to_be_bytes(), &(7i64).to_be_bytes()),
+            Ordering::Greater
+        );
+        assert_eq!(Signed.cmp(b"", &(0i32).to_be_bytes()), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_float() {
+        let floats = [
+            f64::NEG_INFINITY,
+            -1e10,
+            -1.5,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            2.5,
+            f64::INFINITY,
+            f64::NAN,
+        ];
+        let mut keys = floats
+            .iter()
+            .map(|x| x.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        keys.push(b"x".to_vec());
+        let mut shuffled = keys.clone();
+        shuffled.shuffle(&mut StdRng::seed_from_u64(42));
+        assert_eq!(sorted_by(&Float, shuffled), keys);
+    }
+
+    #[test]
+    fn test_nocase() {
+        assert_eq!(NoCase.cmp(b"Hello", b"hELLO"), Ordering::Equal);
+        assert_eq!(NoCase.cmp(b"apple", b"Banana"), Ordering::Less);
+        assert_eq!(NoCase.cmp(b"Zed", b"abc"), Ordering::Greater);
+        assert_eq!(NoCase.cmp(b"ab", b"ABC"), Ordering::Less);
+    }
+}
@@ -1,13 +1,17 @@
+use crate::api::error::Result;
+use crate::api::order::Comparator;
+use std::mem::size_of;
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub(crate) struct Slot {
-    pub(crate) offset: u32,
-    pub(crate) klen: u32,
-    pub(crate) vlen: u32, // if >0 value is stored in the same page as a key (leaf page)
-    pub(crate) page: u32, // if >0 key holds a reference to another page (node page)
+pub struct Slot {
+    pub offset: u32,
+    pub klen: u32,
+    pub vlen: u32, // value stored with the key (subtree entry count for a reference)
+    pub page: u32, // if >0 key holds a reference to another page (node page)
 }
 
 impl Slot {
-    pub(crate) fn new(offset: u32, klen: u32, vlen: u32, page: u32) -> Self {
+    pub fn new(offset: u32, klen: u32, vlen: u32, page: u32) -> Self {
         Self {
             offset,
             klen,
@@ -16,15 +20,26 @@ impl Slot {
         }
     }
 
-    pub(crate) fn empty() -> Self {
+    pub fn empty() -> Self {
         Self::new(0, 0, 0, 0)
     }
 }
 
-pub(crate) trait Page: AsRef<[u8]> + AsMut<[u8]> {
-    fn reserve(capacity: u32) -> Self;
-    fn create(id: u32, cap: u32) -> Self;
+/// Size of the value of a reference entry: number of entries in the referenced subtree (u64).
+pub const COUNT: usize = size_of::<u64>();
 
+/// Decode number of entries stored as a value of a reference entry.
+pub fn get_count(val: &[u8]) -> u64 {
+    let mut buf = [0u8; COUNT];
+    if val.len() == COUNT {
+        buf.copy_from_slice(val);
+    }
+    u64::from_be_bytes(buf)
+}
+
+/// Read-only access to a page: the one owning its buffer (`Page`), or the one borrowing it
+/// (see `Page::map`).
+pub trait View {
     fn id(&self) -> u32;
 
     /// Current page's capacity in bytes.
@@ -48,31 +63,78 @@ pub(crate) trait Page: AsRef<[u8]> + AsMut<[u8]> {
     /// Effectively this is equal to `((len() - HEAD) - free()) * 100 / (len() - HEAD)`.
     fn full(&self) -> u8;
 
+    /// Find a slot with exact match to a given key (if any). Keys of a page are kept in the
+    /// order of given comparator, which must be the same for all calls on the page.
+    fn find(&self, key: &[u8], cmp: &dyn Comparator) -> Option<u32>;
+
+    /// Find a slot with the smallest key greater or equal to a given key.
+    fn ceil(&self, key: &[u8], cmp: &dyn Comparator) -> Option<u32>;
+
+    /// Make an owned copy of all entries in the page: (key, val, page).
+    fn copy(&self) -> Vec<(Vec<u8>, Vec<u8>, u32)>;
+
+    /// Check structural consistency of the page (e.g. after reading it from the disk): capacity,
+    /// slots and payloads within page bounds, keys strictly ascending in given order. Any accessor
+    /// can be used safely on a page that passed the check. Reports the first problem found as
+    /// `CorruptPage`.
+    fn check(&self, cmp: &dyn Comparator) -> Result<()>;
+
+    /// Number of entries stored in the subtree referenced by given slot.
+    fn count(&self, idx: u32) -> u64 {
+        get_count(self.val(idx))
+    }
+
+    /// Number of entries stored in the subtree of the page: own entries of a leaf page,
+    /// sum of referenced subtrees' entries for a node page.
+    fn total(&self) -> u64 {
+        match self.slot(0) {
+            Some(slot) if slot.page > 0 => (0..self.size()).map(|idx| self.count(idx)).sum(),
+            _ => self.size() as u64,
+        }
+    }
+}
+
+pub trait Page: View + AsRef<[u8]> + AsMut<[u8]> {
+    /// Read-only page borrowing its buffer.
+    type Mapped<'a>: View;
+
+    fn reserve(capacity: u32) -> Self;
+    fn create(id: u32, cap: u32) -> Self;
+
+    /// Read-only page over given buffer (e.g. borrowed from the memory mapping) without copying
+    /// it. Accessors may panic unless the page passed `check`.
+    fn map(buf: &[u8]) -> Self::Mapped<'_>;
+
     /// Check if payload (key and value) of given size can fit the page,
     /// taking into account necessary housekeeping overhead.
     fn fits(&self, len: u32) -> bool;
 
-    /// Find a slot with exact match to a given key (if any).
-    fn find(&self, key: &[u8]) -> Option<u32>;
-
-    /// Find a slot with the smallest key greater or equal to a given key.
-    fn ceil(&self, key: &[u8]) -> Option<u32>;
+    /// Max size of an entry (key and value) in a page of given capacity: two entries of such
+    /// size fit into an empty page, thus a page holding the entry can always be split.
+    fn max_entry(cap: u32) -> u32;
 
     /// Put a key-value pair into the page.
     /// Returns slot index if operation was successful.
-    fn put_val(&mut self, key: &[u8], val: &[u8]) -> Option<u32>;
+    fn put_val(&mut self, key: &[u8], val: &[u8], cmp: &dyn Comparator) -> Option<u32>;
 
-    /// Put a key-page-reference pair into the page.
-    /// Returns slot index if operation was successful.
-    fn put_ref(&mut self, key: &[u8], page: u32) -> Option<u32>;
+    /// Put a key-page-reference pair into the page, along with the number of entries stored
+    /// in the referenced subtree. Returns slot index if operation was successful.
+    fn put_ref(&mut self, key: &[u8], page: u32, count: u64, cmp: &dyn Comparator) -> Option<u32>;
 
     /// Remove the slot of a given index and return key-value stored there.
     /// Automatic defragmentation is performed to maximize available capacity.
     fn remove(&mut self, idx: u32);
 
-    /// Make an owned copy of all entries in the page: (key, val, page).
-    fn copy(&self) -> Vec<(Vec<u8>, Vec<u8>, u32)>;
-
     /// Fill whole page (but header) with zeroes.
     fn clear(&mut self);
+
+    /// Update (in place) the number of entries stored in the subtree referenced by given slot.
+    fn set_count(&mut self, idx: u32, count: u64) {
+        if let Some(slot) = self.slot(idx) {
+            if slot.page > 0 && slot.vlen as usize == COUNT {
+                let at = (slot.offset + slot.klen) as usize;
+                self.as_mut()[at..(at + COUNT)].copy_from_slice(&count.to_be_bytes());
+            }
+        }
+    }
 }
@@ -1,57 +1,105 @@
 use crate::api::error::Result;
 use crate::api::page::Page;
+use rand::Rng;
 use std::cell::{Ref, RefMut};
+use std::ops::Bound;
+
+/// Estimated amount of data stored within a range of keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Estimate {
+    /// Number of keys in the range (exact: taken from subtree entry counts).
+    pub keys: u64,
+    /// Bytes taken by the entries in pages (including page housekeeping): the number of keys
+    /// multiplied by the average entry size in the leaf pages at both ends of the range.
+    pub bytes: u64,
+}
 
-pub(crate) trait Tree<P: Page> {
-    fn lookup(&self, key: &[u8]) -> Result<Option<Ref<[u8]>>>;
+pub trait Tree {
+    fn lookup(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>>;
     fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<()>;
     fn remove(&mut self, key: &[u8]) -> Result<()>;
 
-    fn is_empty(&self) -> bool;
+    /// Store an entry, returning the value it replaced (none if the key was absent): the old
+    /// value is read within the same descent of the tree, no `lookup` is needed.
+    fn replace(&mut self, key: &[u8], val: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Remove an entry, returning its value (none if the key was absent).
+    fn take(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    fn is_empty(&self) -> Result<bool>;
+
+    /// Get number of entries stored in the tree.
+    fn len(&self) -> Result<u64>;
+
+    /// Get number of keys strictly lesser than given one (its position, if the key is present).
+    fn rank(&self, key: &[u8]) -> Result<u64>;
+
+    /// Get key at given position (0-based, in ascending order), or none if out of range.
+    fn nth(&self, idx: u64) -> Result<Option<Ref<'_, [u8]>>>;
+
+    /// Get number of keys within given (lower, upper) bounds.
+    fn count_range(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<u64>;
+
+    /// Estimate amount of data within given (lower, upper) bounds, without scanning the range:
+    /// only pages on the paths to both bounds are accessed.
+    fn approximate_size(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<Estimate>;
+
+    /// Get a uniformly random key (or none if the tree is empty): random descent from the root
+    /// choosing each subtree with probability proportional to its number of entries.
+    fn sample<R: Rng>(&self, rng: &mut R) -> Result<Option<Ref<'_, [u8]>>>;
 
     /// Get lowest/smallest key stored in the tree, or none if tree is empty.
-    fn min(&self) -> Result<Option<Ref<[u8]>>>;
+    fn min(&self) -> Result<Option<Ref<'_, [u8]>>>;
 
     /// Get highest/biggest key stored in the tree, or none if tree is empty.
-    fn max(&self) -> Result<Option<Ref<[u8]>>>;
+    fn max(&self) -> Result<Option<Ref<'_, [u8]>>>;
 
     /// Get smallest key that is strictly greater than given one, if any.
-    fn above(&self, key: &[u8]) -> Result<Option<Ref<[u8]>>>;
+    fn above(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>>;
 
     /// Get biggest key that is strictly lesser than given one, if any.
-    fn below(&self, key: &[u8]) -> Result<Option<Ref<[u8]>>>;
+    fn below(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>>;
+
+    /// Flush all pages marked as dirty to the disk.
+    fn flush(&self) -> Result<()>;
 
+    /// Dump tree representation to a string where keys values are presented as hex strings.
+    /// Intended to use for debugging purposes only.
+    fn dump(&self) -> String;
+}
+
+/// Page-level access to the tree, used to maintain its structure. Not exposed: changing pages
+/// directly may break subtree counts, separators or the free list.
+pub(crate) trait Pages<P: Page> {
     /// Get an immutable reference to a root page.
-    fn root(&self) -> Ref<P>;
+    #[cfg(test)]
+    fn root(&self) -> Result<Ref<'_, P>>;
 
-    /// Get an immutable reference to a page having given id, if such page exists.
-    fn page(&self, id: u32) -> Option<Ref<P>>;
+    /// Get an immutable reference to a page having given id (loading it if necessary).
+    fn page(&self, id: u32) -> Result<Ref<'_, P>>;
 
     /// Get a mutable reference to a root page.
-    fn root_mut(&self) -> RefMut<P>;
+    #[cfg(test)]
+    fn root_mut(&self) -> Result<RefMut<'_, P>>;
 
-    /// Get a mutable reference to a page having given id, if such page exists.
-    fn page_mut(&self, id: u32) -> Option<RefMut<P>>;
+    /// Get a mutable reference to a page having given id (loading it if necessary).
+    fn page_mut(&self, id: u32) -> Result<RefMut<'_, P>>;
 
     /// Mark page with given id as dirty and thus eligible for flushing to the disk.
     fn mark(&self, id: u32);
 
-    /// Flush all pages marked as dirty to the disk.
-    fn flush(&self) -> Result<()>;
-
     /// Reserve the provided page id - such id will never be returned by `next_id` until freed.
     fn next_id(&self) -> Result<u32>;
 
     /// Un-reserve the provided page id making it available for future via `next_id`.
     fn free_id(&self, id: u32);
 
-    /// Split given page into two subpages containing ~equal number of entries.
-    fn split(&self, id: u32, parent_id: u32) -> Result<()>;
+    /// Split given page into two subpages containing ~equal number of entries. A page without
+    /// a parent (`parent_id` is 0) is a root: it keeps its id and references both subpages.
+    /// Returns false (nothing is changed) if the parent has no room for one more separator.
+    fn split(&self, id: u32, parent_id: u32) -> Result<bool>;
 
     /// Merge page `src_id` into page `dst_id`, effectively removing page `src_id`.
-    fn merge(&self, src_id: u32, dst_id: u32) -> Result<()>;
-
-    /// Dump tree representation to a string where keys values are presented as hex strings.
-    /// Intended to use for debugging purposes only.
-    fn dump(&self) -> String;
+    /// Returns false (nothing is changed) if the entries of both pages do not fit into one.
+    fn merge(&self, src_id: u32, dst_id: u32) -> Result<bool>;
 }
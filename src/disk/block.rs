@@ -1,10 +1,15 @@
-use crate::api::page::{Page, Slot};
+use crate::api::error::{Error, Result};
+use crate::api::order::Comparator;
+use crate::api::page::{Page, Slot, View, COUNT};
 use crate::util::bsearch::bsearch;
 use bytes::{BufMut, BytesMut};
+use std::cmp::Ordering;
 use std::mem::size_of;
 
-pub(crate) struct Block {
-    buf: BytesMut,
+/// Page layout: header, slots, free space, then packed (key, value) payloads at the end of the
+/// page. The buffer is owned by a page that can be changed, or borrowed by a read-only one.
+pub struct Block<B = BytesMut> {
+    buf: B,
 }
 
 impl AsMut<[u8]> for Block {
@@ -25,25 +30,25 @@ const SIZE_OFFSET: usize = 8;
 const RESERVED: u32 = 0xC0DE1542;
 
 impl Block {
-    fn put_entry(&mut self, key: &[u8], val: &[u8], page: u32) -> Option<u32> {
+    fn put_entry(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        page: u32,
+        cmp: &dyn Comparator,
+    ) -> Option<u32> {
         if !self.fits((key.len() + val.len()) as u32) {
             return None;
         }
 
-        let ceil_opt = self.ceil(key);
-        if let Some(idx) = &ceil_opt {
-            if self.key(*idx) == key {
-                let n = self.size() - 1;
-                put_size(&mut self.buf, n);
-                self.remove(*idx);
-            }
+        if let Some(idx) = self.find(key, cmp) {
+            self.remove(idx);
         }
 
         let size = self.size();
-        let idx = self.ceil(key).unwrap_or_else(|| size);
+        let idx = self.ceil(key, cmp).unwrap_or(size);
 
         let mut slots = (0..size)
-            .into_iter()
             .filter_map(|idx| self.slot(idx))
             .collect::<Vec<_>>();
 
@@ -75,45 +80,38 @@ impl Block {
     }
 }
 
-impl Page for Block {
-    fn reserve(capacity: u32) -> Self {
-        let mut buf = BytesMut::with_capacity(capacity as usize);
-        buf.extend_from_slice(&vec![0u8; capacity as usize]);
-        Self { buf }
-    }
-
-    fn create(id: u32, cap: u32) -> Self {
-        let mut buf = BytesMut::with_capacity(cap as usize);
-        buf.put_u32(id);
-        buf.put_u32(cap);
-        buf.put_u32(0);
-        buf.put_u32(RESERVED);
-        assert_eq!(buf.len(), HEAD);
-        buf.extend_from_slice(&vec![0u8; cap as usize - HEAD]);
-        Self { buf }
+impl<B: AsRef<[u8]>> Block<B> {
+    fn corrupt(&self, reason: String) -> Error {
+        Error::CorruptPage {
+            id: self.id(),
+            reason,
+        }
     }
+}
 
+impl<B: AsRef<[u8]>> View for Block<B> {
     fn id(&self) -> u32 {
-        get_u32(&self.buf, ID_OFFSET)
+        get_u32(self.buf.as_ref(), ID_OFFSET)
     }
 
     fn cap(&self) -> u32 {
-        get_u32(&self.buf, CAP_OFFSET)
+        get_u32(self.buf.as_ref(), CAP_OFFSET)
     }
 
     fn size(&self) -> u32 {
-        get_u32(&self.buf, SIZE_OFFSET)
+        get_u32(self.buf.as_ref(), SIZE_OFFSET)
     }
 
     fn slot(&self, idx: u32) -> Option<Slot> {
         if idx >= self.size() {
             return None;
         }
+        let buf = self.buf.as_ref();
         let pos = HEAD + U32 * 4 * idx as usize;
-        let offset = get_u32(&self.buf, pos);
-        let klen = get_u32(&self.buf, pos + 4);
-        let vlen = get_u32(&self.buf, pos + 8);
-        let page = get_u32(&self.buf, pos + 12);
+        let offset = get_u32(buf, pos);
+        let klen = get_u32(buf, pos + 4);
+        let vlen = get_u32(buf, pos + 8);
+        let page = get_u32(buf, pos + 12);
         Some(Slot::new(offset, klen, vlen, page))
     }
 
@@ -130,7 +128,7 @@ impl Page for Block {
             .map(|slot| {
                 let at = slot.offset as usize;
                 let to = at + slot.klen as usize;
-                &self.buf[at..to]
+                &self.buf.as_ref()[at..to]
             })
             .unwrap_or_default()
     }
@@ -140,7 +138,7 @@ impl Page for Block {
             .map(|slot| {
                 let at = slot.offset as usize + slot.klen as usize;
                 let to = at + slot.vlen as usize;
-                &self.buf[at..to]
+                &self.buf.as_ref()[at..to]
             })
             .unwrap_or_default()
     }
@@ -152,7 +150,6 @@ impl Page for Block {
         }
         let lo = HEAD as u32 + size * SLOT as u32;
         let hi = (0..size)
-            .into_iter()
             .filter_map(|idx| self.slot(idx))
             .map(|slot| slot.offset)
             .min()
@@ -163,48 +160,123 @@ impl Page for Block {
     }
 
     fn full(&self) -> u8 {
-        let len = self.cap() - HEAD as u32;
-        ((len - self.free()) * 100 / len) as u8
-    }
-
-    fn fits(&self, len: u32) -> bool {
-        self.free() >= len + SLOT as u32
+        let len = (self.cap() - HEAD as u32) as u64;
+        ((len - self.free() as u64) * 100 / len) as u8
     }
 
-    fn find(&self, key: &[u8]) -> Option<u32> {
+    fn find(&self, key: &[u8], cmp: &dyn Comparator) -> Option<u32> {
         let n = self.size();
         if n == 0 {
             return None;
         }
 
-        let k = bsearch(key, 0, n - 1, |i| self.key(i));
-        if self.key(k) == key {
+        let k = bsearch(0, n - 1, |i| cmp.cmp(key, self.key(i)));
+        if cmp.cmp(self.key(k), key) == Ordering::Equal {
             Some(k)
         } else {
             None
         }
     }
 
-    fn ceil(&self, key: &[u8]) -> Option<u32> {
+    fn ceil(&self, key: &[u8], cmp: &dyn Comparator) -> Option<u32> {
         let n = self.size();
         if n == 0 {
             return None;
         }
 
-        let k = bsearch(key, 0, n - 1, |i| self.key(i));
-        if self.key(k) >= key {
+        let k = bsearch(0, n - 1, |i| cmp.cmp(key, self.key(i)));
+        if cmp.cmp(self.key(k), key) != Ordering::Less {
             Some(k)
         } else {
             None
         }
     }
 
-    fn put_val(&mut self, key: &[u8], val: &[u8]) -> Option<u32> {
-        self.put_entry(key, val, 0)
+    fn copy(&self) -> Vec<(Vec<u8>, Vec<u8>, u32)> {
+        (0..self.size())
+            .filter_map(|idx| self.slot(idx))
+            .map(|slot| {
+                (
+                    get_key(self.buf.as_ref(), &slot).to_vec(),
+                    get_val(self.buf.as_ref(), &slot).to_vec(),
+                    slot.page,
+                )
+            })
+            .collect::<Vec<_>>()
+    }
+
+    fn check(&self, cmp: &dyn Comparator) -> Result<()> {
+        let cap = self.cap() as u64;
+        if cap != self.buf.as_ref().len() as u64 {
+            return Err(self.corrupt(format!("Capacity mismatch: {}", cap)));
+        }
+        let lo = HEAD as u64 + self.size() as u64 * SLOT as u64;
+        if lo > cap {
+            return Err(self.corrupt(format!("Too many slots: {}", self.size())));
+        }
+
+        let mut used = 0u64;
+        for (idx, slot) in (0..self.size()).filter_map(|idx| self.slot(idx).map(|s| (idx, s))) {
+            let len = slot.klen as u64 + slot.vlen as u64;
+            if (slot.offset as u64) < lo || slot.offset as u64 + len > cap {
+                return Err(self.corrupt(format!("Slot out of bounds: {}", idx)));
+            }
+            if slot.page > 0 && slot.vlen != COUNT as u32 {
+                return Err(self.corrupt(format!("Reference without count: {}", idx)));
+            }
+            used += len;
+        }
+        if lo + used > cap {
+            return Err(self.corrupt("Slots overlap".to_string()));
+        }
+
+        for idx in 1..self.size() {
+            if cmp.cmp(self.key(idx - 1), self.key(idx)) != Ordering::Less {
+                return Err(self.corrupt(format!("Keys out of order: {}", idx)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Page for Block {
+    type Mapped<'a> = Block<&'a [u8]>;
+
+    fn reserve(capacity: u32) -> Self {
+        let mut buf = BytesMut::with_capacity(capacity as usize);
+        buf.extend_from_slice(&vec![0u8; capacity as usize]);
+        Self { buf }
+    }
+
+    fn create(id: u32, cap: u32) -> Self {
+        let mut buf = BytesMut::with_capacity(cap as usize);
+        buf.put_u32(id);
+        buf.put_u32(cap);
+        buf.put_u32(0);
+        buf.put_u32(RESERVED);
+        assert_eq!(buf.len(), HEAD);
+        buf.extend_from_slice(&vec![0u8; cap as usize - HEAD]);
+        Self { buf }
+    }
+
+    fn map(buf: &[u8]) -> Block<&[u8]> {
+        Block { buf }
     }
 
-    fn put_ref(&mut self, key: &[u8], page: u32) -> Option<u32> {
-        self.put_entry(key, &[], page)
+    fn fits(&self, len: u32) -> bool {
+        self.free() >= len + SLOT as u32
+    }
+
+    fn max_entry(cap: u32) -> u32 {
+        (cap.saturating_sub(HEAD as u32) / 2).saturating_sub(SLOT as u32)
+    }
+
+    fn put_val(&mut self, key: &[u8], val: &[u8], cmp: &dyn Comparator) -> Option<u32> {
+        self.put_entry(key, val, 0, cmp)
+    }
+
+    fn put_ref(&mut self, key: &[u8], page: u32, count: u64, cmp: &dyn Comparator) -> Option<u32> {
+        self.put_entry(key, &count.to_be_bytes(), page, cmp)
     }
 
     fn remove(&mut self, idx: u32) {
@@ -214,7 +286,6 @@ impl Page for Block {
         }
 
         let mut slots = (0..size)
-            .into_iter()
             .filter_map(|idx| self.slot(idx))
             .collect::<Vec<_>>();
 
@@ -254,20 +325,6 @@ impl Page for Block {
             .for_each(|(idx, slot)| put_slot(&mut self.buf, idx as u32, &slot));
     }
 
-    fn copy(&self) -> Vec<(Vec<u8>, Vec<u8>, u32)> {
-        (0..self.size())
-            .into_iter()
-            .filter_map(|idx| self.slot(idx))
-            .map(|slot| {
-                (
-                    get_key(&self.buf, &slot).to_vec(),
-                    get_val(&self.buf, &slot).to_vec(),
-                    slot.page,
-                )
-            })
-            .collect::<Vec<_>>()
-    }
-
     fn clear(&mut self) {
         let len = self.cap() as usize;
         let mut tmp = BytesMut::with_capacity(len);
@@ -285,17 +342,17 @@ const U32: usize = size_of::<u32>();
 const SLOT: usize = size_of::<Slot>();
 const HEAD: usize = 4 * U32; // page header: id, length, size, reserved
 
-fn get_u32(buf: &BytesMut, pos: usize) -> u32 {
+fn get_u32(buf: &[u8], pos: usize) -> u32 {
     let mut src = [0u8; U32];
     src.copy_from_slice(&buf[pos..(pos + U32)]);
     u32::from_be_bytes(src)
 }
 
-fn get_key<'a>(buf: &'a BytesMut, slot: &'a Slot) -> &'a [u8] {
+fn get_key<'a>(buf: &'a [u8], slot: &'a Slot) -> &'a [u8] {
     &buf[(slot.offset as usize)..(slot.offset as usize + slot.klen as usize)]
 }
 
-fn get_val<'a>(buf: &'a BytesMut, slot: &'a Slot) -> &'a [u8] {
+fn get_val<'a>(buf: &'a [u8], slot: &'a Slot) -> &'a [u8] {
     &buf[(slot.offset as usize + slot.klen as usize)
         ..(slot.offset as usize + slot.klen as usize + slot.vlen as usize)]
 }
@@ -325,6 +382,8 @@ fn put_slot(buf: &mut BytesMut, idx: u32, slot: &Slot) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::order::Bytewise;
+    use crate::disk::testing::{check_map, check_page};
     use rand::prelude::*;
     use std::collections::HashSet;
 
@@ -340,10 +399,9 @@ mod tests {
         let mut rng = thread_rng();
 
         let size = 32;
-        let len = size * size_of::<u64>() * 4;
+        let len = size * size_of::<u64>() * 5;
 
         let mut keys = (0..size)
-            .into_iter()
             .map(|_| rng.gen::<u64>().to_be_bytes().to_vec())
             .collect::<Vec<_>>();
 
@@ -351,16 +409,15 @@ mod tests {
 
         for (i, key) in keys.iter().enumerate() {
             if i % 2 == 0 {
-                page.put_ref(key, 42).unwrap();
+                page.put_ref(key, 42, 1, &Bytewise).unwrap();
             } else {
-                page.put_val(key, b"undefined").unwrap();
+                page.put_val(key, b"undefined", &Bytewise).unwrap();
             }
         }
 
         keys.sort();
 
         let read = (0..size)
-            .into_iter()
             .map(|idx| page.key(idx as u32).to_vec())
             .collect::<Vec<_>>();
 
@@ -374,10 +431,7 @@ mod tests {
         let size = 64;
         let len = size * size_of::<u64>() * 10;
 
-        let keys = (0..size)
-            .into_iter()
-            .map(|_| rng.gen::<u64>())
-            .collect::<HashSet<_>>();
+        let keys = (0..size).map(|_| rng.gen::<u64>()).collect::<HashSet<_>>();
 
         let pairs = keys
             .iter()
@@ -391,11 +445,11 @@ mod tests {
 
         let mut page = Block::create(42, len as u32);
         for (key, val) in pairs.iter() {
-            page.put_val(key, val).unwrap();
+            page.put_val(key, val, &Bytewise).unwrap();
         }
 
         for (key, val) in pairs.iter() {
-            let idx = page.find(key).unwrap();
+            let idx = page.find(key, &Bytewise).unwrap();
             assert_eq!(page.key(idx), key);
             assert_eq!(page.val(idx), val);
         }
@@ -409,7 +463,6 @@ mod tests {
         let len = size * size_of::<u64>() * 10;
 
         let keys = (0..size)
-            .into_iter()
             .map(|_| {
                 let x = rng.gen::<u64>();
                 x - (x % 100)
@@ -418,7 +471,7 @@ mod tests {
 
         let mut page = Block::create(42, len as u32);
         for key in keys.iter() {
-            page.put_ref(&key.to_be_bytes(), 42).unwrap();
+            page.put_ref(&key.to_be_bytes(), 42, 1, &Bytewise).unwrap();
         }
 
         for k in keys.iter() {
@@ -426,15 +479,15 @@ mod tests {
             let key = &(k - r).to_be_bytes();
 
             let exp = &k.to_be_bytes();
-            let idx = page.ceil(key).unwrap();
+            let idx = page.ceil(key, &Bytewise).unwrap();
             assert_eq!(page.key(idx), exp);
 
-            let idx = page.ceil(exp).unwrap();
+            let idx = page.ceil(exp, &Bytewise).unwrap();
             assert_eq!(page.key(idx), exp);
         }
 
         let missing = keys.iter().max().cloned().unwrap() + 1;
-        assert_eq!(page.ceil(&missing.to_be_bytes()), None);
+        assert_eq!(page.ceil(&missing.to_be_bytes(), &Bytewise), None);
     }
 
     #[test]
@@ -443,7 +496,6 @@ mod tests {
         let count = 32;
 
         let pairs = (0..count)
-            .into_iter()
             .map(|_| {
                 (
                     rng.next_u64().to_be_bytes().to_vec(),
@@ -461,19 +513,41 @@ mod tests {
 
         let half = count / 2;
         for (k, v) in pairs.iter().take(half) {
-            page.put_val(k, v).unwrap();
+            page.put_val(k, v, &Bytewise).unwrap();
         }
         let free = half * (size_of::<u64>() * 2 + SLOT);
         assert_eq!(page.free(), free as u32);
         assert_eq!(page.full(), 50);
 
         for (k, v) in pairs.iter().skip(half) {
-            page.put_val(k, v).unwrap();
+            page.put_val(k, v, &Bytewise).unwrap();
         }
         assert_eq!(page.free(), 0);
         assert_eq!(page.full(), 100);
     }
 
+    #[test]
+    fn test_replace() {
+        let mut page = Block::create(1, 256);
+        for k in [b"a", b"b", b"c", b"d"] {
+            page.put_val(k, b"0", &Bytewise).unwrap();
+        }
+
+        for k in [b"a", b"c", b"d"] {
+            page.put_val(k, b"11", &Bytewise).unwrap();
+        }
+        assert_eq!(page.size(), 4);
+        assert_eq!(
+            page.copy(),
+            vec![
+                (b"a".to_vec(), b"11".to_vec(), 0),
+                (b"b".to_vec(), b"0".to_vec(), 0),
+                (b"c".to_vec(), b"11".to_vec(), 0),
+                (b"d".to_vec(), b"11".to_vec(), 0),
+            ]
+        );
+    }
+
     #[test]
     fn test_page() {
         let k1 = b"bb-cc-dd-ee";
@@ -496,12 +570,11 @@ mod tests {
             &[0, 0, 0, id as u8, 0, 0, 0, len as u8, 0, 0, 0, 0, 0xC0, 0xDE, 0x15, 0x42,]
         );
 
-        assert_eq!(page.put_val(k1, v1), Some(0));
-        assert_eq!(page.put_val(k2, v2), Some(0));
-        assert_eq!(page.put_ref(k3, p3), Some(2));
+        assert_eq!(page.put_val(k1, v1, &Bytewise), Some(0));
+        assert_eq!(page.put_val(k2, v2, &Bytewise), Some(0));
+        assert_eq!(page.put_ref(k3, p3, 7, &Bytewise), Some(2));
 
         let slots = (0..page.size())
-            .into_iter()
             .filter_map(|idx| page.slot(idx))
             .collect::<Vec<_>>();
 
@@ -525,9 +598,10 @@ mod tests {
                         - v2.len() as u32
                         - k1.len() as u32
                         - v1.len() as u32
-                        - k3.len() as u32,
+                        - k3.len() as u32
+                        - COUNT as u32,
                     k3.len() as u32,
-                    0,
+                    COUNT as u32,
                     p3
                 ),
             ]
@@ -539,20 +613,24 @@ mod tests {
 
         assert_eq!(page.val(0), v2);
         assert_eq!(page.val(1), v1);
-        assert_eq!(page.val(2), &[]);
+        assert_eq!(page.val(2), &7u64.to_be_bytes());
+        assert_eq!(page.count(2), 7);
+        page.set_count(2, 8);
+        assert_eq!(page.count(2), 8);
+        assert_eq!(page.val(1), v1);
 
-        assert_eq!(page.find(k1).unwrap(), 1);
-        assert_eq!(page.find(k2).unwrap(), 0);
-        assert_eq!(page.find(k3).unwrap(), 2);
-        assert_eq!(page.find(b"no-such-key"), None);
+        assert_eq!(page.find(k1, &Bytewise).unwrap(), 1);
+        assert_eq!(page.find(k2, &Bytewise).unwrap(), 0);
+        assert_eq!(page.find(k3, &Bytewise).unwrap(), 2);
+        assert_eq!(page.find(b"no-such-key", &Bytewise), None);
 
-        assert_eq!(page.ceil(b"\x01"), Some(0));
-        assert_eq!(page.ceil(b"\x03"), Some(0));
-        assert_eq!(page.ceil(b"a"), Some(1));
-        assert_eq!(page.ceil(b"b"), Some(1));
-        assert_eq!(page.ceil(b"o"), Some(2));
-        assert_eq!(page.ceil(b"x"), Some(2));
-        assert_eq!(page.ceil(b"z"), None);
+        assert_eq!(page.ceil(b"\x01", &Bytewise), Some(0));
+        assert_eq!(page.ceil(b"\x03", &Bytewise), Some(0));
+        assert_eq!(page.ceil(b"a", &Bytewise), Some(1));
+        assert_eq!(page.ceil(b"b", &Bytewise), Some(1));
+        assert_eq!(page.ceil(b"o", &Bytewise), Some(2));
+        assert_eq!(page.ceil(b"x", &Bytewise), Some(2));
+        assert_eq!(page.ceil(b"z", &Bytewise), None);
 
         let free = len
             - HEAD as u32
@@ -561,15 +639,16 @@ mod tests {
             - v1.len() as u32
             - k2.len() as u32
             - v2.len() as u32
-            - k3.len() as u32;
+            - k3.len() as u32
+            - COUNT as u32;
         assert_eq!(page.free(), free);
 
         page.remove(1); // remove (k1, v1)
         assert_eq!(page.free(), free + 16 + k1.len() as u32 + v1.len() as u32);
 
-        assert_eq!(page.find(k2).unwrap(), 0);
-        assert_eq!(page.find(k3).unwrap(), 1);
-        assert_eq!(page.find(b"no-such-key"), None);
+        assert_eq!(page.find(k2, &Bytewise).unwrap(), 0);
+        assert_eq!(page.find(k3, &Bytewise).unwrap(), 1);
+        assert_eq!(page.find(b"no-such-key", &Bytewise), None);
     }
 
     #[test]
@@ -585,7 +664,7 @@ mod tests {
         let mut page = Block::create(id, len);
 
         for (k, v) in data.iter() {
-            page.put_val(k, v);
+            page.put_val(k, v, &Bytewise);
         }
 
         let mut copy = data.clone();
@@ -599,8 +678,29 @@ mod tests {
             copy
         );
 
-        assert_eq!(page.find(&data[0].0), Some(2));
-        assert_eq!(page.find(&data[1].0), Some(0));
-        assert_eq!(page.find(&data[2].0), Some(1));
+        assert_eq!(page.find(&data[0].0, &Bytewise), Some(2));
+        assert_eq!(page.find(&data[1].0, &Bytewise), Some(0));
+        assert_eq!(page.find(&data[2].0, &Bytewise), Some(1));
+    }
+
+    #[test]
+    fn test_map() {
+        check_map::<Block>();
+    }
+
+    #[test]
+    fn test_check() {
+        check_page::<Block>(CAP_OFFSET, SIZE_OFFSET, |_| HEAD);
+    }
+
+    #[test]
+    fn test_max_entry() {
+        let max = Block::max_entry(256) as usize;
+        let mut page = Block::create(1, 256);
+        page.put_val(&[1u8; 8], &vec![0u8; max - 8], &Bytewise)
+            .unwrap();
+        page.put_val(&[2u8; 8], &vec![0u8; max - 8], &Bytewise)
+            .unwrap();
+        assert!(!page.fits(1));
     }
 }
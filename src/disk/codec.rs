@@ -0,0 +1,87 @@
+use crate::util::lz;
+use std::borrow::Cow;
+
+/// Value compression mode of a database, recorded in the file header.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub enum Codec {
+    /// Values are stored as-is.
+    #[default]
+    None,
+    /// Values are compressed with in-crate LZ77 codec (see `util::lz`).
+    /// Each stored value is prefixed with a tag byte: compressed or raw (if not compressible).
+    Lz,
+}
+
+const TAG_RAW: u8 = 0;
+const TAG_LZ: u8 = 1;
+
+impl Codec {
+    pub fn id(&self) -> u32 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz => 1,
+        }
+    }
+
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Lz),
+            _ => None,
+        }
+    }
+
+    /// Convert a value into its stored representation.
+    pub fn encode<'a>(&self, val: &'a [u8]) -> Cow<'a, [u8]> {
+        match self {
+            Codec::None => Cow::Borrowed(val),
+            Codec::Lz => {
+                let packed = lz::compress(val);
+                let mut stored = Vec::with_capacity(1 + packed.len().min(val.len()));
+                if packed.len() < val.len() {
+                    stored.push(TAG_LZ);
+                    stored.extend_from_slice(&packed);
+                } else {
+                    stored.push(TAG_RAW);
+                    stored.extend_from_slice(val);
+                }
+                Cow::Owned(stored)
+            }
+        }
+    }
+
+    /// Convert stored representation back to a value, returns `None` if stored value is corrupt.
+    pub fn decode<'a>(&self, stored: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+        match self {
+            Codec::None => Some(Cow::Borrowed(stored)),
+            Codec::Lz => match stored.first() {
+                Some(&TAG_RAW) => Some(Cow::Borrowed(&stored[1..])),
+                Some(&TAG_LZ) => lz::decompress(&stored[1..]).map(Cow::Owned),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec() {
+        let json = br#"{"name": "abc", "tags": ["abc", "abc", "abc", "abc", "abc"]}"#;
+        for codec in [Codec::None, Codec::Lz] {
+            assert_eq!(Codec::from_id(codec.id()), Some(codec));
+            for val in [&b""[..], b"x", b"abcd", json] {
+                let stored = codec.encode(val);
+                assert_eq!(codec.decode(&stored).unwrap().as_ref(), val);
+            }
+        }
+
+        assert_eq!(Codec::Lz.encode(b"abc").as_ref(), b"\x00abc");
+        assert!(Codec::Lz.encode(json).len() < json.len());
+        assert_eq!(Codec::Lz.decode(b""), None);
+        assert_eq!(Codec::Lz.decode(b"\x02abc"), None);
+        assert_eq!(Codec::from_id(42), None);
+    }
+}
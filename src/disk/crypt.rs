@@ -0,0 +1,104 @@
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Tag, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use std::mem::size_of;
+
+/// Encryption key (256 bits) provided by the user.
+pub(crate) type Key = [u8; 32];
+
+const ID: usize = size_of::<u32>();
+const NONCE: usize = 24;
+const TAG: usize = 16;
+
+/// Number of bytes an encrypted page takes on top of the page content: id, nonce and tag.
+pub(crate) const OVERHEAD: usize = ID + NONCE + TAG;
+
+/// Authenticated page encryption (XChaCha20-Poly1305).
+///
+/// Encrypted page layout: page id (plain, also used as associated data), random nonce (192 bits,
+/// as pages are rewritten any number of times under one key), encrypted page content,
+/// authentication tag. Binding the id to the ciphertext makes a page copied over another page's
+/// position fail authentication.
+pub(crate) struct Crypt {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Crypt {
+    pub(crate) fn new(key: &Key) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(key.into()),
+        }
+    }
+
+    /// Encrypt given content (page or any other block) stored under given id.
+    pub(crate) fn encrypt(&self, id: u32, src: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let mut dst = Vec::with_capacity(src.len() + OVERHEAD);
+        dst.extend_from_slice(&id.to_be_bytes());
+        dst.extend_from_slice(&nonce);
+        dst.extend_from_slice(src);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(
+                XNonce::from_slice(&nonce),
+                &id.to_be_bytes(),
+                &mut dst[(ID + NONCE)..],
+            )
+            .expect("encryption");
+        dst.extend_from_slice(tag.as_slice());
+        dst
+    }
+
+    /// Decrypt the content produced by `encrypt` for given id into `dst`.
+    /// Returns `false` if the id does not match or authentication fails (wrong key or corrupt data).
+    pub(crate) fn decrypt(&self, id: u32, src: &[u8], dst: &mut [u8]) -> bool {
+        if src.len() != dst.len() + OVERHEAD || src[..ID] != id.to_be_bytes() {
+            return false;
+        }
+        let nonce = &src[ID..(ID + NONCE)];
+        let tag = &src[(src.len() - TAG)..];
+        dst.copy_from_slice(&src[(ID + NONCE)..(src.len() - TAG)]);
+        self.cipher
+            .decrypt_in_place_detached(
+                XNonce::from_slice(nonce),
+                &id.to_be_bytes(),
+                dst,
+                Tag::from_slice(tag),
+            )
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crypt() {
+        let crypt = Crypt::new(&[42u8; 32]);
+        let page = b"some page content".to_vec();
+
+        let sealed = crypt.encrypt(7, &page);
+        assert_eq!(sealed.len(), page.len() + OVERHEAD);
+        assert_eq!(&sealed[..4], &[0, 0, 0, 7]);
+        assert!(!sealed.windows(page.len()).any(|w| w == page.as_slice()));
+        assert_ne!(crypt.encrypt(7, &page), sealed);
+
+        let mut dst = vec![0u8; page.len()];
+        assert!(crypt.decrypt(7, &sealed, &mut dst));
+        assert_eq!(dst, page);
+
+        assert!(!crypt.decrypt(8, &sealed, &mut dst));
+        assert!(!Crypt::new(&[0u8; 32]).decrypt(7, &sealed, &mut dst));
+
+        let mut copy = sealed.clone();
+        copy[10] ^= 1;
+        assert!(!crypt.decrypt(7, &copy, &mut dst));
+
+        let mut copy = sealed;
+        copy[..4].copy_from_slice(&8u32.to_be_bytes());
+        assert!(!crypt.decrypt(8, &copy, &mut dst));
+    }
+}
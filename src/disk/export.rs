@@ -0,0 +1,102 @@
+use serde::Serialize;
+use std::fmt::Write;
+
+/// Structure of the database for visualisation: pages reachable from the roots of the main tree,
+/// the catalog, the buckets, the expiry and history trees (in breadth-first order) with their
+/// slots and child links, and pages not referenced by any tree.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Export {
+    pub page_bytes: u32,
+    pub root: u32,
+    /// Root page of the catalog of buckets (0 if there is none), and roots of the buckets.
+    pub catalog: u32,
+    pub buckets: Vec<BucketInfo>,
+    /// Root pages of the expiry tree and the history tree (0 if there is none).
+    pub expiry: u32,
+    pub history: u32,
+    pub pages: Vec<PageInfo>,
+    /// Pages in the file not referenced by any tree (free or lost ones), ascending.
+    pub free: Vec<u32>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct BucketInfo {
+    pub name: String,
+    pub root: u32,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct PageInfo {
+    pub id: u32,
+    /// Page holding the reference to this one (0 for a root).
+    pub parent: u32,
+    pub leaf: bool,
+    /// Occupancy of the page (`Page::full`), percent.
+    pub full: u8,
+    pub slots: Vec<SlotInfo>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SlotInfo {
+    /// Key (hex-encoded), for a node slot it is a separator: lower bound of the child subtree.
+    pub key: String,
+    /// Referenced child page and number of keys in its subtree (node slots only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<u64>,
+}
+
+impl Export {
+    /// Graphviz DOT: a record per page (a field per slot), an edge per child link, a label
+    /// pointing to the root of each bucket, and a single dashed record listing free pages.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::with_capacity(64 * self.pages.len() + 128);
+        dot.push_str("digraph yakvdb {\n");
+        dot.push_str("\tnode [shape=record, fontname=\"monospace\"];\n");
+        for page in self.pages.iter() {
+            let fields = page
+                .slots
+                .iter()
+                .enumerate()
+                .map(|(idx, slot)| match slot.count {
+                    Some(count) => format!("<s{}> {} ({})", idx, slot.key, count),
+                    None => format!("<s{}> {}", idx, slot.key),
+                })
+                .collect::<Vec<_>>()
+                .join("|");
+            let kind = if page.leaf { "leaf" } else { "node" };
+            let _ = writeln!(
+                dot,
+                "\tp{} [label=\"{{page={} {} {}%|{{{}}}}}\"];",
+                page.id, page.id, kind, page.full, fields
+            );
+            for (idx, slot) in page.slots.iter().enumerate() {
+                if let Some(child) = slot.page {
+                    let _ = writeln!(dot, "\tp{}:s{} -> p{};", page.id, idx, child);
+                }
+            }
+        }
+        for (idx, bucket) in self.buckets.iter().enumerate() {
+            let name = bucket.name.replace('\\', "\\\\").replace('"', "\\\"");
+            let _ = writeln!(dot, "\tb{} [label=\"{}\", shape=plaintext];", idx, name);
+            let _ = writeln!(dot, "\tb{} -> p{};", idx, bucket.root);
+        }
+        if !self.free.is_empty() {
+            let ids = self
+                .free
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let _ = writeln!(dot, "\tfree [label=\"{{free|{}}}\", style=dashed];", ids);
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Pretty-printed JSON of the whole structure.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("export is serializable")
+    }
+}
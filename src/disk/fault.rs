@@ -0,0 +1,155 @@
+use crate::disk::io::Io;
+use std::cell::Cell;
+use std::io;
+
+/// Storage wrapper injecting write faults at chosen points (for testing recovery paths).
+///
+/// A planned fault fails the N-th write, optionally "tearing" it: only the first bytes of the
+/// buffer reach the underlying storage, or fails the next sync. After the fault the storage stays broken (every write,
+/// truncate and sync fails, as if the device went away) until `heal` is called. Reads never fail.
+pub(crate) struct Fault<S: Io> {
+    inner: S,
+    /// Number of write calls so far (a vectored write counts as a single one).
+    writes: Cell<usize>,
+    /// Planned fault: index of the failing write and number of its bytes to keep.
+    plan: Cell<Option<(usize, usize)>>,
+    /// Planned fault of the next sync.
+    sync: Cell<bool>,
+    broken: Cell<bool>,
+}
+
+impl<S: Io> Fault<S> {
+    pub(crate) fn new(inner: S) -> Self {
+        Self {
+            inner,
+            writes: Cell::new(0),
+            plan: Cell::new(None),
+            sync: Cell::new(false),
+            broken: Cell::new(false),
+        }
+    }
+
+    /// Fail the write that comes after `after` more successful ones, keeping `torn` bytes of it.
+    pub(crate) fn fail_write(&self, after: usize, torn: usize) {
+        self.plan.set(Some((self.writes.get() + after, torn)));
+    }
+
+    /// Fail the next sync (written bytes are kept, but are not known to be durable).
+    pub(crate) fn fail_sync(&self) {
+        self.sync.set(true);
+    }
+
+    /// Drop planned faults (if any) and make the storage writable again.
+    pub(crate) fn heal(&self) {
+        self.plan.set(None);
+        self.sync.set(false);
+        self.broken.set(false);
+    }
+
+    pub(crate) fn is_broken(&self) -> bool {
+        self.broken.get()
+    }
+
+    pub(crate) fn writes(&self) -> usize {
+        self.writes.get()
+    }
+
+    pub(crate) fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn check(&self) -> io::Result<()> {
+        if self.broken.get() {
+            Err(io::Error::other("Injected fault: storage is broken"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: Io> Io for Fault<S> {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.inner.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check()?;
+        let n = self.writes.get();
+        self.writes.set(n + 1);
+        match self.plan.get() {
+            Some((at, torn)) if at == n => {
+                self.broken.set(true);
+                self.inner.write_at(&buf[..torn.min(buf.len())], offset)?;
+                Err(io::Error::other(format!("Injected fault: write {}", n)))
+            }
+            _ => self.inner.write_at(buf, offset),
+        }
+    }
+
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> io::Result<()> {
+        self.write_at(&bufs.concat(), offset)
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.check()?;
+        self.inner.truncate(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.check()?;
+        if self.sync.replace(false) {
+            self.broken.set(true);
+            return Err(io::Error::other("Injected fault: sync"));
+        }
+        self.inner.sync()
+    }
+
+    fn lock(&self, exclusive: bool) -> io::Result<()> {
+        self.inner.lock(exclusive)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk::mem::Mem;
+
+    #[test]
+    fn test_fault() {
+        let fault = Fault::new(Mem::new());
+        fault.write_at(b"0000", 0).unwrap();
+        fault.fail_write(1, 2);
+
+        fault.write_at(b"11", 4).unwrap();
+        assert!(!fault.is_broken());
+        assert!(fault.write_vectored_at(&[b"22", b"22"], 6).is_err());
+        assert!(fault.is_broken());
+        assert_eq!(fault.inner().bytes(), b"00001122");
+
+        assert!(fault.write_at(b"3", 0).is_err());
+        assert!(fault.append(b"3").is_err());
+        assert!(fault.sync().is_err());
+        assert!(fault.truncate(0).is_err());
+        let mut buf = [0u8; 2];
+        fault.read_at(&mut buf, 6).unwrap();
+        assert_eq!(&buf, b"22");
+        assert_eq!(fault.writes(), 3);
+
+        fault.heal();
+        fault.write_at(b"44", 8).unwrap();
+        fault.sync().unwrap();
+        assert_eq!(fault.inner().bytes(), b"0000112244");
+
+        fault.fail_sync();
+        fault.write_at(b"5", 0).unwrap();
+        assert!(fault.sync().is_err());
+        assert!(fault.is_broken());
+        assert_eq!(fault.inner().bytes(), b"5000112244");
+        fault.heal();
+        fault.sync().unwrap();
+    }
+}
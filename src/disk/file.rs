@@ -1,22 +1,40 @@
 use crate::api::error::{Error, Result};
-use crate::api::page::Page;
-use crate::api::tree::Tree;
+use crate::api::merge::MergeOperator;
+use crate::api::order::{self, Bytewise, Comparator, NAME_LEN};
+use crate::api::page::{get_count, Page, Slot, View, COUNT};
+use crate::api::tree::{Estimate, Pages, Tree};
+use crate::disk::codec::Codec;
+use crate::disk::crypt::{self, Crypt, Key};
+use crate::disk::export::{BucketInfo, Export, PageInfo, SlotInfo};
+use crate::disk::io::Io;
+use crate::util::encoding;
 use crate::util::hex::hex;
+use crate::util::key::separator;
 use bytes::{Buf, BufMut, BytesMut};
-use log::{debug, trace};
-use std::cell::{Ref, RefCell, RefMut};
-use std::cmp::Reverse;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use log::{debug, trace, warn};
+use memmap2::Mmap;
+use rand::Rng;
+use std::borrow::Cow;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
 use std::fs::{self, OpenOptions};
-use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::io;
 use std::mem::size_of;
-use std::ops::Deref;
+use std::ops::{Bound, Deref};
 use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub(crate) struct File<P: Page> {
-    /// Underlying file reference where all data is physically stored.
-    file: RefCell<fs::File>,
+pub struct File<P: Page, S: Io = fs::File> {
+    /// Underlying storage where all data is physically stored (positional I/O only).
+    file: S,
     head: Head,
+    /// Header as it was last written (see `write_head`).
+    written: RefCell<Vec<u8>>,
 
     /// In-memory page cache. All page access happens only through cached page representation.
     cache: RefCell<HashMap<u32, P>>, // TODO limit memory usage (LRU-cache?)
@@ -24,1017 +42,3913 @@ pub(crate) struct File<P: Page> {
 
     /// Min-heap of available page identifiers (this helps avoid "gaps": empty pages inside file).
     empty: RefCell<BinaryHeap<Reverse<u32>>>,
+    /// Pages holding the list of free pages referenced by the header (see `write_chain`), not
+    /// reused until the header references a new list.
+    chain: RefCell<Vec<u32>>,
+    /// Free pages changed since the list of free pages was written.
+    stale: Cell<bool>,
+
+    /// Decoded values (by page id and slot index) of compressed database.
+    /// Entries of a page are dropped as soon as the page is accessed for modification.
+    values: RefCell<HashMap<u32, HashMap<u32, Vec<u8>>>>,
+
+    /// Page encryption, pages are decrypted on load and encrypted on save.
+    crypt: Option<Crypt>,
+
+    /// Read-only memory mapping of the whole file (if enabled): cache misses are served from it,
+    /// and values of clean pages are returned without copying. Remapped when the file grows.
+    /// A lookup reads pages that are not cached in place, without copying (see `lookup_mapped`).
+    map: RefCell<Option<Mmap>>,
+    /// Pages read in place from the mapping that passed the consistency check. A page that is
+    /// not cached does not change in the mapping: pages are written only from the cache.
+    checked: RefCell<HashSet<u32>>,
+
+    /// Opened in read-only mode: `insert` and `remove` are rejected.
+    read_only: bool,
+
+    /// Order of keys (recorded by name in the header).
+    order: Arc<dyn Comparator>,
+
+    /// Page cache accesses served from the cache (hits) and requiring a load (misses).
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+
+    /// Secondary indexes listed in the catalog, by the root page of the indexed tree (an index
+    /// has an extractor once registered for the session).
+    indexes: RefCell<HashMap<u32, Vec<Index>>>,
+
+    /// Current time (milliseconds since the Unix epoch) for expiry of entries.
+    clock: fn() -> u64,
+
+    /// Merge operator (if any) applied by `merge_value`.
+    merge: Option<Arc<dyn MergeOperator>>,
+
+    /// Pages of the write in progress as they were before it, restored if the write fails
+    /// (see `atomic`).
+    undo: RefCell<Option<Undo<P>>>,
 }
 
-const MAGIC: &[u8] = b"YAKVDB42";
+/// Index key of a value (if the value is to be indexed).
+pub type Extract = dyn Fn(&[u8]) -> Option<Vec<u8>>;
+
+/// Secondary index of a tree, stored in a bucket: an entry per indexed value, with the key
+/// encoded as `(index key, primary key)` (see `util::encoding`) and an empty value.
+struct Index {
+    name: String,
+    root: u32,
+    extract: Option<Rc<Extract>>,
+}
+
+/// Named tree stored in the same file as the main tree, sharing its page cache, free pages and
+/// storage. Bucket names and root pages are kept in the catalog (a tree of its own).
+pub struct Bucket<'a, P: Page, S: Io = fs::File> {
+    file: &'a File<P, S>,
+    name: String,
+    root: u32,
+}
+
+/// Bucket in multimap mode: any number of values per key, entries ordered by (key, value).
+/// A pair is stored as a composite key (see `util::encoding`) with an empty value: all the values
+/// of a key are adjacent, and pages are split between pairs just like between any other keys.
+pub struct Multimap<'a, P: Page, S: Io = fs::File> {
+    file: &'a File<P, S>,
+    name: String,
+    root: u32,
+}
+
+/// Database statistics: cheap counters, and figures of a full tree walk (if requested).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub page_bytes: u32,
+    /// Pages in the file (including the free ones).
+    pub pages: u64,
+    /// Pages known to be free (available for reuse), including the ones holding their list.
+    pub free: u64,
+    /// Number of entries in the main tree, and the number of buckets.
+    pub keys: u64,
+    pub buckets: u64,
+    /// Number of pages on the path from the root to a leaf.
+    pub height: u32,
+    /// Pages in the page cache, and the ones not yet flushed.
+    pub cached: u64,
+    pub dirty: u64,
+    /// Page accesses served from the cache, and the ones that required loading the page.
+    pub hits: u64,
+    pub misses: u64,
+    pub walk: Option<Walk>,
+}
+
+/// Figures collected by visiting every page of the tree.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Walk {
+    pub leaves: u64,
+    pub nodes: u64,
+    /// Pages in the file not referenced by any tree (free or lost ones).
+    pub unused: u64,
+    /// Average occupancy (`Page::full`) of the tree pages, percent.
+    pub full: f64,
+    /// Total size of keys and values stored in leaf pages (values as stored: encoded).
+    pub key_bytes: u64,
+    pub val_bytes: u64,
+}
+
+impl Stats {
+    /// Share of page accesses served from the page cache.
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            n => self.hits as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pages={} page={} free={} keys={} height={} cached={} dirty={} hits={} misses={} ratio={:.3}",
+            self.pages,
+            self.page_bytes,
+            self.free,
+            self.keys,
+            self.height,
+            self.cached,
+            self.dirty,
+            self.hits,
+            self.misses,
+            self.hit_ratio()
+        )?;
+        if self.buckets > 0 {
+            write!(f, " buckets={}", self.buckets)?;
+        }
+        if let Some(walk) = self.walk.as_ref() {
+            write!(
+                f,
+                " leaves={} nodes={} unused={} full={:.1}% key_bytes={} val_bytes={}",
+                walk.leaves, walk.nodes, walk.unused, walk.full, walk.key_bytes, walk.val_bytes
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Database settings provided on creation and persisted in the file header.
+/// When opening existing database only the `key` is used, the rest is read from the header.
+#[derive(Default, Clone)]
+pub struct Options {
+    pub codec: Codec,
+    /// Encrypt all pages with given key (page capacity is reduced by `crypt::OVERHEAD` bytes).
+    pub key: Option<Key>,
+    /// Serve reads from memory-mapped file (not persisted, not supported with encryption).
+    pub mmap: bool,
+    /// Open existing database for reading only (not persisted): the file is opened without
+    /// write access and locked shared, thus other readers are allowed, but not writers.
+    pub read_only: bool,
+    /// Order of keys, bytewise if not set. When opening existing database it must be the one
+    /// recorded in the header (a built-in comparator is picked by the recorded name if not set).
+    /// Multimaps, indexes, expiry and versioned mode use composite keys, thus bytewise order.
+    pub order: Option<Arc<dyn Comparator>>,
+    /// Combination of values by `merge_value` (not persisted).
+    pub merge: Option<Arc<dyn MergeOperator>>,
+    /// Versioned mode (set on creation only): past versions of the entries of the main tree are
+    /// retained for reads at the sequence numbers of the last `horizon` writes (see `lookup_at`).
+    pub horizon: Option<u32>,
+}
+
+/// File signature: format name followed by format version.
+const MAGIC: &[u8] = b"YAKVDB47";
+const NAME: usize = 6;
 
 const HEAD: usize = MAGIC.len() + size_of::<Head>();
+/// Header of a page of the list of free pages: page id, next page of the list, count of pages.
+const CHAIN: usize = 3 * size_of::<u32>();
 const ROOT: u32 = 1;
 
+/// Page size limits: page ids are `u32` and offsets within a file are `u64`, thus the file
+/// size is limited only by the page count (`u32::MAX` pages), and offsets within a page are
+/// `u32` (see `Slot`), max page size keeps page-level arithmetic far from overflowing.
+const MIN_PAGE_BYTES: u32 = 128;
+const MAX_PAGE_BYTES: u32 = 1 << 30;
+
 const SPLIT_THRESHOLD: u8 = 80;
 const MERGE_THRESHOLD: u8 = 30;
 
+const CIPHER_NONE: u32 = 0;
+const CIPHER_XCHACHA20_POLY1305: u32 = 1;
+
+/// Encrypted MAGIC, allows checking if provided key is correct.
+const CHECK: usize = MAGIC.len() + crypt::OVERHEAD;
+
+/// Tags of the entries in the expiry tree: deadline of a key, and a key due at a deadline.
+const DEADLINE: u8 = 0;
+const SCHEDULE: u8 = 1;
+
+/// Max number of expired entries removed on each insert/remove.
+const SWEEP: usize = 8;
+
+/// Entries of the history tree (besides the last sequence number under the empty key):
+/// versions of keys, and the key written under each sequence number within the horizon.
+const SEQ: &[u8] = b"";
+const VERSION: u8 = 0;
+const WRITE: u8 = 1;
+
+/// Modes of buckets (recorded in the catalog): a plain tree, a secondary index, or a multimap.
+const PLAIN: u8 = 0;
+const INDEX: u8 = 1;
+const MULTIMAP: u8 = 2;
+
+/// File header. It is written by `flush` once the pages are synced, thus it never references
+/// a root page (or a page of the list of free pages) that is not stored.
 #[derive(Debug)]
 #[repr(C)]
 struct Head {
     page_bytes: u32,
     page_count: u32,
+    codec: u32,
+    cipher: u32,
+    /// Root page of the catalog of buckets (0 if no bucket was ever created).
+    catalog: u32,
+    /// Root page of the expiry tree (0 if no entry ever had a TTL). Set through a shared
+    /// reference, as buckets store expiring entries as well.
+    expiry: Cell<u32>,
+    /// Versioned mode: number of past writes (to the main tree) whose versions are retained,
+    /// 0 if the database is not versioned.
+    horizon: u32,
+    /// Root page of the history tree of a versioned database (0 until the first write).
+    history: Cell<u32>,
+    /// First page of the list of free pages (0 if none is listed).
+    free: Cell<u32>,
+    check: [u8; CHECK],
+    /// Name of the key order (see `Comparator::name`), zero-padded.
+    order: [u8; NAME_LEN],
+}
+
+impl Head {
+    fn put(&self, buf: &mut BytesMut) {
+        buf.put_slice(MAGIC);
+        buf.put_u32(self.page_bytes);
+        buf.put_u32(self.page_count);
+        buf.put_u32(self.codec);
+        buf.put_u32(self.cipher);
+        buf.put_u32(self.catalog);
+        buf.put_u32(self.expiry.get());
+        buf.put_u32(self.horizon);
+        buf.put_u32(self.history.get());
+        buf.put_u32(self.free.get());
+        buf.put_slice(&self.check);
+        buf.put_slice(&self.order);
+    }
+
+    fn read(file: &impl Io) -> Result<Self> {
+        let len = file.len()? as usize;
+        if len < MAGIC.len() {
+            return Err(Error::BadMagic);
+        }
+
+        let mut magic = [0u8; 8];
+        file.read_at(&mut magic, 0)?;
+        if magic[..NAME] != MAGIC[..NAME] {
+            return Err(Error::BadMagic);
+        }
+        if magic != MAGIC {
+            let version = String::from_utf8_lossy(&magic[NAME..]).into_owned();
+            return Err(Error::UnsupportedVersion(version));
+        }
+        if len < HEAD {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Header is truncated").into());
+        }
+
+        let mut buf = BytesMut::with_capacity(HEAD);
+        buf.extend_from_slice(&[0u8; HEAD]);
+        file.read_at(&mut buf[..], 0)?;
+        buf.advance(MAGIC.len());
+
+        let mut head = Head {
+            page_bytes: buf.get_u32(),
+            page_count: buf.get_u32(),
+            codec: buf.get_u32(),
+            cipher: buf.get_u32(),
+            catalog: buf.get_u32(),
+            expiry: Cell::new(buf.get_u32()),
+            horizon: buf.get_u32(),
+            history: Cell::new(buf.get_u32()),
+            free: Cell::new(buf.get_u32()),
+            check: [0u8; CHECK],
+            order: [0u8; NAME_LEN],
+        };
+        buf.copy_to_slice(&mut head.check);
+        buf.copy_to_slice(&mut head.order);
+
+        if Codec::from_id(head.codec).is_none() {
+            return Err(Error::Unsupported(format!("codec {}", head.codec)));
+        }
+
+        if head.cipher != CIPHER_NONE && head.cipher != CIPHER_XCHACHA20_POLY1305 {
+            return Err(Error::Unsupported(format!("cipher {}", head.cipher)));
+        }
+
+        if !(MIN_PAGE_BYTES..=MAX_PAGE_BYTES).contains(&head.page_bytes) {
+            return Err(Error::Unsupported(format!("page size {}", head.page_bytes)));
+        }
+
+        if (len as u64) < head.offset(ROOT + 1) {
+            return Err(Error::PageNotFound(ROOT));
+        }
+
+        Ok(head)
+    }
+
+    /// Position of the page with given id in the file.
+    fn offset(&self, id: u32) -> u64 {
+        HEAD as u64 + (id as u64 - 1) * self.page_bytes as u64
+    }
+
+    /// Name of the key order recorded in the header.
+    fn order_name(&self) -> String {
+        let len = self.order.iter().position(|b| *b == 0).unwrap_or(NAME_LEN);
+        String::from_utf8_lossy(&self.order[..len]).into_owned()
+    }
+
+    /// Resolve the comparator of the database: the provided one must match the recorded name.
+    fn order(&self, order: Option<&Arc<dyn Comparator>>) -> Result<Arc<dyn Comparator>> {
+        let name = self.order_name();
+        match order {
+            Some(order) if order.name() == name => Ok(order.clone()),
+            Some(_) => Err(Error::WrongOrder(name)),
+            None => order::builtin(&name).ok_or(Error::WrongOrder(name)),
+        }
+    }
+
+    /// Check if the header was written with the same encryption key (if any).
+    fn crypt(&self, key: Option<&Key>) -> Result<Option<Crypt>> {
+        match (self.cipher, key) {
+            (CIPHER_NONE, None) => Ok(None),
+            (CIPHER_NONE, Some(_)) => Err(Error::NotEncrypted),
+            (_, None) => Err(Error::KeyRequired),
+            (_, Some(key)) => {
+                let crypt = Crypt::new(key);
+                let mut magic = [0u8; 8];
+                if crypt.decrypt(0, &self.check, &mut magic) && magic == MAGIC {
+                    Ok(Some(crypt))
+                } else {
+                    Err(Error::WrongKey)
+                }
+            }
+        }
+    }
 }
 
 impl<P: Page> File<P> {
-    pub(crate) fn make(path: &Path, page_bytes: u32) -> io::Result<Self> {
+    pub fn make(path: &Path, page_bytes: u32) -> Result<Self> {
+        Self::make_with(path, page_bytes, Options::default())
+    }
+
+    pub fn make_with(path: &Path, page_bytes: u32, opts: Options) -> Result<Self> {
         if path.exists() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("File exists: {:?}", path),
+            let msg = format!("File exists: {:?}", path);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+        }
+        if opts.mmap && opts.key.is_some() {
+            return Err(Error::Unsupported(
+                "memory mapping of encrypted file".to_string(),
             ));
         }
+        if opts.read_only {
+            return Err(Error::ReadOnly);
+        }
 
-        let mut file = OpenOptions::new()
+        let file = OpenOptions::new()
             .create(true)
             .write(true)
             .read(true)
+            .truncate(true)
             .open(path)?;
+        Self::make_in(file, page_bytes, opts)
+    }
 
-        let head = Head {
-            page_bytes,
-            page_count: 1,
-        };
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::open_with(path, Options::default())
+    }
 
-        let mut buf = BytesMut::with_capacity(HEAD + page_bytes as usize);
-        buf.put_slice(&MAGIC);
-        buf.put_u32(head.page_bytes);
-        buf.put_u32(head.page_count);
+    pub fn open_with(path: &Path, opts: Options) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!opts.read_only)
+            .open(path)?;
+        Self::open_in(file, opts)
+    }
 
-        let root = P::create(ROOT, head.page_bytes);
-        buf.put_slice(root.as_ref());
+    /// Re-encrypt all pages of an encrypted database with a new key (database must be closed).
+    /// New content is written to a temporary file first, that then replaces the original one,
+    /// thus the database is never left partially re-encrypted.
+    pub fn rekey(path: &Path, old: &Key, new: &Key) -> Result<()> {
+        let src = OpenOptions::new().read(true).open(path)?;
+        lock(&src, true)?;
+        let mut head = Head::read(&src)?;
+        let old = match head.crypt(Some(old))? {
+            Some(crypt) => crypt,
+            None => return Err(Error::NotEncrypted),
+        };
+        let new = Crypt::new(new);
+        head.check.copy_from_slice(&new.encrypt(0, MAGIC));
 
-        file.write_all(buf.as_ref())?;
-        file.flush()?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".rekey");
+        let tmp = Path::new(&tmp);
+        let dst = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(tmp)?;
+        let mut temp = Temp {
+            path: tmp,
+            keep: false,
+        };
 
-        Ok(Self {
-            file: RefCell::new(file),
-            head,
-            cache: RefCell::new(HashMap::with_capacity(32)),
-            dirty: RefCell::new(HashSet::with_capacity(32)),
-            empty: RefCell::new(BinaryHeap::with_capacity(32)),
-        })
+        let mut buf = BytesMut::with_capacity(HEAD);
+        head.put(&mut buf);
+        dst.write_at(buf.as_ref(), 0)?;
+
+        let page_bytes = head.page_bytes as usize;
+        let count = (src.len()? - HEAD as u64) / page_bytes as u64;
+        let mut raw = vec![0u8; page_bytes];
+        let mut page = vec![0u8; page_bytes - crypt::OVERHEAD];
+        for id in 1..=(count as u32) {
+            src.read_at(&mut raw, head.offset(id))?;
+            if !old.decrypt(id, &raw, &mut page) {
+                return Err(Error::CorruptPage {
+                    id,
+                    reason: "Authentication failed".to_string(),
+                });
+            }
+            dst.append(&new.encrypt(id, &page))?;
+        }
+
+        dst.sync()?;
+        drop(dst);
+        fs::rename(tmp, path)?;
+        temp.keep = true;
+        sync_dir(path)
     }
+}
 
-    pub(crate) fn open(path: &Path) -> io::Result<Self> {
-        let mut file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(path)?;
+/// Temporary file, removed unless kept (e.g. when copying into it fails half-way).
+struct Temp<'a> {
+    path: &'a Path,
+    keep: bool,
+}
 
-        let len = file.metadata()?.len() as usize;
-        if len < HEAD {
-            return Err(io::Error::new(io::ErrorKind::Other, "File too short"));
+impl Drop for Temp<'_> {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
         }
+        if let Err(e) = fs::remove_file(self.path) {
+            debug!("temp: path={:?} not removed: {}", self.path, e);
+        }
+    }
+}
 
-        let mut buf = BytesMut::with_capacity(HEAD);
-        buf.extend_from_slice(&[0u8; HEAD]);
-        file.read_exact(&mut buf[..])?;
+/// Make a rename (or creation) of given file durable by syncing its directory.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
 
-        let mut magic = [0u8; 8];
-        buf.copy_to_slice(&mut magic);
-        if magic != MAGIC {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("MAGIC mismatch: {:?}", magic),
+/// Directories cannot be opened (thus synced) as files, a rename is durable once it returns.
+#[cfg(not(unix))]
+fn sync_dir(_: &Path) -> Result<()> {
+    Ok(())
+}
+
+impl<P: Page, S: Io> File<P, S> {
+    /// Create new database in given (empty) storage.
+    pub fn make_in(file: S, page_bytes: u32, opts: Options) -> Result<Self> {
+        if file.len()? > 0 {
+            let msg = "Storage is not empty";
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg).into());
+        }
+        if opts.mmap && opts.key.is_some() {
+            return Err(Error::Unsupported(
+                "memory mapping of encrypted file".to_string(),
             ));
         }
+        if opts.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if !(MIN_PAGE_BYTES..=MAX_PAGE_BYTES).contains(&page_bytes) {
+            return Err(Error::Unsupported(format!("page size {}", page_bytes)));
+        }
+        let order = opts.order.clone().unwrap_or_else(|| Arc::new(Bytewise));
+        let name = order.name().as_bytes();
+        if name.is_empty() || name.len() > NAME_LEN || !name.is_ascii() || name.contains(&0) {
+            return Err(Error::Unsupported(format!(
+                "comparator name '{}'",
+                order.name()
+            )));
+        }
+        if let Some(horizon) = opts.horizon {
+            if horizon == 0 || order.name() != Bytewise.name() {
+                return Err(Error::Unsupported(format!(
+                    "history horizon {} with comparator '{}'",
+                    horizon,
+                    order.name()
+                )));
+            }
+        }
+        lock(&file, true)?;
 
-        let head = Head {
-            page_bytes: buf.get_u32(),
-            page_count: buf.get_u32(),
+        let crypt = opts.key.as_ref().map(Crypt::new);
+        let mut head = Head {
+            page_bytes,
+            page_count: 1,
+            codec: opts.codec.id(),
+            cipher: CIPHER_NONE,
+            catalog: 0,
+            expiry: Cell::new(0),
+            horizon: opts.horizon.unwrap_or_default(),
+            history: Cell::new(0),
+            free: Cell::new(0),
+            check: [0u8; CHECK],
+            order: [0u8; NAME_LEN],
         };
+        head.order[..name.len()].copy_from_slice(name);
+        if let Some(crypt) = crypt.as_ref() {
+            head.cipher = CIPHER_XCHACHA20_POLY1305;
+            head.check.copy_from_slice(&crypt.encrypt(0, MAGIC));
+        }
 
-        if head.page_bytes > u16::MAX as u32 {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("Page size too large: {}", head.page_bytes),
-            ));
+        let this = Self {
+            file,
+            head,
+            written: RefCell::new(Vec::new()),
+            cache: RefCell::new(HashMap::with_capacity(32)),
+            dirty: RefCell::new(HashSet::with_capacity(32)),
+            empty: RefCell::new(BinaryHeap::with_capacity(32)),
+            chain: RefCell::new(Vec::new()),
+            stale: Cell::new(false),
+            values: RefCell::new(HashMap::with_capacity(32)),
+            crypt,
+            map: RefCell::new(None),
+            checked: RefCell::new(HashSet::new()),
+            read_only: opts.read_only,
+            order,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            indexes: RefCell::new(HashMap::new()),
+            clock: now,
+            merge: opts.merge.clone(),
+            undo: RefCell::new(None),
+        };
+
+        let mut buf = BytesMut::with_capacity(HEAD + page_bytes as usize);
+        this.head.put(&mut buf);
+        let root = P::create(ROOT, this.cap());
+        buf.put_slice(&this.seal(&root));
+
+        this.file.write_at(buf.as_ref(), 0)?;
+        *this.written.borrow_mut() = buf[..HEAD].to_vec();
+        this.cache.borrow_mut().insert(ROOT, root);
+
+        if opts.mmap {
+            this.remap()?;
         }
 
-        if len < HEAD + head.page_bytes as usize {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "File does not contain one full page".to_string(),
+        Ok(this)
+    }
+
+    /// Open existing database from given storage.
+    pub fn open_in(file: S, opts: Options) -> Result<Self> {
+        lock(&file, !opts.read_only)?;
+        let head = Head::read(&file)?;
+        let crypt = head.crypt(opts.key.as_ref())?;
+        let order = head.order(opts.order.as_ref())?;
+        if opts.mmap && crypt.is_some() {
+            return Err(Error::Unsupported(
+                "memory mapping of encrypted file".to_string(),
             ));
         }
 
-        let mut root = P::reserve(head.page_bytes);
-        file.read_exact(root.as_mut())?;
-
         let this = Self {
-            file: RefCell::new(file),
+            file,
             head,
+            written: RefCell::new(Vec::new()),
             cache: RefCell::new(HashMap::with_capacity(32)),
             dirty: RefCell::new(HashSet::with_capacity(32)),
             empty: RefCell::new(BinaryHeap::with_capacity(16)),
+            chain: RefCell::new(Vec::new()),
+            stale: Cell::new(false),
+            values: RefCell::new(HashMap::with_capacity(32)),
+            crypt,
+            map: RefCell::new(None),
+            checked: RefCell::new(HashSet::new()),
+            read_only: opts.read_only,
+            order,
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            indexes: RefCell::new(HashMap::new()),
+            clock: now,
+            merge: opts.merge.clone(),
+            undo: RefCell::new(None),
         };
 
+        if opts.mmap {
+            this.remap()?;
+        }
+
+        let mut buf = BytesMut::with_capacity(HEAD);
+        this.head.put(&mut buf);
+        *this.written.borrow_mut() = buf.to_vec();
+
+        let root = this.load(ROOT)?;
         this.cache.borrow_mut().insert(ROOT, root);
 
-        // TODO perform cleanup/compaction:
-        // get total number of pages based on file length and page size
-        // traverse the BTree, discover empty pages into self.empty
+        // Indexes are known from the catalog, but cannot be maintained until registered.
+        for name in this.buckets()? {
+            if let Some((root, INDEX, of)) = this.catalog_entry(&name)? {
+                this.indexes
+                    .borrow_mut()
+                    .entry(of)
+                    .or_default()
+                    .push(Index {
+                        name,
+                        root,
+                        extract: None,
+                    });
+            }
+        }
+
+        if !this.read_only {
+            this.load_chain()?;
+        }
 
         Ok(this)
     }
 
-    fn load(&self, offset: usize, length: u32) -> io::Result<P> {
-        let mut page = P::reserve(length as u32);
-        self.file
-            .borrow_mut()
-            .seek(SeekFrom::Start(offset as u64))?;
-        self.file.borrow_mut().read_exact(page.as_mut())?;
-        Ok(page)
+    /// Order of keys in the database.
+    fn order(&self) -> &dyn Comparator {
+        self.order.as_ref()
     }
 
-    fn save(&self, page: &P) -> io::Result<()> {
-        let offset = self.offset(page.id()) as u64;
-        self.file.borrow_mut().seek(SeekFrom::Start(offset))?;
-        self.file.borrow_mut().write_all(page.as_ref())
+    /// Capacity of a page: page size without encryption overhead (if any).
+    fn cap(&self) -> u32 {
+        match self.crypt {
+            Some(_) => self.head.page_bytes - crypt::OVERHEAD as u32,
+            None => self.head.page_bytes,
+        }
     }
 
-    fn offset(&self, id: u32) -> usize {
-        HEAD + (id - 1) as usize * self.head.page_bytes as usize
+    /// Get page representation to store on disk.
+    fn seal<'a>(&self, page: &'a P) -> Cow<'a, [u8]> {
+        match self.crypt.as_ref() {
+            Some(crypt) => Cow::Owned(crypt.encrypt(page.id(), page.as_ref())),
+            None => Cow::Borrowed(page.as_ref()),
+        }
     }
-}
-
-impl<P: Page> Tree<P> for File<P> {
-    fn lookup(&self, key: &[u8]) -> Result<Option<Ref<[u8]>>> {
-        let mut seen = HashSet::with_capacity(8);
-        let mut page = self.root();
-        loop {
-            let idx_opt = page.ceil(key);
-            if idx_opt.is_none() {
-                return Ok(None);
-            }
-            let idx = idx_opt.unwrap();
 
-            let slot_opt = page.slot(idx);
-            if slot_opt.is_none() {
-                return Err(Error::Tree(page.id(), format!("Slot not found: {}", idx)));
-            }
-            let slot = slot_opt.unwrap();
+    /// Read page from the storage and check its consistency.
+    fn load(&self, id: u32) -> Result<P> {
+        let mut page = P::reserve(self.cap());
+        self.read(id, page.as_mut())?;
+        if page.id() != id {
+            return Err(Error::CorruptPage {
+                id,
+                reason: format!("Page id mismatch: {}", page.id()),
+            });
+        }
+        page.check(self.order())?;
+        Ok(page)
+    }
 
-            if slot.page == 0 {
-                // Log how deep the lookup went into the tree depth: seen.len()
-                return if key == page.key(idx) {
-                    Ok(Some(Ref::map(page, |p| p.val(idx))))
-                } else {
-                    Ok(None)
-                };
-            } else {
-                let id = page.id();
-                drop(page);
-                if seen.contains(&slot.page) {
-                    return Err(Error::Tree(id, "Cyclic reference detected".to_string()));
-                }
-                seen.insert(id);
+    /// Read content of a page from the storage (copied from the mapping, if any).
+    fn read(&self, id: u32, buf: &mut [u8]) -> Result<()> {
+        if id == 0 {
+            return Err(Error::PageNotFound(id));
+        }
 
-                let page_opt = self.page(slot.page);
-                if page_opt.is_none() {
-                    return Err(Error::Tree(id, format!("Page not found: {}", slot.page)));
-                }
-                page = page_opt.unwrap();
+        let offset = self.offset(id);
+        let found = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::PageNotFound(id),
+            _ => Error::IO(e),
+        };
+        if let Some(map) = self.map.borrow().as_ref() {
+            let to = offset + self.head.page_bytes as u64;
+            if to > map.len() as u64 {
+                return Err(Error::PageNotFound(id));
+            }
+            buf.copy_from_slice(&map[(offset as usize)..(to as usize)]);
+        } else if let Some(crypt) = self.crypt.as_ref() {
+            let mut sealed = vec![0u8; self.head.page_bytes as usize];
+            self.file.read_at(&mut sealed, offset).map_err(found)?;
+            if !crypt.decrypt(id, &sealed, buf) {
+                return Err(Error::CorruptPage {
+                    id,
+                    reason: "Authentication failed".to_string(),
+                });
             }
+        } else {
+            self.file.read_at(buf, offset).map_err(found)?;
         }
+        Ok(())
     }
 
-    fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
-        let mut page = self.root_mut();
-        let mut seen = HashSet::with_capacity(8);
-        let mut path = Vec::with_capacity(8);
-        loop {
-            let id = page.id();
-            let parent_id = path.last().cloned().map(|(id, _)| id).unwrap_or_default();
-
-            if page.size() == 0 {
-                page.put_val(key, val);
-                drop(page);
-                return Ok(());
-            }
+    /// Make sure the page is in the cache.
+    fn cached(&self, id: u32) -> Result<()> {
+        if !self
+            .cache
+            .try_borrow()
+            .map_err(|_| Error::Busy)?
+            .contains_key(&id)
+        {
+            self.misses.set(self.misses.get() + 1);
+            let page = self.load(id)?;
+            self.cache
+                .try_borrow_mut()
+                .map_err(|_| Error::Busy)?
+                .insert(id, page);
+        } else {
+            self.hits.set(self.hits.get() + 1);
+        }
+        Ok(())
+    }
 
-            let idx = page.ceil(key).unwrap_or_else(|| page.size() - 1);
+    /// Collect statistics, visiting every page of the tree if `walk` is set (pages that are
+    /// not cached yet are loaded for the walk only, and do not populate the cache).
+    pub fn stats(&self, walk: bool) -> Result<Stats> {
+        let len = self.file.len()?.saturating_sub(HEAD as u64);
+        let mut stats = Stats {
+            page_bytes: self.head.page_bytes,
+            pages: len / self.head.page_bytes as u64,
+            free: (self.empty.borrow().len() + self.chain.borrow().len()) as u64,
+            keys: self.len()?,
+            buckets: self.buckets()?.len() as u64,
+            height: 1,
+            cached: self.cache.borrow().len() as u64,
+            dirty: self.dirty.borrow().len() as u64,
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+            walk: None,
+        };
 
-            drop(page);
-            if let Some((parent_id, parent_idx)) = path.last().cloned() {
-                let mut parent_page = self.page_mut(parent_id).unwrap();
-                let parent_key = parent_page.key(parent_idx);
-                if key > parent_key {
-                    parent_page.remove(parent_idx);
-                    parent_page.put_ref(key, id);
-                    drop(parent_page);
-                }
+        let mut seen = HashSet::with_capacity(8);
+        let mut id = ROOT;
+        while let Some(next) = self
+            .page(id)?
+            .slot(0)
+            .map(|slot| slot.page)
+            .filter(|p| *p > 0)
+        {
+            seen.insert(id);
+            if seen.contains(&next) {
+                return Err(Error::CycleDetected(id));
             }
-            page = self.page_mut(id).unwrap();
+            stats.height += 1;
+            id = next;
+        }
 
-            let slot_opt = page.slot(idx);
-            if slot_opt.is_none() {
-                return Err(Error::Tree(page.id(), format!("Slot not found: {}", idx)));
+        if walk {
+            let mut walk = Walk::default();
+            let mut full = 0u64;
+            let mut seen = HashSet::with_capacity(stats.pages as usize);
+            let mut stack = self.roots()?;
+            seen.extend(stack.iter().cloned());
+            while let Some(id) = stack.pop() {
+                let leaf = self.visit(id, |page| {
+                    full += page.full() as u64;
+                    let mut leaf = true;
+                    for idx in 0..page.size() {
+                        let slot = slot_at(page, idx)?;
+                        if slot.page > 0 {
+                            leaf = false;
+                            if !seen.insert(slot.page) {
+                                return Err(Error::CycleDetected(id));
+                            }
+                            stack.push(slot.page);
+                        } else {
+                            walk.key_bytes += page.key(idx).len() as u64;
+                            walk.val_bytes += slot.vlen as u64;
+                        }
+                    }
+                    Ok(leaf)
+                })?;
+                if leaf {
+                    walk.leaves += 1;
+                } else {
+                    walk.nodes += 1;
+                }
             }
-            let slot = slot_opt.unwrap();
+            let visited = walk.leaves + walk.nodes;
+            walk.full = full as f64 / visited as f64;
+            walk.unused = stats.pages.saturating_sub(visited);
+            stats.walk = Some(walk);
+        }
+        Ok(stats)
+    }
 
-            if slot.page == 0 {
-                let len = (key.len() + val.len()) as u32;
-                if !page.fits(len) {
-                    return Err(Error::Tree(
-                        page.id(),
-                        format!(
-                            "Entry does not fit into the page: size={} free={}",
-                            len,
-                            page.free()
-                        ),
-                    ));
+    /// Pages referenced by any tree (see `roots`), visited the same way as by the `stats` walk.
+    fn reachable(&self) -> Result<HashSet<u32>> {
+        let mut stack = self.roots()?;
+        let mut seen = stack.iter().cloned().collect::<HashSet<_>>();
+        while let Some(id) = stack.pop() {
+            self.visit(id, |page| {
+                for idx in 0..page.size() {
+                    let slot = slot_at(page, idx)?;
+                    if slot.page > 0 {
+                        if !seen.insert(slot.page) {
+                            return Err(Error::CycleDetected(id));
+                        }
+                        stack.push(slot.page);
+                    }
                 }
-                page.put_val(key, val);
-                let full = page.full();
-                drop(page);
+                Ok(())
+            })?;
+        }
+        Ok(seen)
+    }
 
-                if full > SPLIT_THRESHOLD {
-                    self.split(id, parent_id)?;
-                }
+    /// Apply `f` to the page: either cached one, or the one loaded for this call only
+    /// (so that walking the whole tree does not populate the cache).
+    fn visit<T>(&self, id: u32, f: impl FnOnce(&P) -> Result<T>) -> Result<T> {
+        if let Some(page) = self.cache.borrow().get(&id) {
+            return f(page);
+        }
+        f(&self.load(id)?)
+    }
 
-                while !path.is_empty() {
-                    let (page_id, _) = path.pop().unwrap();
-                    let (parent_id, _) = path.last().cloned().unwrap_or_default();
-                    let full = {
-                        let page = self.page(page_id).unwrap();
-                        page.full()
-                    };
-                    if full > SPLIT_THRESHOLD {
-                        self.split(page_id, parent_id)?;
+    /// Export structure of the tree (see `Export::to_dot` and `Export::to_json`), pages are
+    /// visited the same way as by the `stats` walk.
+    pub fn export(&self) -> Result<Export> {
+        let len = self.file.len()?.saturating_sub(HEAD as u64);
+        let pages = len / self.head.page_bytes as u64;
+        let mut export = Export {
+            page_bytes: self.head.page_bytes,
+            root: ROOT,
+            catalog: self.head.catalog,
+            expiry: self.head.expiry.get(),
+            history: self.head.history.get(),
+            ..Export::default()
+        };
+        for name in self.buckets()? {
+            if let Some(root) = self.bucket_root(&name)? {
+                export.buckets.push(BucketInfo { name, root });
+            }
+        }
+
+        let roots = self.roots()?;
+        let mut seen = roots.iter().cloned().collect::<HashSet<_>>();
+        let mut queue = roots.into_iter().map(|id| (id, 0)).collect::<VecDeque<_>>();
+        while let Some((id, parent)) = queue.pop_front() {
+            let info = self.visit(id, |page| {
+                let mut info = PageInfo {
+                    id,
+                    parent,
+                    leaf: true,
+                    full: page.full(),
+                    slots: Vec::with_capacity(page.size() as usize),
+                };
+                for idx in 0..page.size() {
+                    let slot = slot_at(page, idx)?;
+                    let key = hex(page.key(idx));
+                    if slot.page > 0 {
+                        info.leaf = false;
+                        if !seen.insert(slot.page) {
+                            return Err(Error::CycleDetected(id));
+                        }
+                        queue.push_back((slot.page, id));
+                        info.slots.push(SlotInfo {
+                            key,
+                            page: Some(slot.page),
+                            count: Some(page.count(idx)),
+                        });
+                    } else {
+                        info.slots.push(SlotInfo {
+                            key,
+                            ..SlotInfo::default()
+                        });
                     }
                 }
+                Ok(info)
+            })?;
+            export.pages.push(info);
+        }
+        export.free = (1..=pages as u32).filter(|id| !seen.contains(id)).collect();
+        Ok(export)
+    }
 
-                self.flush()?;
-                return Ok(());
+    /// Write the header (if changed since it was last written) and sync it.
+    fn write_head(&self) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(HEAD);
+        self.head.put(&mut buf);
+        if self.written.borrow().as_slice() == buf.as_ref() {
+            return Ok(());
+        }
+        self.file.write_at(buf.as_ref(), 0)?;
+        self.file.sync()?;
+        *self.written.borrow_mut() = buf.to_vec();
+        Ok(())
+    }
+
+    /// Write a new list of free pages (not synced), return pages holding it. All free pages are
+    /// listed, including pages of the current list (free once the header references the new
+    /// one). The list is held by the first free pages, the file grows if there are not enough.
+    fn write_chain(&self) -> Result<Vec<u32>> {
+        let cap = self.cap() as usize;
+        let per = (cap - CHAIN) / size_of::<u32>();
+        let mut free = self
+            .empty
+            .borrow()
+            .iter()
+            .map(|Reverse(id)| *id)
+            .collect::<Vec<_>>();
+        free.sort_unstable();
+        let mut count = free.len() + self.chain.borrow().len();
+        let (mut taken, mut grown) = (0, 0);
+        while count > (taken + grown) * per {
+            if taken < free.len() {
+                taken += 1;
+                count -= 1;
             } else {
-                path.push((id, idx));
-                seen.insert(id);
-                if seen.contains(&slot.page) {
-                    return Err(Error::Tree(id, "Cyclic reference detected".to_string()));
-                }
+                grown += 1;
+            }
+        }
 
-                drop(page);
-                let page_opt = self.page_mut(slot.page);
-                if page_opt.is_none() {
-                    return Err(Error::Tree(
-                        slot.page,
-                        format!("Page not found: {}", slot.page),
-                    ));
-                }
-                page = page_opt.unwrap();
+        let mut ids = free[..taken].to_vec();
+        let len = self.file.len()?.saturating_sub(HEAD as u64);
+        for n in 1..=grown as u64 {
+            match u32::try_from(len / self.head.page_bytes as u64 + n) {
+                Ok(id) => ids.push(id),
+                _ => return Err(Error::Unsupported(format!("file size {}", len))),
             }
         }
+        let mut listed = free[taken..].to_vec();
+        listed.extend(self.chain.borrow().iter());
+        listed.sort_unstable();
+
+        // A value may still be borrowed from the mapping: fail before the file grows.
+        let mut map = match grown {
+            0 => None,
+            _ => Some(self.map.try_borrow_mut().map_err(|_| Error::Busy)?),
+        };
+        let mut chunks = listed.chunks(per);
+        for (idx, id) in ids.iter().enumerate() {
+            let chunk = chunks.next().unwrap_or_default();
+            let mut buf = BytesMut::with_capacity(cap);
+            buf.put_u32(*id);
+            buf.put_u32(ids.get(idx + 1).cloned().unwrap_or_default());
+            buf.put_u32(chunk.len() as u32);
+            chunk.iter().for_each(|id| buf.put_u32(*id));
+            buf.resize(cap, 0);
+            let sealed = match self.crypt.as_ref() {
+                Some(crypt) => Cow::Owned(crypt.encrypt(*id, &buf)),
+                None => Cow::Borrowed(buf.as_ref()),
+            };
+            self.file.write_at(&sealed, self.offset(*id))?;
+        }
+        if let Some(map) = map.as_mut().filter(|map| map.is_some()) {
+            **map = self.file.map()?;
+        }
+        debug!("flush: free={} chain={:?}", listed.len(), ids);
+        Ok(ids)
     }
 
-    fn remove(&mut self, key: &[u8]) -> Result<()> {
-        let mut page = self.root_mut();
-        let mut seen = HashSet::with_capacity(8);
-        let mut path = Vec::with_capacity(8);
-        loop {
-            let idx_opt = page.ceil(key);
-            if idx_opt.is_none() {
-                return Ok(());
+    /// Read the list of free pages referenced by the header (see `write_chain`).
+    fn load_chain(&self) -> Result<()> {
+        let cap = self.cap() as usize;
+        let per = (cap - CHAIN) / size_of::<u32>();
+        let len = self.file.len()?.saturating_sub(HEAD as u64);
+        let pages = len / self.head.page_bytes as u64;
+        let mut chain = self.chain.borrow_mut();
+        let mut empty = self.empty.borrow_mut();
+        let (mut id, mut prev) = (self.head.free.get(), 0);
+        while id > 0 {
+            if chain.contains(&id) {
+                return Err(Error::CycleDetected(prev));
             }
-            let idx = idx_opt.unwrap();
-
-            let slot_opt = page.slot(idx);
-            if slot_opt.is_none() {
-                return Err(Error::Tree(page.id(), format!("Slot not found: {}", idx)));
+            let mut buf = vec![0u8; cap];
+            self.read(id, &mut buf)?;
+            let corrupt = |reason: String| Error::CorruptPage { id, reason };
+            let mut buf = buf.as_slice();
+            if buf.get_u32() != id {
+                return Err(corrupt("Page id mismatch".to_string()));
             }
-            let slot = slot_opt.unwrap();
-
-            let id = page.id();
-            if slot.page == 0 {
-                debug!("remove: key={} page={} idx={}", hex(key), id, idx);
-                page.remove(idx);
-                drop(page);
-
-                // Navigate up-tree and remove/update references if needed
-                let mut page_id = id;
-                for (parent_id, mut idx) in path.iter().cloned().rev() {
-                    let full = self.page(page_id).unwrap().full();
-                    if full < MERGE_THRESHOLD {
-                        let peer_id = {
-                            let parent = self.page(parent_id).unwrap();
-                            let mut peers = Vec::with_capacity(2);
-                            if idx > 0 {
-                                let peer = parent.slot(idx - 1).unwrap().page;
-                                peers.push(peer);
-                            }
-                            if idx < parent.size() - 1 {
-                                let peer = parent.slot(idx + 1).unwrap().page;
-                                peers.push(peer);
-                            }
-                            drop(parent);
-
-                            peers
-                                .into_iter()
-                                .filter_map(|peer_id| {
-                                    let peer = self.page(peer_id).unwrap();
-                                    let full = peer.full();
-                                    if peer.size() > 0 && full < MERGE_THRESHOLD {
-                                        Some((peer_id, full))
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .min_by_key(|(_, full)| *full)
-                                .map(|(peer_id, _)| peer_id)
-                        };
-                        if let Some(peer_id) = peer_id {
-                            trace!(
-                                "merge: found peer_id={} to merge page_id={} (parent_id={})",
-                                peer_id,
-                                page_id,
-                                parent_id
-                            );
-                            let peer_max = {
-                                let peer = self.page(peer_id).unwrap();
-                                peer.max().to_vec()
-                            };
-                            trace!("\t merge: peer_max={}", hex(&peer_max));
-                            let mut parent = self.page_mut(parent_id).unwrap();
-                            parent.remove(idx);
-                            let peer_idx = parent.ceil(&peer_max).unwrap();
-                            trace!("\t merge: parent remove: peer_idx={} idx={}", peer_idx, idx);
-                            parent.remove(peer_idx);
-                            drop(parent);
-
-                            self.merge(page_id, peer_id)?;
-                            let page_max = {
-                                let peer = self.page(peer_id).unwrap();
-                                peer.max().to_vec()
-                            };
-                            trace!("\t merge: page_max={}", hex(&page_max));
-                            let mut parent = self.page_mut(parent_id).unwrap();
-                            trace!(
-                                "\t merge: parent insert: page_max={}, peer_id={}",
-                                hex(&page_max),
-                                peer_id
-                            );
-                            parent.put_ref(&page_max, peer_id);
-                            idx = parent.ceil(&page_max).unwrap();
-                            page_id = peer_id;
-                        }
-                    }
-
-                    let max_opt = {
-                        let page = self.page(page_id).unwrap();
-                        if page.size() > 0 {
-                            Some(page.max().to_vec())
-                        } else {
-                            None
-                        }
-                    };
-
-                    let mut parent = self.page_mut(parent_id).unwrap();
-                    if let Some(max) = max_opt {
-                        if max < parent.key(idx).to_vec() {
-                            parent.remove(idx);
-                            parent.put_ref(&max, page_id);
-                        }
-                    } else {
-                        parent.remove(idx);
-                    }
-                    drop(parent);
-                    page_id = parent_id;
-                }
-
-                self.flush()?;
-                return Ok(());
-            } else {
-                path.push((id, idx));
-                seen.insert(id);
-                if seen.contains(&slot.page) {
-                    return Err(Error::Tree(id, "Cyclic reference detected".to_string()));
-                }
-                drop(page);
-
-                let page_opt = self.page_mut(slot.page);
-                if page_opt.is_none() {
-                    return Err(Error::Tree(id, format!("Page not found: {}", slot.page)));
+            let next = buf.get_u32();
+            let count = buf.get_u32() as usize;
+            if count > per {
+                return Err(corrupt(format!("Free pages overflow: {}", count)));
+            }
+            for _ in 0..count {
+                let free = buf.get_u32();
+                if free == 0 || free as u64 > pages {
+                    return Err(corrupt(format!("Free page not found: {}", free)));
                 }
-                page = page_opt.unwrap();
+                empty.push(Reverse(free));
             }
+            chain.push(id);
+            prev = id;
+            id = next;
         }
+        Ok(())
     }
 
-    fn is_empty(&self) -> bool {
-        self.root().size() == 0
+    /// The header references a new list of free pages: pages holding it are no longer free,
+    /// pages of the previous list are free (and listed in the new one).
+    fn listed(&self, ids: Vec<u32>) {
+        let mut empty = self.empty.borrow_mut();
+        // Pages holding the list were taken in order from the first free ones (if any).
+        for id in ids.iter() {
+            if empty.peek() == Some(&Reverse(*id)) {
+                empty.pop();
+            }
+        }
+        let old = std::mem::replace(&mut *self.chain.borrow_mut(), ids);
+        empty.extend(old.into_iter().map(Reverse));
+        self.stale.set(false);
     }
 
-    fn min(&self) -> Result<Option<Ref<[u8]>>> {
-        let mut page = self.root();
-        if page.size() == 0 {
-            return Ok(None);
-        }
-        loop {
-            let slot = page.slot(0).unwrap();
-            if slot.page == 0 {
-                return Ok(Some(Ref::map(page, |p| p.min())));
-            } else {
-                let id = slot.page;
-                if let Some(next) = self.page(id) {
-                    page = next;
-                } else {
-                    return Err(Error::Tree(id, "Page not found".to_string()));
-                }
-            }
+    /// Find pages that are neither used by a tree nor listed as free (e.g. left behind by an
+    /// interrupted flush) and make them free, return the number of pages found. Every page of
+    /// every tree is visited: a page that cannot be read fails the call and nothing is changed.
+    pub fn reclaim(&mut self) -> Result<u64> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
         }
+        let mut used = self.reachable()?;
+        used.extend(self.chain.borrow().iter());
+        used.extend(self.empty.borrow().iter().map(|Reverse(id)| *id));
+        let len = self.file.len()?.saturating_sub(HEAD as u64);
+        let pages = (len / self.head.page_bytes as u64) as u32;
+        let found = (1..=pages)
+            .filter(|id| !used.contains(id))
+            .collect::<Vec<_>>();
+        found.iter().for_each(|id| self.free_id(*id));
+        self.flush()?;
+        debug!("reclaim: pages={}", found.len());
+        Ok(found.len() as u64)
     }
 
-    fn max(&self) -> Result<Option<Ref<[u8]>>> {
-        let mut page = self.root();
-        if page.size() == 0 {
+    /// Root page of a bucket with given name (if any).
+    fn bucket_root(&self, name: &str) -> Result<Option<u32>> {
+        Ok(self.catalog_entry(name)?.map(|(root, _, _)| root))
+    }
+
+    /// Root page, mode and indexed tree (root page, 0 unless the bucket is an index) of a bucket
+    /// with given name (if any): the catalog entry is the root page id, followed by a mode byte
+    /// for a bucket that is not a plain one, and by the root page id of the indexed tree.
+    fn catalog_entry(&self, name: &str) -> Result<Option<(u32, u8, u32)>> {
+        let catalog = self.head.catalog;
+        if catalog == 0 {
             return Ok(None);
         }
-        loop {
-            let last = page.size() - 1;
-            let slot = page.slot(last).unwrap();
-            if slot.page == 0 {
-                return Ok(Some(Ref::map(page, |p| p.max())));
-            } else {
-                let id = slot.page;
-                if let Some(next) = self.page(id) {
-                    page = next;
-                } else {
-                    return Err(Error::Tree(id, "Page not found".to_string()));
+        let id = |val: &[u8]| <[u8; 4]>::try_from(val).ok().map(u32::from_be_bytes);
+        match self.lookup_in(catalog, name.as_bytes())? {
+            Some(val) => match (val.len(), id(&val[..val.len().min(4)])) {
+                (4, Some(root)) => Ok(Some((root, PLAIN, 0))),
+                (5, Some(root)) if val[4] != INDEX => Ok(Some((root, val[4], 0))),
+                (9, Some(root)) if val[4] == INDEX => {
+                    Ok(Some((root, INDEX, id(&val[5..]).unwrap_or_default())))
                 }
-            }
+                _ => Err(Error::CorruptPage {
+                    id: catalog,
+                    reason: format!("Bucket root is not a page id: {}", hex(&val)),
+                }),
+            },
+            None => Ok(None),
         }
     }
 
-    fn above(&self, key: &[u8]) -> Result<Option<Ref<[u8]>>> {
-        let mut path = Vec::with_capacity(8);
-        let mut page = self.root();
-        if page.size() == 0 {
-            return Ok(None);
+    /// Root pages of all trees: the main one, the catalog, the buckets, the expiry tree and
+    /// the history tree (if any).
+    fn roots(&self) -> Result<Vec<u32>> {
+        let mut roots = vec![ROOT];
+        if self.head.expiry.get() > 0 {
+            roots.push(self.head.expiry.get());
         }
-        loop {
-            let idx = page.ceil(key).unwrap();
-            let slot = page.slot(idx).unwrap();
-            if slot.page == 0 {
-                return if key < page.key(idx) {
-                    Ok(Some(Ref::map(page, |p| p.key(idx))))
-                } else if key == page.key(idx) && idx < page.size() - 1 {
-                    Ok(Some(Ref::map(page, |p| p.key(idx + 1))))
-                } else {
-                    // ceil == key, need to take min value from parent's next adjacent subtree
-                    for (parent_id, parent_idx) in path.iter().rev().cloned() {
-                        page = self.page(parent_id).unwrap();
-                        if parent_idx < page.size() - 1 {
-                            let id = page.slot(parent_idx + 1).unwrap().page;
-                            page = self.page(id).unwrap();
-                            loop {
-                                let slot = page.slot(0).unwrap();
-                                if slot.page == 0 {
-                                    return Ok(Some(Ref::map(page, |p| p.min())));
-                                } else {
-                                    page = self.page(slot.page).unwrap();
-                                }
-                            }
-                        }
-                    }
-
-                    // key seems to be the maximum stored value in the tree
-                    Ok(None)
-                };
-            } else {
-                path.push((page.id(), idx));
-                let id = slot.page;
-                if let Some(next) = self.page(id) {
-                    page = next;
-                } else {
-                    return Err(Error::Tree(id, "Page not found".to_string()));
-                }
+        if self.head.history.get() > 0 {
+            roots.push(self.head.history.get());
+        }
+        if self.head.catalog > 0 {
+            roots.push(self.head.catalog);
+            for name in self.buckets()? {
+                roots.extend(self.bucket_root(&name)?);
             }
         }
+        Ok(roots)
     }
 
-    fn below(&self, key: &[u8]) -> Result<Option<Ref<[u8]>>> {
-        let mut path = Vec::with_capacity(8);
-        let mut page = self.root();
-        if page.size() == 0 {
-            return Ok(None);
-        }
-        loop {
-            let idx = page.ceil(key).unwrap();
-            let slot = page.slot(idx).unwrap();
-            if slot.page == 0 {
-                return if idx > 0 && key > page.key(idx - 1) {
-                    Ok(Some(Ref::map(page, |p| p.key(idx - 1))))
-                } else {
-                    // ceil == key, need to take max value from parent's previous adjacent page
-                    for (parent_id, parent_idx) in path.iter().rev().cloned() {
-                        page = self.page(parent_id).unwrap();
-                        if parent_idx > 0 {
-                            let idx = parent_idx - 1;
-                            let id = page.slot(idx).unwrap().page;
-                            page = self.page(id).unwrap();
-                            return Ok(Some(Ref::map(page, |p| p.max())));
-                        }
-                    }
+    /// Open an existing bucket.
+    pub fn bucket(&self, name: &str) -> Result<Bucket<'_, P, S>> {
+        let root = self.bucket_of(name, PLAIN)?;
+        Ok(Bucket {
+            file: self,
+            name: name.to_string(),
+            root,
+        })
+    }
 
-                    // key seems to be the maximum stored value in the tree
-                    Ok(None)
-                };
-            } else {
-                path.push((page.id(), idx));
-                let id = slot.page;
-                if let Some(next) = self.page(id) {
-                    page = next;
-                } else {
-                    return Err(Error::Tree(id, "Page not found".to_string()));
-                }
-            }
+    /// Open an existing multimap bucket.
+    pub fn multimap(&self, name: &str) -> Result<Multimap<'_, P, S>> {
+        let root = self.bucket_of(name, MULTIMAP)?;
+        Ok(Multimap {
+            file: self,
+            name: name.to_string(),
+            root,
+        })
+    }
+
+    /// Root page of an existing bucket, that must be of given mode.
+    fn bucket_of(&self, name: &str, mode: u8) -> Result<u32> {
+        let (root, found, _) = self
+            .catalog_entry(name)?
+            .ok_or_else(|| Error::BucketNotFound(name.to_string()))?;
+        if found != mode {
+            return Err(Error::Unsupported(format!(
+                "bucket '{}' is {}",
+                name,
+                mode_name(found)
+            )));
         }
+        // Root page of the bucket stays cached as long as the root of the main tree does.
+        self.cached(root)?;
+        Ok(root)
     }
 
-    fn root(&self) -> Ref<P> {
-        self.page(ROOT).unwrap()
+    /// Create an empty bucket: a named tree with its own root page (the catalog of buckets is
+    /// created along with the first bucket).
+    pub fn create_bucket(&mut self, name: &str) -> Result<Bucket<'_, P, S>> {
+        self.create_tree(name, PLAIN, 0)?;
+        self.bucket(name)
     }
 
-    fn page(&self, id: u32) -> Option<Ref<P>> {
-        if !self.cache.borrow().contains_key(&id) {
-            let page = self.load(self.offset(id), self.head.page_bytes).ok()?;
-            self.cache.borrow_mut().insert(id, page);
+    /// Create an empty multimap bucket (see `Multimap`).
+    pub fn create_multimap(&mut self, name: &str) -> Result<Multimap<'_, P, S>> {
+        if self.order().name() != Bytewise.name() {
+            return Err(Error::Unsupported(format!(
+                "multimap with comparator '{}'",
+                self.order().name()
+            )));
         }
-        let page = Ref::map(self.cache.borrow(), |cache| cache.get(&id).unwrap());
-        Some(page)
+        self.create_tree(name, MULTIMAP, 0)?;
+        self.multimap(name)
     }
 
-    fn root_mut(&self) -> RefMut<P> {
-        self.mark(ROOT);
-        self.page_mut(ROOT).unwrap()
+    /// Allocate the root page of a new bucket of given mode and list it in the catalog (along
+    /// with the root page of the indexed tree, for an index). Returns the root page.
+    fn create_tree(&mut self, name: &str, mode: u8, indexed: u32) -> Result<u32> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.bucket_root(name)?.is_some() {
+            return Err(Error::BucketExists(name.to_string()));
+        }
+        if self.head.catalog == 0 {
+            self.head.catalog = self.next_id()?;
+        }
+        let root = self.next_id()?;
+        let mut entry = root.to_be_bytes().to_vec();
+        if mode != PLAIN {
+            entry.push(mode);
+        }
+        if mode == INDEX {
+            entry.extend_from_slice(&indexed.to_be_bytes());
+        }
+        if let Err(e) = self.insert_in(self.head.catalog, name.as_bytes(), &entry) {
+            self.free_id(root);
+            return Err(e);
+        }
+        Ok(root)
     }
 
-    fn page_mut(&self, id: u32) -> Option<RefMut<P>> {
-        if !self.cache.borrow().contains_key(&id) {
-            let page = self.load(self.offset(id), self.head.page_bytes).ok()?;
-            self.cache.borrow_mut().insert(id, page);
+    /// Names of all buckets, multimap and index ones included (in the order of the database
+    /// comparator).
+    pub fn buckets(&self) -> Result<Vec<String>> {
+        let catalog = self.head.catalog;
+        let mut names = Vec::new();
+        if catalog == 0 {
+            return Ok(names);
         }
-        let page = RefMut::map(self.cache.borrow_mut(), |cache| cache.get_mut(&id).unwrap());
-        self.mark(id);
-        Some(page)
+        let mut next = self.min_in(catalog)?.map(|key| key.to_vec());
+        while let Some(key) = next {
+            next = self.above_in(catalog, &key)?.map(|key| key.to_vec());
+            names.push(String::from_utf8_lossy(&key).into_owned());
+        }
+        Ok(names)
     }
 
-    fn mark(&self, id: u32) {
-        self.dirty.borrow_mut().insert(id);
+    /// Rename a bucket. The catalog entry of the new name is stored before the old one is
+    /// removed: if interrupted (e.g. by an I/O error), the bucket is listed under both names.
+    pub fn rename_bucket(&mut self, from: &str, to: &str) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let root = self
+            .bucket_root(from)?
+            .ok_or_else(|| Error::BucketNotFound(from.to_string()))?;
+        if self.bucket_root(to)?.is_some() {
+            return Err(Error::BucketExists(to.to_string()));
+        }
+        let catalog = self.head.catalog;
+        let entry = self
+            .lookup_in(catalog, from.as_bytes())?
+            .map(|entry| entry.to_vec())
+            .unwrap_or_default();
+        self.insert_in(catalog, to.as_bytes(), &entry)?;
+        self.remove_in(catalog, from.as_bytes())?;
+        for index in self.indexes.borrow_mut().values_mut().flatten() {
+            if index.root == root {
+                index.name = to.to_string();
+            }
+        }
+        Ok(())
     }
 
-    fn flush(&self) -> crate::api::error::Result<()> {
-        let pages = {
-            let result = self.dirty.borrow().iter().cloned().collect::<Vec<_>>();
-            self.dirty.borrow_mut().clear();
-            result
-        };
-        for id in pages {
-            if let Some(page) = self.page(id) {
-                self.save(page.deref())?;
-                debug!("flush: page={}", id);
-            } else {
-                return Err(Error::Tree(id, "Page not found".to_string()));
+    /// Remove a bucket with all its entries, pages of the bucket become free. Indexes of the
+    /// bucket (registered or not) are removed first.
+    pub fn drop_bucket(&mut self, name: &str) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let root = self
+            .bucket_root(name)?
+            .ok_or_else(|| Error::BucketNotFound(name.to_string()))?;
+        for index in self.buckets()? {
+            if matches!(self.catalog_entry(&index)?, Some((_, INDEX, of)) if of == root) {
+                self.drop_bucket(&index)?;
             }
         }
 
-        Ok(())
-    }
+        let mut pages = Vec::with_capacity(16);
+        let mut seen = HashSet::with_capacity(16);
+        let mut stack = vec![root];
+        seen.insert(root);
+        while let Some(id) = stack.pop() {
+            let page = self.page(id)?;
+            for idx in 0..page.size() {
+                let slot = slot_at(page.deref(), idx)?;
+                if slot.page > 0 {
+                    if !seen.insert(slot.page) {
+                        return Err(Error::CycleDetected(id));
+                    }
+                    stack.push(slot.page);
+                }
+            }
+            pages.push(id);
+        }
 
-    fn next_id(&self) -> Result<u32> {
-        if !self.empty.borrow().is_empty() {
-            let id = self.empty.borrow_mut().pop().unwrap().0;
-            let temp = P::create(id, self.head.page_bytes);
-            let mut page = self.page_mut(id).unwrap();
-            page.as_mut().copy_from_slice(temp.as_ref());
-            return Ok(id);
+        // Deadlines of the entries are dropped along with the bucket (flushed together).
+        let expiry = self.head.expiry.get();
+        if expiry > 0 {
+            let prefix = encoding::encode(&(DEADLINE, root));
+            let mut next = self.above_in(expiry, &prefix)?.map(|key| key.to_vec());
+            while let Some(entry) = next.filter(|key| key.starts_with(&prefix)) {
+                next = self.above_in(expiry, &entry)?.map(|key| key.to_vec());
+                let (_, _, key) = self.decode_expiry::<(u8, u32, Vec<u8>)>(&entry)?;
+                let at = self.deadline(&entry)?.unwrap_or_default();
+                self.delete_in(expiry, &encoding::encode(&(SCHEDULE, at, root, key)))?;
+                self.delete_in(expiry, &entry)?;
+            }
         }
 
-        let len = self.file.borrow_mut().metadata().unwrap().len();
-        let id = 1 + ((len - HEAD as u64) / self.head.page_bytes as u64) as u32;
-        let page = P::create(id, self.head.page_bytes);
+        // Pages are released only after the bucket is unlinked from the catalog.
+        self.remove_in(self.head.catalog, name.as_bytes())?;
         {
-            let mut f = self.file.borrow_mut();
-            f.seek(SeekFrom::End(0))?;
-            f.write_all(page.as_ref())?;
+            let mut indexes = self.indexes.borrow_mut();
+            indexes.remove(&root);
+            indexes
+                .values_mut()
+                .for_each(|list| list.retain(|i| i.root != root));
         }
-
-        Ok(id)
+        for id in pages {
+            self.page_mut(id)?.clear();
+            self.free_id(id);
+        }
+        self.flush()
     }
 
-    fn free_id(&self, id: u32) {
-        self.empty.borrow_mut().push(Reverse(id))
+    /// Register a secondary index of a tree (the main one if `tree` is not set, or a bucket):
+    /// the index is stored in a bucket of given name, keyed by the index key `extract`ed from
+    /// each value. The bucket is created and filled from the existing entries if it does not
+    /// exist. Extractors are not persisted: indexes must be registered each time the database
+    /// is opened, until then writes to the indexed tree fail with `IndexNotRegistered`.
+    pub fn create_index<F>(&mut self, tree: Option<&str>, name: &str, extract: F) -> Result<()>
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + 'static,
+    {
+        if self.order().name() != Bytewise.name() {
+            // Index keys are composite, thus they rely on the byte order of the encoding.
+            return Err(Error::Unsupported(format!(
+                "index with comparator '{}'",
+                self.order().name()
+            )));
+        }
+        let root = match tree {
+            Some(tree) => self
+                .bucket_root(tree)?
+                .ok_or_else(|| Error::BucketNotFound(tree.to_string()))?,
+            None => ROOT,
+        };
+        let registered = self
+            .indexes
+            .borrow()
+            .values()
+            .flatten()
+            .any(|i| i.name == name && i.extract.is_some());
+        if registered || tree == Some(name) {
+            return Err(Error::BucketExists(name.to_string()));
+        }
+
+        let index = match self.catalog_entry(name)? {
+            Some((id, INDEX, of)) if of == root => id,
+            Some((_, INDEX, _)) => {
+                return Err(Error::Unsupported(format!(
+                    "bucket '{}' is an index of another tree",
+                    name
+                )))
+            }
+            Some((_, mode, _)) => {
+                return Err(Error::Unsupported(format!(
+                    "bucket '{}' is {}",
+                    name,
+                    mode_name(mode)
+                )))
+            }
+            None => {
+                let id = self.create_tree(name, INDEX, root)?;
+                let empty = self.codec().encode(&[]).len();
+                let mut next = self.min_in(root)?.map(|key| key.to_vec());
+                while let Some(key) = next {
+                    let ikey = match self.lookup_in(root, &key)? {
+                        Some(val) => extract(&val),
+                        None => None,
+                    };
+                    if let Some(ikey) = ikey {
+                        let entry = index_key(&ikey, &key);
+                        self.entry_len(entry.len(), empty)?;
+                        self.put_in(id, &entry, &[])?;
+                    }
+                    next = self.above_in(root, &key)?.map(|key| key.to_vec());
+                }
+                self.flush()?;
+                id
+            }
+        };
+        self.cached(index)?;
+        let mut indexes = self.indexes.borrow_mut();
+        let indexes = indexes.entry(root).or_default();
+        let extract = Some(Rc::new(extract) as Rc<Extract>);
+        match indexes.iter_mut().find(|i| i.root == index) {
+            Some(known) => known.extract = extract,
+            None => indexes.push(Index {
+                name: name.to_string(),
+                root: index,
+                extract,
+            }),
+        }
+        Ok(())
     }
 
-    fn split(&self, id: u32, parent_id: u32) -> Result<()> {
-        if id == ROOT {
-            let lo_id = self.next_id()?;
-            let hi_id = self.next_id()?;
-            debug!(
-                "split: root={} into lo={} and hi={} (parent={})",
-                id, lo_id, hi_id, parent_id
-            );
+    /// Primary keys of the entries having given index key (ascending).
+    pub fn index_lookup(&self, index: &str, ikey: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let bounds = (Bound::Included(ikey), Bound::Included(ikey));
+        let found = self.index_range(index, bounds)?;
+        Ok(found.into_iter().map(|(_, key)| key).collect())
+    }
 
-            let (copy, lo_max, hi_max) = {
-                let page = self.page(id).unwrap();
-                let copy = page.copy();
-                let half = page.size() as usize / 2;
-                let lo_max = copy.get(half - 1).map(|(k, _, _)| k).cloned().unwrap();
-                let hi_max = copy.last().map(|(k, _, _)| k).cloned().unwrap();
-                (copy, lo_max, hi_max)
+    /// Entries of the index (index key, primary key) with index keys within given bounds.
+    pub fn index_range(
+        &self,
+        index: &str,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let root = self.bucket_of(index, INDEX)?;
+        // Entries of an index key follow its encoding, which is a prefix of their keys.
+        let mut next = match bounds.0 {
+            Bound::Included(ikey) | Bound::Excluded(ikey) => {
+                let prefix = encoding::encode(&(ikey.to_vec(),));
+                self.above_in(root, &prefix)?.map(|key| key.to_vec())
+            }
+            Bound::Unbounded => self.min_in(root)?.map(|key| key.to_vec()),
+        };
+        let mut found = Vec::new();
+        while let Some(key) = next {
+            let (ikey, pkey) =
+                encoding::decode::<(Vec<u8>, Vec<u8>)>(&key).ok_or_else(|| Error::CorruptPage {
+                    id: root,
+                    reason: format!("Malformed index entry: {}", hex(&key)),
+                })?;
+            let after = match bounds.1 {
+                Bound::Included(hi) => ikey.as_slice() > hi,
+                Bound::Excluded(hi) => ikey.as_slice() >= hi,
+                Bound::Unbounded => false,
             };
-            let half = copy.len() / 2;
-
-            {
-                let mut lo = self.page_mut(lo_id).unwrap();
-                copy.iter().take(half).for_each(|(key, val, page)| {
-                    trace!(
-                        "split: move k={} v={} p={} from {} to {}",
-                        hex(key),
-                        hex(val),
-                        *page,
-                        id,
-                        lo_id
-                    );
-                    if *page == 0 {
-                        lo.put_val(key, val);
-                    } else {
-                        lo.put_ref(key, *page);
-                    }
-                });
+            if after {
+                break;
             }
-
-            {
-                let mut hi = self.page_mut(hi_id).unwrap();
-                copy.iter().skip(half).for_each(|(key, val, page)| {
-                    trace!(
-                        "split: move k={} v={} p={} from {} to {}",
-                        hex(key),
-                        hex(val),
-                        *page,
-                        id,
-                        hi_id
-                    );
-                    if *page == 0 {
-                        hi.put_val(key, val);
-                    } else {
-                        hi.put_ref(key, *page);
-                    }
-                });
+            next = self.above_in(root, &key)?.map(|key| key.to_vec());
+            if !matches!(bounds.0, Bound::Excluded(lo) if lo == ikey.as_slice()) {
+                found.push((ikey, pkey));
             }
+        }
+        Ok(found)
+    }
 
-            {
-                let mut page = self.page_mut(id).unwrap();
-                page.clear();
-                page.put_ref(&lo_max, lo_id);
-                page.put_ref(&hi_max, hi_id);
-            }
+    /// Replace the value of a key (in the main tree) only if the current one is `expected`
+    /// (none: the key is absent). Returns whether the value was replaced. As all the atomic
+    /// read-modify-write operations, done in a single descent of the tree (see `modify_in`).
+    pub fn compare_and_swap(
+        &mut self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: &[u8],
+    ) -> Result<bool> {
+        let (_, swapped) = self.modify_in(ROOT, key, None, |old| {
+            (old == expected).then_some(Cow::Borrowed(new))
+        })?;
+        Ok(swapped)
+    }
 
-            Ok(())
-        } else {
-            let (copy, max) = {
-                let page = self.page(id).unwrap();
-                (page.copy(), page.max().to_vec())
-            };
-            let half = copy.len() / 2;
-            let peer_id = self.next_id()?;
-            debug!(
-                "split: page={} into peer={} (parent={})",
-                id, peer_id, parent_id
-            );
+    /// Update the value of a key: `f` gets the current value (none if the key is absent) and
+    /// returns the new one (none leaves the entry as is). Returns the resulting value.
+    pub fn update<F>(&mut self, key: &[u8], f: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        let mut new = None;
+        let (old, updated) = self.modify_in(ROOT, key, None, |old| {
+            new = f(old);
+            new.clone().map(Cow::Owned)
+        })?;
+        Ok(if updated { new } else { old })
+    }
 
-            let page_max = {
-                let mut page = self.page_mut(id).unwrap();
-                copy.iter().skip(half).for_each(|(key, _, _)| {
-                    let idx = page.find(key).unwrap();
-                    page.remove(idx);
-                });
-                page.max().to_vec()
-            };
+    /// Get the value of a key, storing given one first if the key is absent.
+    pub fn get_or_insert(&mut self, key: &[u8], default: &[u8]) -> Result<Vec<u8>> {
+        let (old, _) = self.modify_in(ROOT, key, None, |old| {
+            old.is_none().then_some(Cow::Borrowed(default))
+        })?;
+        Ok(old.unwrap_or_else(|| default.to_vec()))
+    }
 
-            let peer_max = {
-                let mut peer = self.page_mut(peer_id).unwrap();
-                copy.iter().skip(half).for_each(|(key, val, p)| {
-                    trace!(
-                        "split: move k={} v={} p={} from {} to {}",
-                        hex(key),
-                        hex(val),
-                        *p,
-                        id,
-                        peer_id
-                    );
-                    if *p == 0 {
-                        peer.put_val(key, val);
-                    } else {
-                        peer.put_ref(key, *p);
-                    }
-                });
-                peer.max().to_vec()
-            };
+    /// Store an entry only if the key is absent. Returns whether the entry was stored.
+    pub fn insert_if_absent(&mut self, key: &[u8], val: &[u8]) -> Result<bool> {
+        let (_, inserted) = self.modify_in(ROOT, key, None, |old| {
+            old.is_none().then_some(Cow::Borrowed(val))
+        })?;
+        Ok(inserted)
+    }
 
-            {
-                let mut parent = self.page_mut(parent_id).unwrap();
-                let idx = parent.find(&max).unwrap();
-                parent.remove(idx);
-                parent.put_ref(&page_max, id);
-                parent.put_ref(&peer_max, peer_id);
-            }
+    /// Combine the value of a key (in the main tree) with given operand by the merge operator of
+    /// the database: the current value is read and replaced within a single descent of the tree
+    /// (see `modify_in`). Fails with `BadOperand` if the operator rejects the operand.
+    pub fn merge_value(&mut self, key: &[u8], operand: &[u8]) -> Result<()> {
+        let op = self
+            .merge
+            .clone()
+            .ok_or_else(|| Error::Unsupported("merge without operator".to_string()))?;
+        let (_, merged) = self.modify_in(ROOT, key, None, |old| {
+            op.merge(key, old, operand).map(Cow::Owned)
+        })?;
+        if !merged {
+            return Err(Error::BadOperand(op.name().to_string()));
+        }
+        Ok(())
+    }
+
+    /// Store an entry (in the main tree) that expires after given time: once the deadline
+    /// passes, the entry is hidden from lookups and scans (`min`, `max`, `above`, `below`), but it
+    /// is still counted (`len`, `rank`, ...) until removed. Expired entries are removed in the
+    /// order of their deadlines: a few on each insert/remove, or all by `purge_expired`. A plain
+    /// insert (or remove) of the key drops its deadline.
+    pub fn insert_with_ttl(&mut self, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        self.insert_with_ttl_in(ROOT, key, val, ttl)
+    }
 
-            Ok(())
+    /// Sequence number of the last write to the main tree of a versioned database (0 if there
+    /// was none): each insert or remove of an entry gets the next one.
+    pub fn sequence(&self) -> Result<u64> {
+        self.last_seq()
+    }
+
+    /// Value of a key (in the main tree of a versioned database) as of given sequence number:
+    /// as it was right after the write of that number. Reads before the horizon (of retained
+    /// versions) fail with `Pruned`.
+    pub fn lookup_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        match self.history_at(seq)? {
+            0 => Ok(None),
+            history => self.value_at(history, key, seq),
         }
     }
 
-    fn merge(&self, src_id: u32, dst_id: u32) -> Result<()> {
-        debug!("merge: src={} into dst={}", src_id, dst_id);
-        let src_copy = {
-            let page = self.page(src_id).unwrap();
-            page.copy()
+    /// Entries (of the main tree of a versioned database) within given bounds as of given
+    /// sequence number (see `lookup_at`), in ascending order of keys.
+    pub fn scan_at(
+        &self,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+        seq: u64,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let history = match self.history_at(seq)? {
+            0 => return Ok(vec![]),
+            history => history,
         };
-
-        {
-            let mut page = self.page_mut(dst_id).unwrap();
-            for (key, val, p) in src_copy {
-                trace!(
-                    "merge: move k={} v={} p={} from {} to {}",
-                    hex(&key),
-                    hex(&val),
-                    p,
-                    src_id,
-                    dst_id
-                );
-                if p == 0 {
-                    page.put_val(&key, &val);
-                } else {
-                    page.put_ref(&key, p);
+        // Versions of a key are adjacent, the next key follows the upper bound of its versions.
+        let versions = encoding::encode(&(VERSION,));
+        let mut next = match bounds.0 {
+            Bound::Included(key) => self.above_in(history, &versions_of(key).0)?,
+            Bound::Excluded(key) => self.above_in(history, &versions_of(key).1)?,
+            Bound::Unbounded => self.above_in(history, &versions)?,
+        }
+        .map(|entry| entry.to_vec());
+        let mut found = Vec::new();
+        while let Some(entry) = next.filter(|entry| entry.starts_with(&versions)) {
+            let (_, key, _) = encoding::decode::<(u8, Vec<u8>, u64)>(&entry).ok_or_else(|| {
+                Error::CorruptPage {
+                    id: history,
+                    reason: format!("Malformed version key: {}", hex(&entry)),
                 }
+            })?;
+            let after = match bounds.1 {
+                Bound::Included(hi) => key.as_slice() > hi,
+                Bound::Excluded(hi) => key.as_slice() >= hi,
+                Bound::Unbounded => false,
+            };
+            if after {
+                break;
+            }
+            next = self
+                .above_in(history, &versions_of(&key).1)?
+                .map(|entry| entry.to_vec());
+            if let Some(val) = self.value_at(history, &key, seq)? {
+                found.push((key, val));
             }
-            page.max().to_vec()
-        };
-
-        {
-            let mut page = self.page_mut(src_id).unwrap();
-            page.clear();
         }
+        Ok(found)
+    }
 
-        self.free_id(src_id);
-        Ok(())
+    /// Remove all expired entries (of all trees), returns the number of removed entries.
+    pub fn purge_expired(&mut self) -> Result<u64> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let removed = self.sweep(usize::MAX)?;
+        self.flush()?;
+        Ok(removed)
     }
 
-    fn dump(&self) -> String {
-        fn dump_page<P: Page>(
-            file: &File<P>,
-            page_id: u32,
-            parent_id: u32,
-            acc: &mut String,
-            prefix: String,
-            tab: String,
-        ) {
-            if page_id == 0 {
-                return;
-            } else {
-                let page = file.page(page_id).unwrap();
-                let copy = page.copy();
-                let full = page.full();
+    /// Follow a reference from page `from` to page `id`, making sure no page is visited twice.
+    fn follow(&self, seen: &mut HashSet<u32>, from: u32, id: u32) -> Result<Ref<'_, P>> {
+        seen.insert(from);
+        if seen.contains(&id) {
+            return Err(Error::CycleDetected(from));
+        }
+        self.page(id)
+    }
 
-                acc.push_str(&if copy.is_empty() {
-                    format!("{}page={}: empty", prefix, page_id)
-                } else {
-                    let entries = copy
-                        .iter()
-                        .map(|(k, v, p)| format!("{}{}, {}, {}", prefix, hex(&k), hex(&v), p))
-                        .collect::<Vec<_>>()
-                        .join("\n");
-                    format!(
-                        "{}page={}: (parent={}) {}% full\n{}",
-                        prefix, page_id, parent_id, full, entries
-                    )
-                });
+    /// Smallest key stored in the subtree of given page.
+    fn lowest(&self, id: u32) -> Result<Ref<'_, [u8]>> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut page = self.page(id)?;
+        loop {
+            let slot = slot_at(page.deref(), 0)?;
+            if slot.page == 0 {
+                return Ok(Ref::map(page, |p| p.min()));
+            }
+            let id = page.id();
+            drop(page);
+            page = self.follow(&mut seen, id, slot.page)?;
+        }
+    }
 
-                acc.push('\n');
-                let links = copy.iter().map(|(_, _, p)| p).cloned().collect::<Vec<_>>();
+    /// Biggest key stored in the subtree of given page.
+    fn highest(&self, id: u32) -> Result<Ref<'_, [u8]>> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut page = self.page(id)?;
+        loop {
+            let slot = slot_at(page.deref(), last(page.deref())?)?;
+            if slot.page == 0 {
+                return Ok(Ref::map(page, |p| p.max()));
+            }
+            let id = page.id();
+            drop(page);
+            page = self.follow(&mut seen, id, slot.page)?;
+        }
+    }
 
-                links.into_iter().for_each(|id| {
-                    let mut p = prefix.clone();
-                    p.push_str(&tab);
-                    dump_page(file, id, page_id, acc, p, tab.clone());
-                });
+    /// Number of keys strictly lesser than given one (`None` goes after the last key), if the
+    /// key itself is present, and the leaf page the key belongs to. Entries of subtrees left of
+    /// the path are summed up from the counts of node pages.
+    fn position(&self, root: u32, key: Option<&[u8]>) -> Result<(u64, bool, u32)> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut page = self.page(root)?;
+        let mut rank = 0;
+        loop {
+            let leaf = page.slot(0).map(|slot| slot.page == 0).unwrap_or(true);
+            let idx = match key.and_then(|key| page.ceil(key, self.order())) {
+                Some(idx) => idx,
+                None if leaf => return Ok((rank + page.size() as u64, false, page.id())),
+                // Key is above all separators: it goes after every key of the last subtree.
+                None => last(page.deref())?,
+            };
+            let slot = slot_at(page.deref(), idx)?;
+            if slot.page == 0 {
+                let found = key
+                    .map(|key| self.order().cmp(page.key(idx), key) == Ordering::Equal)
+                    .unwrap_or_default();
+                return Ok((rank + idx as u64, found, page.id()));
             }
+            rank += (0..idx).map(|i| page.count(i)).sum::<u64>();
+            let id = page.id();
+            drop(page);
+            page = self.follow(&mut seen, id, slot.page)?;
         }
+    }
 
-        let mut acc = String::with_capacity(1024);
-        dump_page(&self, ROOT, 0, &mut acc, "".to_string(), "\t".to_string());
-        acc
+    /// Leaf page holding the smallest key (root page of an empty tree).
+    fn leftmost(&self, root: u32) -> Result<u32> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut page = self.page(root)?;
+        while let Some(slot) = page.slot(0).filter(|slot| slot.page > 0) {
+            let id = page.id();
+            drop(page);
+            page = self.follow(&mut seen, id, slot.page)?;
+        }
+        Ok(page.id())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::disk::block::Block;
-    use crate::util::hex::hex;
-    use rand::prelude::StdRng;
-    use rand::seq::SliceRandom;
-    use rand::{thread_rng, RngCore, SeedableRng};
-    use std::borrow::Borrow;
-    use std::ops::Deref;
+    /// Number of keys preceding given bound of a range (for an upper bound: keys up to the end
+    /// of the range), and the leaf page the bound belongs to.
+    fn bound(&self, root: u32, bound: Bound<&[u8]>, upper: bool) -> Result<(u64, u32)> {
+        let (rank, found, leaf) = match bound {
+            Bound::Included(key) | Bound::Excluded(key) => self.position(root, Some(key))?,
+            Bound::Unbounded if upper => self.position(root, None)?,
+            Bound::Unbounded => (0, false, self.leftmost(root)?),
+        };
+        let after = matches!(
+            (bound, upper),
+            (Bound::Included(_), true) | (Bound::Excluded(_), false)
+        );
+        Ok((rank + (found && after) as u64, leaf))
+    }
 
-    fn get<P: Page>(page: &P, key: &[u8]) -> Option<(Vec<u8>, u32)> {
-        page.find(key)
-            .map(|idx| (page.val(idx).to_vec(), page.slot(idx).unwrap().page))
+    /// Copy of a page, to be changed without affecting the page itself (see `store`).
+    fn scratch(&self, id: u32) -> Result<P> {
+        let mut copy = P::reserve(self.cap());
+        copy.as_mut().copy_from_slice(self.page(id)?.as_ref());
+        Ok(copy)
     }
 
-    #[test]
-    fn test_page() {
-        let path = Path::new("target/test_page.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
+    /// Replace content of a page with given (changed) copy of it.
+    fn store(&self, copy: &P) -> Result<()> {
+        let mut page = self.page_mut(copy.id())?;
+        page.as_mut().copy_from_slice(copy.as_ref());
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn save(&self, page: &P) -> io::Result<()> {
+        self.file.write_at(&self.seal(page), self.offset(page.id()))
+    }
+
+    fn offset(&self, id: u32) -> u64 {
+        self.head.offset(id)
+    }
+
+    /// (Re-)create memory mapping of the whole file.
+    fn remap(&self) -> Result<()> {
+        match self.file.map()? {
+            Some(map) => {
+                *self.map.try_borrow_mut().map_err(|_| Error::Busy)? = Some(map);
+                Ok(())
+            }
+            None => Err(Error::Unsupported("memory mapping by storage".to_string())),
         }
-        let size: u32 = 256;
+    }
 
-        let data = vec![
-            (b"aaa".to_vec(), b"zxczxczxc".to_vec(), 0),
-            (b"bbb".to_vec(), b"asdasdasd".to_vec(), 0),
-            (b"ccc".to_vec(), b"qweqweqwe".to_vec(), 0),
-            (b"ddd".to_vec(), b"123123123".to_vec(), 0),
-            (b"xxx".to_vec(), vec![], 3333),
-            (b"yyy".to_vec(), vec![], 2222),
-            (b"zzz".to_vec(), vec![], 1111),
-        ];
+    fn codec(&self) -> Codec {
+        Codec::from_id(self.head.codec).unwrap_or_default()
+    }
 
-        {
-            let file: File<Block> = File::make(path, size).unwrap();
-            {
-                let mut page = file.root_mut();
-                for (k, v, p) in data.iter() {
-                    if *p == 0 {
-                        page.put_val(k, v);
-                    } else {
-                        page.put_ref(k, *p);
-                    }
+    /// Get a value stored in given slot of given page, decoding it if necessary.
+    fn value<'a>(&'a self, page: Ref<'a, P>, idx: u32) -> Result<Ref<'a, [u8]>> {
+        let codec = self.codec();
+        if codec == Codec::None {
+            let id = page.id();
+            if self.map.borrow().is_some() && !self.dirty.borrow().contains(&id) {
+                // Clean page is the same in the cache and on the disk: borrow from the mapping.
+                if let Some(slot) = page.slot(idx) {
+                    let at = (self.offset(id) + slot.offset as u64 + slot.klen as u64) as usize;
+                    let to = at + slot.vlen as usize;
+                    drop(page);
+                    return Ok(Ref::map(self.map.borrow(), |map| {
+                        map.as_ref().map(|map| &map[at..to]).unwrap_or_default()
+                    }));
+                }
+            }
+            return Ok(Ref::map(page, |p| p.val(idx)));
+        }
+
+        let id = page.id();
+        let cached = self
+            .values
+            .borrow()
+            .get(&id)
+            .map(|values| values.contains_key(&idx))
+            .unwrap_or_default();
+        if !cached {
+            let val = match codec.decode(page.val(idx)) {
+                Some(val) => val.into_owned(),
+                None => {
+                    return Err(Error::CorruptPage {
+                        id,
+                        reason: format!("Corrupt value: {}", idx),
+                    })
                 }
             };
-            let page = file.root();
-            file.save(page.deref()).unwrap();
+            drop(page);
+            self.values
+                .borrow_mut()
+                .entry(id)
+                .or_default()
+                .insert(idx, val);
         }
+        Ok(Ref::map(self.values.borrow(), |values| {
+            values[&id][&idx].as_slice()
+        }))
+    }
+}
 
-        let file: File<Block> = File::open(path).unwrap();
-        let mut page = file.load(file.offset(ROOT), size).unwrap();
+/// Lock the storage (see `Io::lock`), a conflicting lock held by someone else is `Locked`.
+fn lock<S: Io>(file: &S, exclusive: bool) -> Result<()> {
+    file.lock(exclusive).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock => Error::Locked,
+        _ => Error::IO(e),
+    })
+}
 
-        assert_eq!(page.copy(), data);
+/// Milliseconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
 
-        for (k, v, p) in data.iter() {
-            assert_eq!(get(&page, k), Some((v.to_vec(), *p)));
-        }
+/// Mode of a bucket as told in errors.
+fn mode_name(mode: u8) -> &'static str {
+    match mode {
+        MULTIMAP => "multimap",
+        INDEX => "an index",
+        _ => "plain",
+    }
+}
 
-        page.remove(page.find(b"aaa").unwrap());
-        assert_eq!(get(&page, b"aaa"), None);
+/// Get a slot of given index, missing slot means the page is corrupt.
+fn slot_at<V: View>(page: &V, idx: u32) -> Result<Slot> {
+    page.slot(idx).ok_or_else(|| Error::CorruptPage {
+        id: page.id(),
+        reason: format!("Slot not found: {}", idx),
+    })
+}
 
-        page.remove(page.find(b"zzz").unwrap());
-        assert_eq!(get(&page, b"zzz"), None);
+/// Slot of a page where a lookup of the key continues (with its index): the slot holding the key
+/// in a leaf page, or the one referencing the child page to descend to (none if the key is not
+/// in the subtree).
+fn step<V: View>(page: &V, key: &[u8], cmp: &dyn Comparator) -> Result<Option<(u32, Slot)>> {
+    let idx = match page.ceil(key, cmp) {
+        Some(idx) => idx,
+        None => return Ok(None),
+    };
+    let slot = slot_at(page, idx)?;
+    if slot.page == 0 && cmp.cmp(key, page.key(idx)) != Ordering::Equal {
+        return Ok(None);
     }
+    Ok(Some((idx, slot)))
+}
 
-    #[test]
-    fn test_file() {
-        let path = Path::new("target/test_file.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
-        }
-        let size: u32 = 256;
+/// Index of the last slot of a page, only a root page can be empty.
+fn last<P: Page>(page: &P) -> Result<u32> {
+    match page.size() {
+        0 => Err(Error::CorruptPage {
+            id: page.id(),
+            reason: "Page is empty".to_string(),
+        }),
+        n => Ok(n - 1),
+    }
+}
 
-        let data = vec![
-            (b"uno".to_vec(), b"la squadra azzurra".to_vec()),
-            (b"due".to_vec(), b"it's coming home".to_vec()),
-            (b"tre".to_vec(), b"red devils".to_vec()),
-        ];
+/// Modification of an index tree or the expiry tree (by its root page).
+enum Change {
+    Put(u32, Vec<u8>, Vec<u8>),
+    Remove(u32, Vec<u8>),
+}
 
-        let mut file: File<Block> = File::make(path, size).unwrap();
+/// Changes of a write in progress to undo if it fails: pages as they were before their first
+/// change, page ids taken from (or returned to) the free ones, and the root of the history tree
+/// (created by the first write).
+struct Undo<P> {
+    pages: HashMap<u32, P>,
+    taken: Vec<u32>,
+    freed: Vec<u32>,
+    history: u32,
+}
 
-        for (k, v) in data.iter() {
-            file.insert(k, v).unwrap();
+impl<P> Undo<P> {
+    fn new(history: u32) -> Self {
+        Self {
+            pages: HashMap::with_capacity(8),
+            taken: Vec::new(),
+            freed: Vec::new(),
+            history,
         }
+    }
 
-        for (k, v) in data.iter() {
-            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
-            file.remove(k).unwrap();
+    /// Page id is allocated: a page freed by the same write is simply in use again.
+    fn take(&mut self, id: u32) {
+        match self.freed.iter().position(|x| *x == id) {
+            Some(at) => {
+                self.freed.swap_remove(at);
+            }
+            None => self.taken.push(id),
         }
+    }
 
-        for (k, _) in data.iter() {
-            assert!(file.lookup(k).unwrap().is_none());
+    /// Page id is freed: a page allocated by the same write is simply free again.
+    fn free(&mut self, id: u32) {
+        match self.taken.iter().position(|x| *x == id) {
+            Some(at) => {
+                self.taken.swap_remove(at);
+            }
+            None => self.freed.push(id),
         }
+    }
+}
 
-        let root = file.root();
-        assert_eq!(root.copy(), vec![]);
+/// Bounds of the multimap entries of a key: all of them start with the encoded key, which
+/// ends with a terminator (`00 01`), thus the entries of greater keys follow the upper bound.
+fn pairs_of(key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let lo = encoding::encode(&(key.to_vec(),));
+    let mut hi = lo.clone();
+    if let Some(last) = hi.last_mut() {
+        *last += 1;
     }
+    (lo, hi)
+}
 
-    #[test]
-    fn test_split() {
-        let path = Path::new("target/test_split.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
-        }
+/// Key of a version in the history tree: the key followed by the complement of the sequence
+/// number, thus versions of a key are ordered from the latest one.
+fn version_key(key: &[u8], seq: u64) -> Vec<u8> {
+    encoding::encode(&(VERSION, key.to_vec(), !seq))
+}
 
-        let size: u32 = 256;
-        let mut file: File<Block> = File::make(path, size).unwrap();
+/// Bounds of the versions of given key in the history tree: the common prefix of their keys,
+/// and the least key greater than all of them.
+fn versions_of(key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let lo = encoding::encode(&(VERSION, key.to_vec()));
+    let mut hi = lo.clone();
+    if let Some(last) = hi.last_mut() {
+        *last += 1;
+    }
+    (lo, hi)
+}
 
-        let count = 25;
-        let data = (0..count)
-            .into_iter()
-            .map(|i| {
-                let c = 'a' as u8 + (i % ('z' as u8 - 'a' as u8 + 1) as u8 as u64) as u8;
-                (vec![c; 8], vec![c; 8])
-            })
-            .collect::<Vec<_>>();
+/// Key of an index entry: index key followed by the primary key.
+fn index_key(ikey: &[u8], key: &[u8]) -> Vec<u8> {
+    encoding::encode(&(ikey.to_vec(), key.to_vec()))
+}
 
-        for (k, v) in data.iter() {
-            file.insert(k, v).unwrap();
-        }
+/// Number of entries stored in the subtrees of given (copied) page entries.
+fn weight(copy: &[(Vec<u8>, Vec<u8>, u32)]) -> u64 {
+    copy.iter()
+        .map(|(_, val, page)| if *page == 0 { 1 } else { get_count(val) })
+        .sum()
+}
 
-        for (k, v) in data.iter() {
-            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
-        }
+/// Store an entry taken from a page (see `Page::copy`): a value or a reference.
+fn put_copy<P: Page>(
+    page: &mut P,
+    (key, val, p): &(Vec<u8>, Vec<u8>, u32),
+    cmp: &dyn Comparator,
+) -> Option<u32> {
+    if *p == 0 {
+        page.put_val(key, val, cmp)
+    } else {
+        page.put_ref(key, *p, get_count(val), cmp)
     }
+}
 
-    #[test]
-    fn test_merge() {
-        let path = Path::new("target/test_merge.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
-        }
+/// Result of storing an entry that fits into the page by construction (an entry moved by a
+/// split, or a reference replacing removed ones): not fitting means the page is corrupt.
+fn fitted<T>(put: Option<T>, id: u32, key: &[u8]) -> Result<T> {
+    put.ok_or_else(|| Error::CorruptPage {
+        id,
+        reason: format!("Entry does not fit: {}", hex(key)),
+    })
+}
 
-        let size: u32 = 256;
-        let mut file: File<Block> = File::make(path, size).unwrap();
+/// Shortest key separating lower half (`..half`) of page entries from the upper one.
+/// For a node page, entry keys are already separators of child pages and have to be kept as-is.
+fn split_key(copy: &[(Vec<u8>, Vec<u8>, u32)], half: usize, cmp: &dyn Comparator) -> Vec<u8> {
+    let (lo, _, page) = &copy[half - 1];
+    if *page == 0 {
+        separator(lo, &copy[half].0, cmp)
+    } else {
+        lo.clone()
+    }
+}
 
-        let count = 25;
-        let data = {
-            let mut rng = StdRng::seed_from_u64(3);
-            let mut result = (0..count)
-                .into_iter()
-                .map(|i| {
-                    let c = 'a' as u8 + (i % ('z' as u8 - 'a' as u8 + 1) as u8 as u64) as u8;
-                    (vec![c; 8], vec![c; 8])
-                })
-                .collect::<Vec<_>>();
-            result.shuffle(&mut rng);
-            result
-        };
+/// Tree operations on the tree with given root page: the main tree (`ROOT`) or a bucket.
+impl<P: Page, S: Io> File<P, S> {
+    fn lookup_in(&self, root: u32, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        if self.codec() == Codec::None && self.map.borrow().is_some() {
+            return self.lookup_mapped(root, key);
+        }
+        match self.find_in(root, key)? {
+            Some((page, idx)) => self.value(page, idx).map(Some),
+            None => Ok(None),
+        }
+    }
 
-        for (k, v) in data.iter() {
-            debug!("insert: key={} val={}", hex(k), hex(v));
-            file.insert(k, v).unwrap();
+    /// Lookup reading pages that are not cached in place from the mapping: such pages are neither
+    /// copied nor cached, and a value found in one is borrowed from the mapping. Cached pages are
+    /// read from the cache (a dirty one differs from the mapped page until flushed).
+    fn lookup_mapped(&self, root: u32, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut id = root;
+        loop {
+            let cached = self
+                .cache
+                .try_borrow()
+                .map_err(|_| Error::Busy)?
+                .contains_key(&id);
+            let slot = if cached {
+                let page = self.page(id)?;
+                match step(page.deref(), key, self.order())? {
+                    Some((idx, slot)) if slot.page == 0 => return self.value(page, idx).map(Some),
+                    Some((_, slot)) => slot,
+                    None => return Ok(None),
+                }
+            } else {
+                let map = self.map.borrow();
+                let found = step(&self.mapped(map.as_ref(), id)?, key, self.order())?;
+                match found {
+                    Some((_, slot)) if slot.page == 0 => {
+                        let at = (self.offset(id) + slot.offset as u64 + slot.klen as u64) as usize;
+                        let to = at + slot.vlen as usize;
+                        return Ok(Some(Ref::map(map, |map| {
+                            map.as_ref().map(|map| &map[at..to]).unwrap_or_default()
+                        })));
+                    }
+                    Some((_, slot)) => slot,
+                    None => return Ok(None),
+                }
+            };
+            seen.insert(id);
+            if seen.contains(&slot.page) {
+                return Err(Error::CycleDetected(id));
+            }
+            id = slot.page;
         }
-        debug!("{}", file.dump());
+    }
 
-        let keys = {
-            let mut rng = StdRng::seed_from_u64(3);
-            let mut result = data.iter().map(|(k, _)| k).cloned().collect::<Vec<_>>();
-            result.shuffle(&mut rng);
-            result
+    /// Page read in place from the mapping, checked on its first read.
+    fn mapped<'a>(&self, map: Option<&'a Mmap>, id: u32) -> Result<P::Mapped<'a>> {
+        let at = self.offset(id) as usize;
+        let to = at + self.head.page_bytes as usize;
+        let buf = match map {
+            Some(map) if id > 0 && to <= map.len() => &map[at..to],
+            _ => return Err(Error::PageNotFound(id)),
         };
+        let page = P::map(buf);
+        if !self.checked.borrow().contains(&id) {
+            if page.id() != id {
+                return Err(Error::CorruptPage {
+                    id,
+                    reason: format!("Page id mismatch: {}", page.id()),
+                });
+            }
+            page.check(self.order())?;
+            self.checked.borrow_mut().insert(id);
+        }
+        Ok(page)
+    }
 
-        let mut removed = HashSet::with_capacity(size as usize);
-        for key in keys.iter() {
-            debug!("remove: key={}", hex(key));
-            file.remove(key).unwrap();
-            removed.insert(key.to_vec());
-            for (k, v) in data.iter() {
-                if removed.contains(k) {
-                    assert!(file.lookup(k).unwrap().is_none());
-                } else {
-                    assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
-                }
+    /// Get the key as stored in the tree (if present).
+    fn key_in(&self, root: u32, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        let found = self.find_in(root, key)?;
+        Ok(found.map(|(page, idx)| Ref::map(page, |p| p.key(idx))))
+    }
+
+    /// Leaf page holding given key and the index of the key in it (if present).
+    fn find_in(&self, root: u32, key: &[u8]) -> Result<Option<(Ref<'_, P>, u32)>> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut page = self.page(root)?;
+        loop {
+            let (idx, slot) = match step(page.deref(), key, self.order())? {
+                Some(found) => found,
+                None => return Ok(None),
+            };
+            if slot.page == 0 {
+                return Ok(Some((page, idx)));
             }
+            let id = page.id();
+            drop(page);
+            page = self.follow(&mut seen, id, slot.page)?;
         }
-        debug!("{}", file.dump());
+    }
 
-        let root = file.root();
-        let copy = root.copy();
-        assert_eq!(copy, vec![]);
+    fn insert_in(&self, root: u32, key: &[u8], val: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store_in(root, key, val, None)
     }
 
-    #[test]
-    fn test_above() {
-        let path = Path::new("target/test_above.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
+    /// Store an entry expiring at given deadline (if set, otherwise the entry never expires).
+    /// Returns the replaced value (none if the key was absent or expired).
+    fn store_in(
+        &self,
+        root: u32,
+        key: &[u8],
+        val: &[u8],
+        deadline: Option<u64>,
+    ) -> Result<Option<Vec<u8>>> {
+        self.modify_in(root, key, deadline, |_| Some(Cow::Borrowed(val)))
+            .map(|(old, _)| old)
+    }
+
+    /// Read-modify-write of an entry, read and stored in a single descent of the tree: `f`
+    /// decides the value to store from the current one (none if the key is absent or expired),
+    /// nothing is stored if it returns none. The stored entry expires at given deadline (if set).
+    /// Other trees are accessed only when in use: the expiry tree (the deadline of the key is
+    /// read once), the history tree and secondary indexes. All their entries are checked before
+    /// anything is modified, a failure while updating them undoes the whole write (see `atomic`).
+    /// Some expired entries are removed once the write is done (see `expire`), and the pages are
+    /// flushed. Returns the current value and whether the new one was stored.
+    fn modify_in<'v, F>(
+        &self,
+        root: u32,
+        key: &[u8],
+        deadline: Option<u64>,
+        f: F,
+    ) -> Result<(Option<Vec<u8>>, bool)>
+    where
+        F: FnOnce(Option<&[u8]>) -> Option<Cow<'v, [u8]>>,
+    {
+        if self.read_only {
+            return Err(Error::ReadOnly);
         }
+        let mut changes = Vec::new();
+        let (mut expired, mut stored) = (false, false);
+        let old = self.atomic(|| {
+            let old = self.upsert_in(root, key, |old| {
+                let at = self.deadline_of(root, key)?;
+                expired = old.is_some() && self.due(at);
+                let val = match f(old.filter(|_| !expired)) {
+                    Some(val) => val,
+                    None => return Ok(None),
+                };
+                changes = self.index_changes(root, key, old, Some(&val))?;
+                changes.extend(self.expiry_changes(root, key, at, deadline)?);
+                changes.extend(self.history_changes(root, key, Some(&val))?);
+                stored = true;
+                Ok(Some(val))
+            })?;
+            self.apply(std::mem::take(&mut changes))?;
+            Ok(old)
+        })?;
+        self.expire();
+        self.flush()?;
+        Ok((old.filter(|_| !expired), stored))
+    }
 
-        let size: u32 = 256;
-        let mut file: File<Block> = File::make(path, size).unwrap();
+    /// Remove an entry in a single descent of the tree. Other trees are accessed only when in
+    /// use: the expiry tree (the deadline of the key is read once), the history tree and
+    /// secondary indexes, updated as a whole (see `atomic`), then some expired entries are
+    /// removed (see `expire`). Returns the removed value (none if the key is absent or expired).
+    fn remove_in(&self, root: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut removed = self.remove_keys_in(root, &[key])?;
+        Ok(removed.pop().flatten())
+    }
 
-        let count = 10;
-        let mut data = {
-            let mut rng = StdRng::seed_from_u64(3);
+    /// Remove entries of given keys as a whole (see `remove_in`), pages are flushed once.
+    /// Returns the removed values.
+    fn remove_keys_in(&self, root: u32, keys: &[&[u8]]) -> Result<Vec<Option<Vec<u8>>>> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let removed = self.atomic(|| {
+            keys.iter()
+                .map(|key| self.erase_in(root, key))
+                .collect::<Result<Vec<_>>>()
+        })?;
+        self.expire();
+        self.flush()?;
+        Ok(removed)
+    }
+
+    /// Remove an entry along with its index, expiry and history entries (pages are not flushed).
+    /// Returns the removed value (none if the key is absent or expired).
+    fn erase_in(&self, root: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let at = self.deadline_of(root, key)?;
+        let expired = self.due(at);
+        let mut changes = self.expiry_changes(root, key, at, None)?;
+        // History entries are checked before the descent, and dropped if the key was absent.
+        let history = self.history_changes(root, key, None)?;
+        let old = self.delete_in(root, key)?;
+        if old.is_some() {
+            changes.extend(history);
+        }
+        // Only removals: nothing to check, thus index entries are found after the descent.
+        changes.extend(self.index_changes(root, key, old.as_deref(), None)?);
+        self.apply(changes)?;
+        Ok(old.filter(|_| !expired))
+    }
+
+    /// Run a write spanning several trees (an entry and its index, expiry and history entries)
+    /// as a whole: if it fails, every page it changed is restored from the copy taken before the
+    /// first change (see `page_mut`), and page allocations are undone. Pages are not flushed.
+    fn atomic<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        if self.undo.borrow().is_some() {
+            // Part of an enclosing write.
+            return f();
+        }
+        *self.undo.borrow_mut() = Some(Undo::new(self.head.history.get()));
+        let result = f();
+        let undo = self.undo.borrow_mut().take();
+        if let (Err(e), Some(undo)) = (result.as_ref(), undo) {
+            debug!("rollback: pages={} error={}", undo.pages.len(), e);
+            self.rollback(undo);
+        }
+        result
+    }
+
+    /// Restore pages changed by a failed write (and mark them dirty, as some may have been
+    /// flushed meanwhile), return pages it allocated and take back the ones it freed.
+    fn rollback(&self, undo: Undo<P>) {
+        self.head.history.set(undo.history);
+        self.stale.set(true);
+        {
+            let mut empty = self.empty.borrow_mut();
+            empty.retain(|Reverse(id)| !undo.freed.contains(id));
+            empty.extend(undo.taken.iter().map(|id| Reverse(*id)));
+        }
+        // A page can be changed only while no value is borrowed (see `page_mut`).
+        if let Ok(mut values) = self.values.try_borrow_mut() {
+            for id in undo.pages.keys() {
+                values.remove(id);
+            }
+        }
+        if let Ok(mut cache) = self.cache.try_borrow_mut() {
+            let mut dirty = self.dirty.borrow_mut();
+            for (id, page) in undo.pages {
+                cache.insert(id, page);
+                dirty.insert(id);
+            }
+        }
+    }
+
+    /// Store an entry that expires after given time (see `insert_with_ttl`).
+    fn insert_with_ttl_in(&self, root: u32, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        if self.order().name() != Bytewise.name() {
+            return Err(Error::Unsupported(format!(
+                "expiry with comparator '{}'",
+                self.order().name()
+            )));
+        }
+        if self.head.expiry.get() == 0 {
+            self.head.expiry.set(self.next_id()?);
+        }
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let deadline = (self.clock)().saturating_add(ttl);
+        self.store_in(root, key, val, Some(deadline)).map(|_| ())
+    }
+
+    /// Expiry tree entries to remove and to add when the deadline of given key in the tree
+    /// changes from `old` (entries without a deadline are not present in the expiry tree).
+    fn expiry_changes(
+        &self,
+        root: u32,
+        key: &[u8],
+        old: Option<u64>,
+        deadline: Option<u64>,
+    ) -> Result<Vec<Change>> {
+        let expiry = self.head.expiry.get();
+        if expiry == 0 {
+            return Ok(vec![]);
+        }
+        let entry = encoding::encode(&(DEADLINE, root, key.to_vec()));
+        let mut changes = Vec::with_capacity(3);
+        if old == deadline {
+            return Ok(changes);
+        }
+        if let Some(at) = old {
+            changes.push(Change::Remove(
+                expiry,
+                encoding::encode(&(SCHEDULE, at, root, key.to_vec())),
+            ));
+        }
+        match deadline {
+            Some(at) => {
+                let due = encoding::encode(&(SCHEDULE, at, root, key.to_vec()));
+                let val = at.to_be_bytes().to_vec();
+                self.entry_len(entry.len(), self.codec().encode(&val).len())?;
+                self.entry_len(due.len(), self.codec().encode(&[]).len())?;
+                changes.push(Change::Put(expiry, entry, val));
+                changes.push(Change::Put(expiry, due, vec![]));
+            }
+            None => changes.push(Change::Remove(expiry, entry)),
+        }
+        Ok(changes)
+    }
+
+    /// Deadline stored in the expiry tree under given entry (if any).
+    fn deadline(&self, entry: &[u8]) -> Result<Option<u64>> {
+        let expiry = self.head.expiry.get();
+        match self.lookup_in(expiry, entry)? {
+            Some(val) => match <[u8; 8]>::try_from(val.as_ref()) {
+                Ok(at) => Ok(Some(u64::from_be_bytes(at))),
+                Err(_) => Err(Error::CorruptPage {
+                    id: expiry,
+                    reason: format!("Deadline is not a timestamp: {}", hex(&val)),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn decode_expiry<T: encoding::KeyPart>(&self, entry: &[u8]) -> Result<T> {
+        encoding::decode(entry).ok_or_else(|| Error::CorruptPage {
+            id: self.head.expiry.get(),
+            reason: format!("Malformed expiry entry: {}", hex(entry)),
+        })
+    }
+
+    /// Deadline of given key in the tree (none if there is no expiry tree).
+    fn deadline_of(&self, root: u32, key: &[u8]) -> Result<Option<u64>> {
+        if self.head.expiry.get() == 0 {
+            return Ok(None);
+        }
+        self.deadline(&encoding::encode(&(DEADLINE, root, key.to_vec())))
+    }
+
+    /// Check if given deadline has passed.
+    fn due(&self, deadline: Option<u64>) -> bool {
+        matches!(deadline, Some(at) if at <= (self.clock)())
+    }
+
+    /// Check if the entry of given key has expired (but is not removed yet).
+    fn expired(&self, root: u32, key: &[u8]) -> Result<bool> {
+        Ok(self.due(self.deadline_of(root, key)?))
+    }
+
+    /// Remove (at most `limit`) expired entries in the order of their deadlines, along with their
+    /// index entries. Pages are not flushed. Returns the number of removed entries.
+    fn sweep(&self, limit: usize) -> Result<u64> {
+        let expiry = self.head.expiry.get();
+        if expiry == 0 {
+            return Ok(0);
+        }
+        let now = (self.clock)();
+        let mut removed = 0;
+        while removed < limit {
+            // Scheduled entries follow all the deadlines of keys (by the tag).
+            let due = match self.above_in(expiry, &[SCHEDULE])? {
+                Some(due) => due.to_vec(),
+                None => break,
+            };
+            let (_, at, root, key) = self.decode_expiry::<(u8, u64, u32, Vec<u8>)>(&due)?;
+            if at > now {
+                break;
+            }
+            trace!("expire: root={} key={} deadline={}", root, hex(&key), at);
+            // Each entry is removed along with its index, history and expiry entries as a whole.
+            self.atomic(|| {
+                let history = self.history_changes(root, &key, None)?;
+                let old = self.delete_in(root, &key)?;
+                self.apply(self.index_changes(root, &key, old.as_deref(), None)?)?;
+                self.apply(history)?;
+                self.delete_in(expiry, &due)?;
+                self.delete_in(expiry, &encoding::encode(&(DEADLINE, root, key)))
+            })?;
+            removed += 1;
+        }
+        Ok(removed as u64)
+    }
+
+    /// Remove a few expired entries once a write is done (flushed along with it). A failure is
+    /// only logged: it does not fail the write, the entries are removed later.
+    fn expire(&self) {
+        if self.head.expiry.get() == 0 {
+            return;
+        }
+        if let Err(e) = self.sweep(SWEEP) {
+            warn!("sweep: {}", e);
+        }
+    }
+
+    /// Lookup hiding expired entries.
+    fn live_lookup_in(&self, root: u32, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        if self.expired(root, key)? {
+            return Ok(None);
+        }
+        self.lookup_in(root, key)
+    }
+
+    /// First key of a scan that has not expired: starting at `first`, moving with `next`.
+    fn live_in<'a, F>(
+        &'a self,
+        root: u32,
+        first: Option<Ref<'a, [u8]>>,
+        next: F,
+    ) -> Result<Option<Ref<'a, [u8]>>>
+    where
+        F: Fn(&[u8]) -> Result<Option<Ref<'a, [u8]>>>,
+    {
+        if self.head.expiry.get() == 0 {
+            return Ok(first);
+        }
+        let mut key = first.map(|key| key.to_vec());
+        while let Some(k) = key {
+            if !self.expired(root, &k)? {
+                // Checking expiry may load pages, thus a key cannot be borrowed meanwhile.
+                return self.key_in(root, &k);
+            }
+            key = next(&k)?.map(|key| key.to_vec());
+        }
+        Ok(None)
+    }
+
+    /// Writes to the tree are versioned.
+    fn versioned(&self, root: u32) -> bool {
+        root == ROOT && self.head.horizon > 0
+    }
+
+    /// Last sequence number stored in the history tree (0 if there was no write yet).
+    fn last_seq(&self) -> Result<u64> {
+        let history = self.head.history.get();
+        if history == 0 {
+            return Ok(0);
+        }
+        match self.lookup_in(history, SEQ)? {
+            Some(val) => match <[u8; 8]>::try_from(val.as_ref()) {
+                Ok(seq) => Ok(u64::from_be_bytes(seq)),
+                Err(_) => Err(Error::CorruptPage {
+                    id: history,
+                    reason: format!("Sequence number is malformed: {}", hex(&val)),
+                }),
+            },
+            None => Ok(0),
+        }
+    }
+
+    /// Root page of the history tree, created on the first write that changes an entry.
+    fn history_root(&self) -> Result<u32> {
+        if self.head.history.get() == 0 {
+            self.head.history.set(self.next_id()?);
+        }
+        Ok(self.head.history.get())
+    }
+
+    /// History tree entries of a write of given key (`val` is none for a removal) in versioned
+    /// mode: the version under the next sequence number, the sequence number itself, and removal
+    /// of the versions that the write pushes behind the horizon: of the key written under the
+    /// cutoff, only the version at the cutoff is still needed (unless it is a removal). New
+    /// entries are checked to fit into a page.
+    fn history_changes(&self, root: u32, key: &[u8], val: Option<&[u8]>) -> Result<Vec<Change>> {
+        if !self.versioned(root) {
+            return Ok(vec![]);
+        }
+        if val.is_none() && self.head.history.get() == 0 {
+            // No write yet, thus no key to remove.
+            return Ok(vec![]);
+        }
+        let history = self.history_root()?;
+        let seq = self.last_seq()? + 1;
+        let entry = version_key(key, seq);
+        let mut version = Vec::with_capacity(1 + val.map(|val| val.len()).unwrap_or_default());
+        version.push(val.is_some() as u8);
+        version.extend_from_slice(val.unwrap_or_default());
+        let write = encoding::encode(&(WRITE, seq));
+        self.entry_len(entry.len(), self.codec().encode(&version).len())?;
+        self.entry_len(write.len(), self.codec().encode(key).len())?;
+
+        let mut changes = vec![
+            Change::Put(history, SEQ.to_vec(), seq.to_be_bytes().to_vec()),
+            Change::Put(history, entry, version),
+            Change::Put(history, write, key.to_vec()),
+        ];
+        let cutoff = seq.saturating_sub(u64::from(self.head.horizon));
+        let write = encoding::encode(&(WRITE, cutoff));
+        if let Some(old) = self.lookup_in(history, &write)?.map(|key| key.to_vec()) {
+            changes.push(Change::Remove(history, write));
+            // Older versions of the key were already behind the cutoff: at most one is left.
+            let (prefix, _) = versions_of(&old);
+            let mut next = self.ceil_in(history, &version_key(&old, cutoff))?;
+            let mut latest = true;
+            while let Some(entry) = next.filter(|entry| entry.starts_with(&prefix)) {
+                next = self.above_in(history, &entry)?.map(|entry| entry.to_vec());
+                if !latest || self.version(history, &entry)?.is_none() {
+                    changes.push(Change::Remove(history, entry));
+                }
+                latest = false;
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Value stored in a version entry of the history tree (none for a removal).
+    fn version(&self, history: u32, entry: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.lookup_in(history, entry)? {
+            Some(version) => match version.split_first() {
+                Some((1, val)) => Ok(Some(val.to_vec())),
+                Some((0, _)) => Ok(None),
+                _ => Err(Error::CorruptPage {
+                    id: history,
+                    reason: format!("Malformed version: {}", hex(&version)),
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Smallest key greater or equal to given one, if any.
+    fn ceil_in(&self, root: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.lookup_in(root, key)?.is_some() {
+            return Ok(Some(key.to_vec()));
+        }
+        Ok(self.above_in(root, key)?.map(|key| key.to_vec()))
+    }
+
+    /// Value of given key as of given sequence number: the latest version not after it.
+    fn value_at(&self, history: u32, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        let (prefix, _) = versions_of(key);
+        match self.ceil_in(history, &version_key(key, seq))? {
+            Some(entry) if entry.starts_with(&prefix) => self.version(history, &entry),
+            _ => Ok(None),
+        }
+    }
+
+    /// History tree for a read at given sequence number, that must be within the horizon.
+    fn history_at(&self, seq: u64) -> Result<u32> {
+        if self.head.horizon == 0 {
+            return Err(Error::Unsupported(
+                "reads at a sequence number of unversioned database".to_string(),
+            ));
+        }
+        let cutoff = self
+            .last_seq()?
+            .saturating_sub(u64::from(self.head.horizon));
+        if seq < cutoff {
+            return Err(Error::Pruned(cutoff));
+        }
+        Ok(self.head.history.get())
+    }
+
+    /// Index entries to remove and to add (by index root page) when the value of given key in
+    /// the tree changes from `old` to `val` (or is removed). New entries are checked to fit
+    /// into a page.
+    fn index_changes(
+        &self,
+        root: u32,
+        key: &[u8],
+        old: Option<&[u8]>,
+        val: Option<&[u8]>,
+    ) -> Result<Vec<Change>> {
+        let indexes = self.indexes.borrow();
+        let indexes = match indexes.get(&root) {
+            Some(indexes) if !indexes.is_empty() => indexes,
+            _ => return Ok(vec![]),
+        };
+        let empty = self.codec().encode(&[]).len();
+        let mut changes = Vec::with_capacity(indexes.len() * 2);
+        for index in indexes.iter() {
+            let extract = index
+                .extract
+                .as_ref()
+                .ok_or_else(|| Error::IndexNotRegistered(index.name.clone()))?;
+            let was = old.and_then(|val| extract(val));
+            let now = val.and_then(|val| extract(val));
+            if was == now {
+                continue;
+            }
+            if let Some(ikey) = was {
+                changes.push(Change::Remove(index.root, index_key(&ikey, key)));
+            }
+            if let Some(ikey) = now {
+                let entry = index_key(&ikey, key);
+                self.entry_len(entry.len(), empty)?;
+                changes.push(Change::Put(index.root, entry, vec![]));
+            }
+        }
+        Ok(changes)
+    }
+
+    fn apply(&self, changes: Vec<Change>) -> Result<()> {
+        for change in changes {
+            match change {
+                Change::Put(root, key, val) => self.put_in(root, &key, &val)?,
+                Change::Remove(root, key) => self.delete_in(root, &key).map(|_| ())?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Size of an entry (key and stored value) in a page, rejecting entries too large to store.
+    fn entry_len(&self, key: usize, val: usize) -> Result<u32> {
+        // Key is also stored in node pages as a reference entry (with a count as a value).
+        let len = (key + val.max(COUNT)) as u32;
+        let max = P::max_entry(self.cap());
+        if len > max {
+            return Err(Error::KeyTooLarge { size: len, max });
+        }
+        Ok(len)
+    }
+
+    /// Store an entry in the tree (pages are not flushed).
+    fn put_in(&self, root: u32, key: &[u8], val: &[u8]) -> Result<()> {
+        self.upsert_in(root, key, |_| Ok(Some(Cow::Borrowed(val))))
+            .map(|_| ())
+    }
+
+    /// Store an entry in the tree in a single descent (pages are not flushed): once the leaf page
+    /// is found, `f` decides the value to store from the current one (nothing is stored if it
+    /// returns none). No page is borrowed while `f` runs, thus it can access other trees.
+    /// Returns the current value.
+    fn upsert_in<'v, F>(&self, root: u32, key: &[u8], f: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(Option<&[u8]>) -> Result<Option<Cow<'v, [u8]>>>,
+    {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let max = P::max_entry(self.cap());
+        let mut f = Some(f);
+        // Current value and the new one (encoded): decided on the first visit of the leaf.
+        let mut old = None;
+        let mut val = Cow::Borrowed(&[][..]);
+        let mut len = 0;
+        let mut page = self.page(root)?;
+        let mut seen = HashSet::with_capacity(8);
+        let mut path = Vec::with_capacity(8);
+        loop {
+            let id = page.id();
+
+            if page.size() == 0 {
+                drop(page);
+                if let Some(f) = f.take() {
+                    val = match f(None)? {
+                        Some(new) => self.encoded(new),
+                        None => return Ok(None),
+                    };
+                    len = self.entry_len(key.len(), val.len())?;
+                }
+                let put = self.page_mut(id)?.put_val(key, &val, self.order());
+                put.ok_or(Error::KeyTooLarge { size: len, max })?;
+                return Ok(old);
+            }
+
+            let idx = page
+                .ceil(key, self.order())
+                .unwrap_or_else(|| page.size() - 1);
+            let slot = slot_at(page.deref(), idx)?;
+
+            if slot.page != 0 {
+                path.push((id, idx));
+                seen.insert(id);
+                if seen.contains(&slot.page) {
+                    return Err(Error::CycleDetected(id));
+                }
+                drop(page);
+                page = self.page(slot.page)?;
+                continue;
+            }
+
+            if let Some(f) = f.take() {
+                if let Some(idx) = page.find(key, self.order()) {
+                    old = Some(self.decoded(page.deref(), idx)?);
+                }
+                drop(page);
+                val = match f(old.as_deref())? {
+                    Some(new) => self.encoded(new),
+                    None => return Ok(old),
+                };
+                len = self.entry_len(key.len(), val.len())?;
+            } else {
+                drop(page);
+            }
+
+            // Pages are changed only once the entry is known to be stored (and to fit a page).
+            if let Some(at) = self.widen(key, &path)? {
+                // No room for the longer separator: split the page holding it and start over.
+                let ids = path.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                self.split_up(&ids[..=at])?;
+                page = self.page(root)?;
+                seen.clear();
+                path.clear();
+                continue;
+            }
+
+            // Page is over-full after a split interrupted by an I/O error, or the entry does
+            // not fit into it: split the page and start over from the root. Entry is at most
+            // `max` bytes, thus it always fits into a page with a single other entry.
+            let mut leaf = self.page_mut(id)?;
+            let added = leaf.find(key, self.order()).is_none();
+            let done = (leaf.full() <= SPLIT_THRESHOLD || leaf.size() < 2)
+                && leaf.put_val(key, &val, self.order()).is_some();
+            if !done {
+                if leaf.size() < 2 {
+                    return Err(Error::KeyTooLarge { size: len, max });
+                }
+                drop(leaf);
+                let mut ids = path.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+                ids.push(id);
+                self.split_up(&ids)?;
+                page = self.page(root)?;
+                seen.clear();
+                path.clear();
+                continue;
+            }
+            let full = leaf.full();
+            drop(leaf);
+
+            if added {
+                for (parent_id, idx) in path.iter().cloned() {
+                    let mut parent = self.page_mut(parent_id)?;
+                    let count = parent.count(idx);
+                    parent.set_count(idx, count + 1);
+                }
+            }
+
+            // Pages left over-full (if a parent has no room for one more separator) are split
+            // on the next insert into them.
+            let parent_id = path.last().map(|(id, _)| *id).unwrap_or_default();
+            if full > SPLIT_THRESHOLD {
+                self.split(id, parent_id)?;
+            }
+
+            while let Some((page_id, _)) = path.pop() {
+                let (parent_id, _) = path.last().cloned().unwrap_or_default();
+                let full = self.page(page_id)?.full();
+                if full > SPLIT_THRESHOLD {
+                    self.split(page_id, parent_id)?;
+                }
+            }
+
+            return Ok(old);
+        }
+    }
+
+    /// Widen separators on the path to a leaf (top-down) that are lesser than given key: the
+    /// key is above all the keys of the subtree. Returns the position on the path of the page
+    /// having no room for the longer separator (its old separator is kept then).
+    fn widen(&self, key: &[u8], path: &[(u32, u32)]) -> Result<Option<usize>> {
+        for (at, (id, idx)) in path.iter().cloned().enumerate() {
+            if self.order().cmp(key, self.page(id)?.key(idx)) != Ordering::Greater {
+                continue;
+            }
+            let mut page = self.page_mut(id)?;
+            let sep = page.key(idx).to_vec();
+            let count = page.count(idx);
+            let child = slot_at(page.deref(), idx)?.page;
+            page.remove(idx);
+            if page.put_ref(key, child, count, self.order()).is_none() {
+                fitted(page.put_ref(&sep, child, count, self.order()), id, &sep)?;
+                return Ok(Some(at));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Split the last page of given path (page ids from the root), or its closest ancestor
+    /// having room for one more separator in the parent: a root split always succeeds.
+    fn split_up(&self, ids: &[u32]) -> Result<()> {
+        for at in (0..ids.len()).rev() {
+            let parent_id = if at > 0 { ids[at - 1] } else { 0 };
+            if self.split(ids[at], parent_id)? {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Owned copy of a value stored in a leaf page (decoded).
+    fn decoded(&self, page: &P, idx: u32) -> Result<Vec<u8>> {
+        match self.codec().decode(page.val(idx)) {
+            Some(val) => Ok(val.into_owned()),
+            None => Err(Error::CorruptPage {
+                id: page.id(),
+                reason: format!("Corrupt value: {}", idx),
+            }),
+        }
+    }
+
+    /// Encode a value to be stored (keeping the value itself if the codec does not change it).
+    fn encoded<'v>(&self, val: Cow<'v, [u8]>) -> Cow<'v, [u8]> {
+        let encoded = match self.codec().encode(&val) {
+            Cow::Owned(encoded) => Some(encoded),
+            Cow::Borrowed(_) => None,
+        };
+        encoded.map(Cow::Owned).unwrap_or(val)
+    }
+
+    /// Remove an entry from the tree (pages are not flushed). Returns the removed value.
+    fn delete_in(&self, root: u32, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        let mut page = self.page_mut(root)?;
+        let mut seen = HashSet::with_capacity(8);
+        let mut path = Vec::with_capacity(8);
+        loop {
+            let idx = match page.ceil(key, self.order()) {
+                Some(idx) => idx,
+                None => return Ok(None),
+            };
+            let slot = slot_at(page.deref(), idx)?;
+
+            let id = page.id();
+            if slot.page == 0 {
+                if self.order().cmp(page.key(idx), key) != Ordering::Equal {
+                    return Ok(None);
+                }
+                debug!("remove: key={} page={} idx={}", hex(key), id, idx);
+                let old = self.decoded(page.deref(), idx)?;
+                page.remove(idx);
+                drop(page);
+
+                for (parent_id, idx) in path.iter().cloned() {
+                    let mut parent = self.page_mut(parent_id)?;
+                    let count = parent.count(idx);
+                    parent.set_count(idx, count.saturating_sub(1));
+                }
+
+                // Navigate up-tree and remove/update references if needed
+                let mut page_id = id;
+                for (parent_id, mut idx) in path.iter().cloned().rev() {
+                    let full = self.page(page_id)?.full();
+                    if full < MERGE_THRESHOLD {
+                        let peers = {
+                            let parent = self.page(parent_id)?;
+                            let mut peers = Vec::with_capacity(2);
+                            if idx > 0 {
+                                peers.push((slot_at(parent.deref(), idx - 1)?.page, idx - 1));
+                            }
+                            if idx + 1 < parent.size() {
+                                peers.push((slot_at(parent.deref(), idx + 1)?.page, idx + 1));
+                            }
+                            peers
+                        };
+
+                        let mut peer_opt: Option<(u32, u32, u8)> = None;
+                        for (peer_id, peer_idx) in peers {
+                            let peer = self.page(peer_id)?;
+                            let full = peer.full();
+                            let better = peer_opt.map(|(_, _, min)| full < min).unwrap_or(true);
+                            if peer.size() > 0 && full < MERGE_THRESHOLD && better {
+                                peer_opt = Some((peer_id, peer_idx, full));
+                            }
+                        }
+
+                        let merged = match peer_opt {
+                            Some((peer_id, _, _)) => self.merge(page_id, peer_id)?,
+                            None => false,
+                        };
+                        if let Some((peer_id, peer_idx, _)) = peer_opt.filter(|_| merged) {
+                            trace!(
+                                "merge: merged page_id={} into peer_id={} (parent_id={})",
+                                page_id,
+                                peer_id,
+                                parent_id
+                            );
+                            // Merged page covers both key ranges, thus the upper separator is kept.
+                            let mut parent = self.page_mut(parent_id)?;
+                            let (lo_idx, hi_idx) = if idx < peer_idx {
+                                (idx, peer_idx)
+                            } else {
+                                (peer_idx, idx)
+                            };
+                            let sep = parent.key(hi_idx).to_vec();
+                            let count = parent.count(lo_idx) + parent.count(hi_idx);
+                            trace!("\t merge: parent remove: peer_idx={} idx={}", peer_idx, idx);
+                            parent.remove(hi_idx);
+                            parent.remove(lo_idx);
+                            trace!(
+                                "\t merge: parent insert: sep={}, peer_id={}",
+                                hex(&sep),
+                                peer_id
+                            );
+                            let put = parent.put_ref(&sep, peer_id, count, self.order());
+                            fitted(put, parent_id, &sep)?;
+                            idx = parent.find(&sep, self.order()).ok_or_else(|| {
+                                Error::CorruptPage {
+                                    id: parent_id,
+                                    reason: format!("Separator not found: {}", hex(&sep)),
+                                }
+                            })?;
+                            page_id = peer_id;
+                        }
+                    }
+
+                    // Separator stays valid when keys are removed, only empty page is unlinked
+                    // (and freed, as the source page of a merge is).
+                    if self.page(page_id)?.size() == 0 {
+                        self.page_mut(parent_id)?.remove(idx);
+                        self.page_mut(page_id)?.clear();
+                        self.free_id(page_id);
+                    }
+                    page_id = parent_id;
+                }
+
+                return Ok(Some(old));
+            } else {
+                path.push((id, idx));
+                seen.insert(id);
+                if seen.contains(&slot.page) {
+                    return Err(Error::CycleDetected(id));
+                }
+                drop(page);
+                page = self.page_mut(slot.page)?;
+            }
+        }
+    }
+
+    fn is_empty_in(&self, root: u32) -> Result<bool> {
+        Ok(self.page(root)?.size() == 0)
+    }
+
+    fn len_in(&self, root: u32) -> Result<u64> {
+        Ok(self.page(root)?.total())
+    }
+
+    fn rank_in(&self, root: u32, key: &[u8]) -> Result<u64> {
+        self.position(root, Some(key)).map(|(rank, _, _)| rank)
+    }
+
+    fn nth_in(&self, root: u32, idx: u64) -> Result<Option<Ref<'_, [u8]>>> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut page = self.page(root)?;
+        let mut idx = idx;
+        loop {
+            let next = match page.slot(0) {
+                Some(slot) if slot.page > 0 => {
+                    let mut next = None;
+                    for i in 0..page.size() {
+                        let count = page.count(i);
+                        if idx < count {
+                            next = Some(slot_at(page.deref(), i)?.page);
+                            break;
+                        }
+                        idx -= count;
+                    }
+                    next
+                }
+                _ if idx < page.size() as u64 => {
+                    return Ok(Some(Ref::map(page, |p| p.key(idx as u32))));
+                }
+                _ => return Ok(None),
+            };
+            match next {
+                Some(next) => {
+                    let id = page.id();
+                    drop(page);
+                    page = self.follow(&mut seen, id, next)?;
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn count_range_in(&self, root: u32, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<u64> {
+        let (lo, _) = self.bound(root, bounds.0, false)?;
+        let (hi, _) = self.bound(root, bounds.1, true)?;
+        Ok(hi.saturating_sub(lo))
+    }
+
+    fn approximate_size_in(
+        &self,
+        root: u32,
+        bounds: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> Result<Estimate> {
+        let (lo, lo_leaf) = self.bound(root, bounds.0, false)?;
+        let (hi, hi_leaf) = self.bound(root, bounds.1, true)?;
+        let keys = hi.saturating_sub(lo);
+
+        // Average size of an entry in the leaf pages at both ends of the range.
+        let (mut used, mut entries) = (0u64, 0u64);
+        let mut leaves = vec![lo_leaf];
+        if hi_leaf != lo_leaf {
+            leaves.push(hi_leaf);
+        }
+        for id in leaves {
+            let page = self.page(id)?;
+            used += page.full() as u64 * page.cap() as u64 / 100;
+            entries += page.size() as u64;
+        }
+        let bytes = match entries {
+            0 => 0,
+            n => keys * used / n,
+        };
+        Ok(Estimate { keys, bytes })
+    }
+
+    fn sample_in<R: Rng>(&self, root: u32, rng: &mut R) -> Result<Option<Ref<'_, [u8]>>> {
+        match self.len_in(root)? {
+            0 => Ok(None),
+            n => self.nth_in(root, rng.gen_range(0..n)),
+        }
+    }
+
+    fn min_in(&self, root: u32) -> Result<Option<Ref<'_, [u8]>>> {
+        if self.is_empty_in(root)? {
+            return Ok(None);
+        }
+        self.lowest(root).map(Some)
+    }
+
+    fn max_in(&self, root: u32) -> Result<Option<Ref<'_, [u8]>>> {
+        if self.is_empty_in(root)? {
+            return Ok(None);
+        }
+        self.highest(root).map(Some)
+    }
+
+    fn above_in(&self, root: u32, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut path = Vec::with_capacity(8);
+        let mut page = self.page(root)?;
+        if page.size() == 0 {
+            return Ok(None);
+        }
+        loop {
+            // Separators are upper bounds for a subtree, thus a key above all of them
+            // (or above all keys in a leaf) belongs to the last slot.
+            let idx = match page.ceil(key, self.order()) {
+                Some(idx) => idx,
+                None => last(page.deref())?,
+            };
+            let slot = slot_at(page.deref(), idx)?;
+            if slot.page == 0 {
+                let order = self.order().cmp(key, page.key(idx));
+                if order == Ordering::Less {
+                    return Ok(Some(Ref::map(page, |p| p.key(idx))));
+                } else if order == Ordering::Equal && idx + 1 < page.size() {
+                    return Ok(Some(Ref::map(page, |p| p.key(idx + 1))));
+                }
+                drop(page);
+
+                // ceil == key (or no ceil in the page), need to take min value from parent's
+                // next adjacent subtree
+                for (parent_id, parent_idx) in path.iter().rev().cloned() {
+                    let next = {
+                        let parent = self.page(parent_id)?;
+                        if parent_idx + 1 < parent.size() {
+                            Some(slot_at(parent.deref(), parent_idx + 1)?.page)
+                        } else {
+                            None
+                        }
+                    };
+                    if let Some(id) = next {
+                        return self.lowest(id).map(Some);
+                    }
+                }
+
+                // key seems to be the maximum stored value in the tree
+                return Ok(None);
+            } else {
+                let id = page.id();
+                path.push((id, idx));
+                drop(page);
+                page = self.follow(&mut seen, id, slot.page)?;
+            }
+        }
+    }
+
+    fn below_in(&self, root: u32, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        let mut seen = HashSet::with_capacity(8);
+        let mut path = Vec::with_capacity(8);
+        let mut page = self.page(root)?;
+        if page.size() == 0 {
+            return Ok(None);
+        }
+        loop {
+            // Separators are upper bounds for a subtree, thus a key above all of them
+            // (or above all keys in a leaf) belongs to the last slot.
+            let idx = match page.ceil(key, self.order()) {
+                Some(idx) => idx,
+                None => last(page.deref())?,
+            };
+            let slot = slot_at(page.deref(), idx)?;
+            if slot.page == 0 {
+                let cmp = self.order();
+                if cmp.cmp(key, page.key(idx)) == Ordering::Greater {
+                    return Ok(Some(Ref::map(page, |p| p.key(idx))));
+                } else if idx > 0 && cmp.cmp(key, page.key(idx - 1)) == Ordering::Greater {
+                    return Ok(Some(Ref::map(page, |p| p.key(idx - 1))));
+                }
+                drop(page);
+
+                // ceil == key, need to take max value from parent's previous adjacent subtree
+                for (parent_id, parent_idx) in path.iter().rev().cloned() {
+                    if parent_idx > 0 {
+                        let id = slot_at(self.page(parent_id)?.deref(), parent_idx - 1)?.page;
+                        return self.highest(id).map(Some);
+                    }
+                }
+
+                // key seems to be the minimum stored value in the tree
+                return Ok(None);
+            } else {
+                let id = page.id();
+                path.push((id, idx));
+                drop(page);
+                page = self.follow(&mut seen, id, slot.page)?;
+            }
+        }
+    }
+
+    fn dump_in(&self, root: u32) -> String {
+        fn dump_page<P: Page, S: Io>(
+            file: &File<P, S>,
+            page_id: u32,
+            parent_id: u32,
+            acc: &mut String,
+            seen: &mut HashSet<u32>,
+            prefix: String,
+            tab: String,
+        ) {
+            if page_id == 0 {
+                return;
+            }
+            if !seen.insert(page_id) {
+                acc.push_str(&format!("{}page={}: cyclic reference\n", prefix, page_id));
+            } else {
+                let (copy, full) = match file.page(page_id) {
+                    Ok(page) => (page.copy(), page.full()),
+                    Err(e) => {
+                        acc.push_str(&format!("{}page={}: {}\n", prefix, page_id, e));
+                        return;
+                    }
+                };
+
+                acc.push_str(&if copy.is_empty() {
+                    format!("{}page={}: empty", prefix, page_id)
+                } else {
+                    let entries = copy
+                        .iter()
+                        .map(|(k, v, p)| format!("{}{}, {}, {}", prefix, hex(k), hex(v), p))
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    format!(
+                        "{}page={}: (parent={}) {}% full\n{}",
+                        prefix, page_id, parent_id, full, entries
+                    )
+                });
+
+                acc.push('\n');
+                let links = copy.iter().map(|(_, _, p)| p).cloned().collect::<Vec<_>>();
+
+                links.into_iter().for_each(|id| {
+                    let mut p = prefix.clone();
+                    p.push_str(&tab);
+                    dump_page(file, id, page_id, acc, seen, p, tab.clone());
+                });
+            }
+        }
+
+        let mut acc = String::with_capacity(1024);
+        let mut seen = HashSet::with_capacity(32);
+        dump_page(
+            self,
+            root,
+            0,
+            &mut acc,
+            &mut seen,
+            "".to_string(),
+            "\t".to_string(),
+        );
+        acc
+    }
+}
+
+impl<P: Page, S: Io> Tree for File<P, S> {
+    fn lookup(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        self.live_lookup_in(ROOT, key)
+    }
+
+    fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.insert_in(ROOT, key, val).map(|_| ())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.remove_in(ROOT, key).map(|_| ())
+    }
+
+    fn replace(&mut self, key: &[u8], val: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.insert_in(ROOT, key, val)
+    }
+
+    fn take(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.remove_in(ROOT, key)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.is_empty_in(ROOT)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.len_in(ROOT)
+    }
+
+    fn rank(&self, key: &[u8]) -> Result<u64> {
+        self.rank_in(ROOT, key)
+    }
+
+    fn nth(&self, idx: u64) -> Result<Option<Ref<'_, [u8]>>> {
+        self.nth_in(ROOT, idx)
+    }
+
+    fn count_range(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<u64> {
+        self.count_range_in(ROOT, bounds)
+    }
+
+    fn approximate_size(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<Estimate> {
+        self.approximate_size_in(ROOT, bounds)
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> Result<Option<Ref<'_, [u8]>>> {
+        self.sample_in(ROOT, rng)
+    }
+
+    fn min(&self) -> Result<Option<Ref<'_, [u8]>>> {
+        let first = self.min_in(ROOT)?;
+        self.live_in(ROOT, first, |key| self.above_in(ROOT, key))
+    }
+
+    fn max(&self) -> Result<Option<Ref<'_, [u8]>>> {
+        let first = self.max_in(ROOT)?;
+        self.live_in(ROOT, first, |key| self.below_in(ROOT, key))
+    }
+
+    fn above(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        let first = self.above_in(ROOT, key)?;
+        self.live_in(ROOT, first, |key| self.above_in(ROOT, key))
+    }
+
+    fn below(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        let first = self.below_in(ROOT, key)?;
+        self.live_in(ROOT, first, |key| self.below_in(ROOT, key))
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut pages = self.dirty.borrow_mut().drain().collect::<Vec<_>>();
+        if pages.is_empty() && !self.stale.get() {
+            return Ok(());
+        }
+        pages.sort_unstable();
+
+        let free = self.head.free.get();
+        match self.write_all(&pages) {
+            Ok(Some(chain)) => self.listed(chain),
+            Ok(None) => (),
+            Err(e) => {
+                // Written pages are not known to be durable: written (and synced) again on next
+                // flush, along with a new list of free pages (if it changed).
+                self.head.free.set(free);
+                self.dirty.borrow_mut().extend(pages);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn dump(&self) -> String {
+        self.dump_in(ROOT)
+    }
+}
+
+impl<P: Page, S: Io> File<P, S> {
+    /// Write (and sync) given dirty pages, the list of free pages (if changed) and then the
+    /// header. Returns pages holding the new list of free pages (if written).
+    fn write_all(&self, pages: &[u32]) -> Result<Option<Vec<u32>>> {
+        // Dirty pages are always cached (marked only after being accessed via `page_mut`).
+        let cache = self.cache.borrow();
+        let mut sealed = Vec::with_capacity(pages.len());
+        for id in pages.iter() {
+            match cache.get(id) {
+                Some(page) => sealed.push(self.seal(page)),
+                None => return Err(Error::PageNotFound(*id)),
+            }
+        }
+
+        // Runs of adjacent pages are written with a single vectored write each.
+        let mut lo = 0;
+        while lo < pages.len() {
+            let mut hi = lo + 1;
+            while hi < pages.len() && pages[hi] == pages[hi - 1] + 1 {
+                hi += 1;
+            }
+            let bufs = sealed[lo..hi]
+                .iter()
+                .map(|b| b.as_ref())
+                .collect::<Vec<_>>();
+            let offset = self.offset(pages[lo]);
+            self.file.write_vectored_at(&bufs, offset)?;
+            debug!("flush: pages={}..={}", pages[lo], pages[hi - 1]);
+            lo = hi;
+        }
+
+        let chain = match self.stale.get() {
+            true => Some(self.write_chain()?),
+            false => None,
+        };
+        // Pages are synced before the header that references them (see `Head`).
+        self.file.sync()?;
+        if let Some(chain) = chain.as_ref() {
+            self.head
+                .free
+                .set(chain.first().cloned().unwrap_or_default());
+        }
+        self.write_head()?;
+        Ok(chain)
+    }
+}
+
+impl<P: Page, S: Io> Pages<P> for File<P, S> {
+    #[cfg(test)]
+    fn root(&self) -> Result<Ref<'_, P>> {
+        self.page(ROOT)
+    }
+
+    fn page(&self, id: u32) -> Result<Ref<'_, P>> {
+        self.cached(id)?;
+        let cache = self.cache.try_borrow().map_err(|_| Error::Busy)?;
+        Ref::filter_map(cache, |cache| cache.get(&id)).map_err(|_| Error::PageNotFound(id))
+    }
+
+    #[cfg(test)]
+    fn root_mut(&self) -> Result<RefMut<'_, P>> {
+        self.page_mut(ROOT)
+    }
+
+    fn page_mut(&self, id: u32) -> Result<RefMut<'_, P>> {
+        self.cached(id)?;
+        let cache = self.cache.try_borrow_mut().map_err(|_| Error::Busy)?;
+        let page = RefMut::filter_map(cache, |cache| cache.get_mut(&id))
+            .map_err(|_| Error::PageNotFound(id))?;
+        // A decoded value of the page may still be borrowed: it must outlive any change.
+        let mut values = self.values.try_borrow_mut().map_err(|_| Error::Busy)?;
+        values.remove(&id);
+        if let Some(undo) = self.undo.borrow_mut().as_mut() {
+            undo.pages.entry(id).or_insert_with(|| {
+                let mut copy = P::reserve(self.cap());
+                copy.as_mut().copy_from_slice(page.as_ref());
+                copy
+            });
+        }
+        self.mark(id);
+        Ok(page)
+    }
+
+    fn mark(&self, id: u32) {
+        self.dirty.borrow_mut().insert(id);
+    }
+
+    fn next_id(&self) -> Result<u32> {
+        let free = self.empty.borrow_mut().pop();
+        if let Some(Reverse(id)) = free {
+            // Content of a free page is not loaded: it is of no use (and may be a page of the
+            // list of free pages, see `write_chain`).
+            let cache = self.cache.try_borrow_mut();
+            let values = self.values.try_borrow_mut();
+            let (mut cache, mut values) = match (cache, values) {
+                (Ok(cache), Ok(values)) => (cache, values),
+                _ => {
+                    // Not taken (thus not a change to undo): back to the free ones as is.
+                    self.empty.borrow_mut().push(Reverse(id));
+                    return Err(Error::Busy);
+                }
+            };
+            values.remove(&id);
+            cache.insert(id, P::create(id, self.cap()));
+            self.mark(id);
+            self.stale.set(true);
+            if let Some(undo) = self.undo.borrow_mut().as_mut() {
+                undo.take(id);
+            }
+            return Ok(id);
+        }
+
+        let len = self.file.len()?.saturating_sub(HEAD as u64);
+        let id = match u32::try_from(1 + len / self.head.page_bytes as u64) {
+            Ok(id) => id,
+            _ => return Err(Error::Unsupported(format!("file size {}", len))),
+        };
+        // A value may still be borrowed from the mapping: fail before the file grows.
+        let mut map = self.map.try_borrow_mut().map_err(|_| Error::Busy)?;
+        let page = P::create(id, self.cap());
+        self.file.write_at(&self.seal(&page), self.offset(id))?;
+        if let Some(undo) = self.undo.borrow_mut().as_mut() {
+            undo.take(id);
+        }
+        if map.is_some() {
+            *map = self.file.map()?;
+        }
+
+        Ok(id)
+    }
+
+    fn free_id(&self, id: u32) {
+        if let Some(undo) = self.undo.borrow_mut().as_mut() {
+            undo.free(id);
+        }
+        self.empty.borrow_mut().push(Reverse(id));
+        self.stale.set(true);
+    }
+
+    fn split(&self, id: u32, parent_id: u32) -> Result<bool> {
+        let copy = self.page(id)?.copy();
+        if copy.len() < 2 {
+            // Nothing to split: a single entry takes the whole page.
+            return Ok(true);
+        }
+        let half = copy.len() / 2;
+        let max = copy[copy.len() - 1].0.clone();
+
+        if parent_id == 0 {
+            let lo_id = self.next_id()?;
+            let hi_id = self.next_id()?;
+            debug!(
+                "split: root={} into lo={} and hi={} (parent={})",
+                id, lo_id, hi_id, parent_id
+            );
+            let lo_max = split_key(&copy, half, self.order());
+
+            // Each half fits into an empty page, as both did into the page being split.
+            for (page_id, entries) in [(lo_id, &copy[..half]), (hi_id, &copy[half..])] {
+                let mut page = self.page_mut(page_id)?;
+                for entry in entries {
+                    trace!(
+                        "split: move k={} v={} p={} from {} to {}",
+                        hex(&entry.0),
+                        hex(&entry.1),
+                        entry.2,
+                        id,
+                        page_id
+                    );
+                    fitted(put_copy(&mut *page, entry, self.order()), page_id, &entry.0)?;
+                }
+            }
+
+            {
+                let mut page = self.page_mut(id)?;
+                page.clear();
+                let lo = page.put_ref(&lo_max, lo_id, weight(&copy[..half]), self.order());
+                fitted(lo, id, &lo_max)?;
+                let hi = page.put_ref(&max, hi_id, weight(&copy[half..]), self.order());
+                fitted(hi, id, &max)?;
+            }
+
+            Ok(true)
+        } else {
+            // Check the parent before anything is moved, so a corrupt one leaves pages intact.
+            let sep = {
+                let parent = self.page(parent_id)?;
+                let idx = parent
+                    .ceil(&max, self.order())
+                    .ok_or_else(|| Error::CorruptPage {
+                        id: parent_id,
+                        reason: format!("Separator not found: {}", hex(&max)),
+                    })?;
+                parent.key(idx).to_vec()
+            };
+            let peer_id = self.next_id()?;
+
+            // Upper half keeps the separator of the original page, lower half gets a new one:
+            // parent is updated on a copy first, the split is given up if it has no room.
+            let mut parent = self.scratch(parent_id)?;
+            if let Some(idx) = parent.find(&sep, self.order()) {
+                parent.remove(idx);
+            }
+            let lo_sep = split_key(&copy, half, self.order());
+            let room = parent
+                .put_ref(&lo_sep, id, weight(&copy[..half]), self.order())
+                .is_some()
+                && parent
+                    .put_ref(&sep, peer_id, weight(&copy[half..]), self.order())
+                    .is_some();
+            if !room {
+                debug!("split: page={} parent={} is full", id, parent_id);
+                self.free_id(peer_id);
+                return Ok(false);
+            }
+            debug!(
+                "split: page={} into peer={} (parent={})",
+                id, peer_id, parent_id
+            );
+
+            {
+                let mut page = self.page_mut(id)?;
+                copy.iter().skip(half).for_each(|(key, _, _)| {
+                    if let Some(idx) = page.find(key, self.order()) {
+                        page.remove(idx);
+                    }
+                });
+            }
+
+            {
+                let mut peer = self.page_mut(peer_id)?;
+                for entry in copy.iter().skip(half) {
+                    trace!(
+                        "split: move k={} v={} p={} from {} to {}",
+                        hex(&entry.0),
+                        hex(&entry.1),
+                        entry.2,
+                        id,
+                        peer_id
+                    );
+                    fitted(put_copy(&mut *peer, entry, self.order()), peer_id, &entry.0)?;
+                }
+            }
+
+            self.store(&parent)?;
+            Ok(true)
+        }
+    }
+
+    fn merge(&self, src_id: u32, dst_id: u32) -> Result<bool> {
+        let src_copy = self.page(src_id)?.copy();
+
+        // Entries are moved on a copy of the destination page, given up if they do not fit.
+        let mut page = self.scratch(dst_id)?;
+        for entry in src_copy.iter() {
+            trace!(
+                "merge: move k={} v={} p={} from {} to {}",
+                hex(&entry.0),
+                hex(&entry.1),
+                entry.2,
+                src_id,
+                dst_id
+            );
+            if put_copy(&mut page, entry, self.order()).is_none() {
+                debug!("merge: src={} does not fit into dst={}", src_id, dst_id);
+                return Ok(false);
+            }
+        }
+        debug!("merge: src={} into dst={}", src_id, dst_id);
+        self.store(&page)?;
+
+        self.page_mut(src_id)?.clear();
+        self.free_id(src_id);
+        Ok(true)
+    }
+}
+
+impl<'a, P: Page, S: Io> Bucket<'a, P, S> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Store an entry expiring after given time (see `File::insert_with_ttl`).
+    pub fn insert_with_ttl(&mut self, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        self.file.insert_with_ttl_in(self.root, key, val, ttl)
+    }
+}
+
+impl<'a, P: Page, S: Io> Tree for Bucket<'a, P, S> {
+    fn lookup(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        self.file.live_lookup_in(self.root, key)
+    }
+
+    fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.file.insert_in(self.root, key, val).map(|_| ())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.file.remove_in(self.root, key).map(|_| ())
+    }
+
+    fn replace(&mut self, key: &[u8], val: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.file.insert_in(self.root, key, val)
+    }
+
+    fn take(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.file.remove_in(self.root, key)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.file.is_empty_in(self.root)
+    }
+
+    fn len(&self) -> Result<u64> {
+        self.file.len_in(self.root)
+    }
+
+    fn rank(&self, key: &[u8]) -> Result<u64> {
+        self.file.rank_in(self.root, key)
+    }
+
+    fn nth(&self, idx: u64) -> Result<Option<Ref<'_, [u8]>>> {
+        self.file.nth_in(self.root, idx)
+    }
+
+    fn count_range(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<u64> {
+        self.file.count_range_in(self.root, bounds)
+    }
+
+    fn approximate_size(&self, bounds: (Bound<&[u8]>, Bound<&[u8]>)) -> Result<Estimate> {
+        self.file.approximate_size_in(self.root, bounds)
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> Result<Option<Ref<'_, [u8]>>> {
+        self.file.sample_in(self.root, rng)
+    }
+
+    fn min(&self) -> Result<Option<Ref<'_, [u8]>>> {
+        let first = self.file.min_in(self.root)?;
+        self.file
+            .live_in(self.root, first, |key| self.file.above_in(self.root, key))
+    }
+
+    fn max(&self) -> Result<Option<Ref<'_, [u8]>>> {
+        let first = self.file.max_in(self.root)?;
+        self.file
+            .live_in(self.root, first, |key| self.file.below_in(self.root, key))
+    }
+
+    fn above(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        let first = self.file.above_in(self.root, key)?;
+        self.file
+            .live_in(self.root, first, |key| self.file.above_in(self.root, key))
+    }
+
+    fn below(&self, key: &[u8]) -> Result<Option<Ref<'_, [u8]>>> {
+        let first = self.file.below_in(self.root, key)?;
+        self.file
+            .live_in(self.root, first, |key| self.file.below_in(self.root, key))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.file.flush()
+    }
+
+    fn dump(&self) -> String {
+        self.file.dump_in(self.root)
+    }
+}
+
+impl<'a, P: Page, S: Io> Multimap<'a, P, S> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Store a pair, returns whether it was added (false if it is present already).
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) -> Result<bool> {
+        let entry = encoding::encode(&(key.to_vec(), val.to_vec()));
+        Ok(self.file.insert_in(self.root, &entry, &[])?.is_none())
+    }
+
+    /// Remove a pair, returns whether it was present.
+    pub fn remove(&mut self, key: &[u8], val: &[u8]) -> Result<bool> {
+        let entry = encoding::encode(&(key.to_vec(), val.to_vec()));
+        Ok(self.file.remove_in(self.root, &entry)?.is_some())
+    }
+
+    /// Remove all the values of a key as a whole (none is removed if it fails), returns the
+    /// number of removed pairs.
+    pub fn remove_all(&mut self, key: &[u8]) -> Result<u64> {
+        let entries = self
+            .get(key)?
+            .into_iter()
+            .map(|val| encoding::encode(&(key.to_vec(), val)))
+            .collect::<Vec<_>>();
+        let keys = entries.iter().map(|e| e.as_slice()).collect::<Vec<_>>();
+        let removed = self.file.remove_keys_in(self.root, &keys)?;
+        Ok(removed.iter().filter(|old| old.is_some()).count() as u64)
+    }
+
+    pub fn contains(&self, key: &[u8], val: &[u8]) -> Result<bool> {
+        let entry = encoding::encode(&(key.to_vec(), val.to_vec()));
+        Ok(self.file.lookup_in(self.root, &entry)?.is_some())
+    }
+
+    /// All the values of a key (ascending).
+    pub fn get(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (lo, _) = pairs_of(key);
+        let mut values = Vec::new();
+        let mut next = self.file.above_in(self.root, &lo)?.map(|e| e.to_vec());
+        while let Some(entry) = next.filter(|entry| entry.starts_with(&lo)) {
+            next = self.file.above_in(self.root, &entry)?.map(|e| e.to_vec());
+            let (_, val) = encoding::decode::<(Vec<u8>, Vec<u8>)>(&entry).ok_or_else(|| {
+                Error::CorruptPage {
+                    id: self.root,
+                    reason: format!("Malformed multimap entry: {}", hex(&entry)),
+                }
+            })?;
+            values.push(val);
+        }
+        Ok(values)
+    }
+
+    /// Number of values of a key: taken from subtree entry counts, the values are not scanned.
+    pub fn count(&self, key: &[u8]) -> Result<u64> {
+        let (lo, hi) = pairs_of(key);
+        let bounds = (
+            Bound::Included(lo.as_slice()),
+            Bound::Excluded(hi.as_slice()),
+        );
+        self.file.count_range_in(self.root, bounds)
+    }
+
+    /// Number of pairs.
+    pub fn len(&self) -> Result<u64> {
+        self.file.len_in(self.root)
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        self.file.is_empty_in(self.root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::merge;
+    use crate::disk::block::Block;
+    use crate::disk::fault::Fault;
+    use crate::disk::mem::Mem;
+    use crate::disk::prefix::Prefixed;
+    use crate::disk::testing::{random_pairs, temp_path};
+    use crate::util::hex::hex;
+    use rand::prelude::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::{thread_rng, Rng, RngCore, SeedableRng};
+    use std::borrow::Borrow;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::ops::{Bound, Deref, RangeBounds};
+
+    fn get<P: Page>(page: &P, key: &[u8]) -> Option<(Vec<u8>, u32)> {
+        page.find(key, &Bytewise)
+            .map(|idx| (page.val(idx).to_vec(), page.slot(idx).unwrap().page))
+    }
+
+    #[test]
+    fn test_page() {
+        let path = Path::new("target/test_page.tmp");
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+        let size: u32 = 256;
+
+        let data = vec![
+            (b"aaa".to_vec(), b"zxczxczxc".to_vec(), 0),
+            (b"bbb".to_vec(), b"asdasdasd".to_vec(), 0),
+            (b"ccc".to_vec(), b"qweqweqwe".to_vec(), 0),
+            (b"ddd".to_vec(), b"123123123".to_vec(), 0),
+            (b"xxx".to_vec(), 3u64.to_be_bytes().to_vec(), 3333),
+            (b"yyy".to_vec(), 2u64.to_be_bytes().to_vec(), 2222),
+            (b"zzz".to_vec(), 1u64.to_be_bytes().to_vec(), 1111),
+        ];
+
+        {
+            let file: File<Block> = File::make(path, size).unwrap();
+            {
+                let mut page = file.root_mut().unwrap();
+                for (k, v, p) in data.iter() {
+                    if *p == 0 {
+                        page.put_val(k, v, &Bytewise);
+                    } else {
+                        page.put_ref(k, *p, get_count(v), &Bytewise);
+                    }
+                }
+            };
+            let page = file.root().unwrap();
+            file.save(page.deref()).unwrap();
+        }
+
+        let file: File<Block> = File::open(path).unwrap();
+        let mut page = file.load(ROOT).unwrap();
+
+        assert_eq!(page.copy(), data);
+
+        for (k, v, p) in data.iter() {
+            assert_eq!(get(&page, k), Some((v.to_vec(), *p)));
+        }
+
+        page.remove(page.find(b"aaa", &Bytewise).unwrap());
+        assert_eq!(get(&page, b"aaa"), None);
+
+        page.remove(page.find(b"zzz", &Bytewise).unwrap());
+        assert_eq!(get(&page, b"zzz"), None);
+    }
+
+    #[test]
+    fn test_file() {
+        let path = Path::new("target/test_file.tmp");
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+        let size: u32 = 256;
+
+        let data = [
+            (b"uno".to_vec(), b"la squadra azzurra".to_vec()),
+            (b"due".to_vec(), b"it's coming home".to_vec()),
+            (b"tre".to_vec(), b"red devils".to_vec()),
+        ];
+
+        let mut file: File<Block> = File::make(path, size).unwrap();
+
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+            file.remove(k).unwrap();
+        }
+
+        for (k, _) in data.iter() {
+            assert!(file.lookup(k).unwrap().is_none());
+        }
+
+        let root = file.root().unwrap();
+        assert_eq!(root.copy(), vec![]);
+    }
+
+    #[test]
+    fn test_split() {
+        let path = Path::new("target/test_split.tmp");
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+
+        let size: u32 = 256;
+        let mut file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 25;
+        let data = (0..count)
+            .map(|i| {
+                let c = b'a' + (i % (b'z' - b'a' + 1) as u64) as u8;
+                (vec![c; 8], vec![c; 8])
+            })
+            .collect::<Vec<_>>();
+
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let path = Path::new("target/test_merge.tmp");
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+
+        let size: u32 = 256;
+        let mut file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 25;
+        let data = {
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut result = (0..count)
+                .map(|i| {
+                    let c = b'a' + (i % (b'z' - b'a' + 1) as u64) as u8;
+                    (vec![c; 8], vec![c; 8])
+                })
+                .collect::<Vec<_>>();
+            result.shuffle(&mut rng);
+            result
+        };
+
+        for (k, v) in data.iter() {
+            debug!("insert: key={} val={}", hex(k), hex(v));
+            file.insert(k, v).unwrap();
+        }
+        debug!("{}", file.dump());
+
+        let keys = {
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut result = data.iter().map(|(k, _)| k).cloned().collect::<Vec<_>>();
+            result.shuffle(&mut rng);
+            result
+        };
+
+        let mut removed = HashSet::with_capacity(size as usize);
+        for key in keys.iter() {
+            debug!("remove: key={}", hex(key));
+            file.remove(key).unwrap();
+            removed.insert(key.to_vec());
+            for (k, v) in data.iter() {
+                if removed.contains(k) {
+                    assert!(file.lookup(k).unwrap().is_none());
+                } else {
+                    assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+                }
+            }
+        }
+        debug!("{}", file.dump());
+
+        let root = file.root().unwrap();
+        let copy = root.copy();
+        assert_eq!(copy, vec![]);
+    }
+
+    #[test]
+    fn test_reuse() {
+        let path = &temp_path("test_reuse");
+
+        let size: u32 = 256;
+        let mut file: File<Block> = File::make(path, size).unwrap();
+
+        let data = random_pairs(100, 8);
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        for (k, _) in data.iter() {
+            file.remove(k).unwrap();
+        }
+
+        // Pages emptied by removals are reused (merged or unlinked ones alike).
+        let stats = file.stats(false).unwrap();
+        assert!(stats.pages > 1);
+        assert_eq!(stats.free, stats.pages - 1);
+    }
+
+    #[test]
+    fn test_above() {
+        let path = Path::new("target/test_above.tmp");
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+
+        let size: u32 = 256;
+        let mut file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 10;
+        let mut data = {
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut result = (0..count)
+                .map(|i| {
+                    let b = i * count;
+                    (vec![b; 8], vec![b; 8])
+                })
+                .collect::<Vec<_>>();
+            result.shuffle(&mut rng);
+            result
+        };
+
+        for (k, v) in data.iter() {
+            debug!("insert: key={} val={}", hex(k), hex(v));
+            file.insert(k, v).unwrap();
+        }
+
+        data.sort();
+        let min = file.min().unwrap().unwrap().to_vec();
+        let max = file.max().unwrap().unwrap().to_vec();
+        assert_eq!(min, data[0].0.to_vec());
+        assert_eq!(max, data.last().unwrap().0.to_vec());
+        assert!(file.above(&max).unwrap().is_none());
+
+        let asc = {
+            let mut result = Vec::with_capacity(data.len());
+            let mut val = file.min().unwrap().unwrap().to_vec();
+            result.push(val.clone());
+            while let Some(next) = file.above(&val).unwrap() {
+                result.push(next.to_vec());
+                val = next.to_vec();
+            }
+            result
+        };
+        assert_eq!(
+            asc,
+            data.clone().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_below() {
+        let path = Path::new("target/test_below.tmp");
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+
+        let size: u32 = 256;
+        let mut file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 10;
+        let mut data = {
+            let mut rng = StdRng::seed_from_u64(3);
             let mut result = (0..count)
-                .into_iter()
                 .map(|i| {
-                    let b = i * count as u8;
+                    let b = i * count;
                     (vec![b; 8], vec![b; 8])
                 })
                 .collect::<Vec<_>>();
@@ -1043,207 +3957,2197 @@ mod tests {
         };
 
         for (k, v) in data.iter() {
-            debug!("insert: key={} val={}", hex(k), hex(v));
+            debug!("insert: key={} val={}", hex(k), hex(v));
+            file.insert(k, v).unwrap();
+        }
+
+        data.sort();
+        let min = file.min().unwrap().unwrap().to_vec();
+        let max = file.max().unwrap().unwrap().to_vec();
+        assert_eq!(min, data[0].0.to_vec());
+        assert_eq!(max, data.last().unwrap().0.to_vec());
+        assert_eq!(file.below(&min).unwrap().map(|v| v.to_vec()), None);
+
+        data.reverse();
+        let desc = {
+            let mut result = Vec::with_capacity(data.len());
+            let mut val = file.max().unwrap().unwrap().to_vec();
+            result.push(val.clone());
+            while let Some(next) = file.below(&val).unwrap() {
+                result.push(next.to_vec());
+                val = next.to_vec();
+            }
+            result
+        };
+        assert_eq!(
+            desc,
+            data.clone().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_1k() {
+        let mut rng = thread_rng();
+
+        let path = Path::new("target/test_1k.tmp");
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+
+        let size: u32 = 4096;
+        let mut file: File<Block> = File::make(path, size).unwrap();
+
+        let count = 1000;
+        let data = (0..count)
+            .map(|_| {
+                (
+                    rng.next_u64().to_be_bytes().to_vec(),
+                    rng.next_u64().to_be_bytes().to_vec(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for (i, (k, v)) in data.iter().enumerate() {
+            debug!("({:05}) insert: key={} val={}", i, hex(k), hex(v));
+            file.insert(k, v).unwrap();
+        }
+        drop(file);
+
+        let mut file: File<Block> = File::open(path).unwrap();
+
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+        }
+
+        let mut sorted = data.iter().map(|(k, _)| k).cloned().collect::<Vec<_>>();
+        sorted.sort();
+        let min = file.min().unwrap().unwrap().to_vec();
+        let max = file.max().unwrap().unwrap().to_vec();
+        assert_eq!(min, sorted[0]);
+        assert_eq!(max, sorted.last().cloned().unwrap());
+        assert_eq!(file.below(&min).unwrap().map(|v| v.to_vec()), None);
+        assert_eq!(file.above(&max).unwrap().map(|v| v.to_vec()), None);
+        let asc = {
+            let mut result = Vec::with_capacity(data.len());
+            let mut this = file.min().unwrap().unwrap().to_vec();
+            result.push(this.clone());
+            while let Some(next) = file.above(&this).unwrap() {
+                result.push(next.to_vec());
+                this = next.to_vec();
+            }
+            result
+        };
+        let desc = {
+            let mut result = Vec::with_capacity(data.len());
+            let mut this = file.max().unwrap().unwrap().to_vec();
+            result.push(this.clone());
+            while let Some(next) = file.below(&this).unwrap() {
+                result.push(next.to_vec());
+                this = next.to_vec();
+            }
+            result
+        };
+
+        for (i, (put, got)) in sorted.iter().zip(asc.iter()).enumerate() {
+            assert_eq!(
+                put,
+                got,
+                "ASC: index={}: expected '{}' but got '{}'",
+                i,
+                hex(put),
+                hex(got)
+            );
+        }
+        assert_eq!(asc, sorted);
+
+        sorted.reverse();
+        for (i, (put, got)) in sorted.iter().zip(desc.iter()).enumerate() {
+            assert_eq!(
+                put,
+                got,
+                "DESC: index={}: expected '{}' but got '{}'",
+                i,
+                hex(put),
+                hex(got)
+            );
+        }
+        assert_eq!(desc, sorted);
+
+        for (i, (key, _)) in data.iter().enumerate() {
+            debug!("({:05}) remove: key={}", i, hex(key));
+            file.remove(key).unwrap();
+        }
+
+        for (i, (key, _)) in data.iter().enumerate() {
+            debug!("({:05}) lookup: key={}", i, hex(key));
+            let found = file
+                .lookup(key)
+                .unwrap()
+                .map(|v| v.borrow().to_vec())
+                .map(|v| hex(&v));
+            assert_eq!(found, None);
+        }
+
+        let copy = file.root().unwrap().copy();
+        debug!("{}", file.dump());
+        assert!(copy.is_empty());
+    }
+
+    #[test]
+    fn test_separators() {
+        let path = &temp_path("test_separators");
+
+        let size: u32 = 1024;
+        let mut file: File<Block> = File::make(path, size).unwrap();
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let key = |rng: &mut StdRng| {
+            format!(
+                "tenant/{:04}/object/{:032x}",
+                rng.next_u32() % 16,
+                rng.next_u64()
+            )
+            .into_bytes()
+        };
+        let data = (0..2000).map(|_| key(&mut rng)).collect::<Vec<_>>();
+        for k in data.iter() {
+            file.insert(k, b"value").unwrap();
+        }
+
+        // Node pages keep truncated keys: shorter than any key stored in leaves.
+        let root = file.root().unwrap().copy();
+        assert!(root.iter().all(|(_, _, p)| *p > 0));
+        let seps = root.iter().rev().skip(1).map(|(k, _, _)| k.len());
+        assert!(seps.max().unwrap() < data[0].len());
+        drop(root);
+
+        let mut set = data.iter().cloned().collect::<BTreeSet<_>>();
+        let check = |file: &File<Block>, set: &BTreeSet<Vec<u8>>, k: &[u8]| {
+            let above = set
+                .range::<[u8], _>((Bound::Excluded(k), Bound::Unbounded))
+                .next();
+            let below = set
+                .range::<[u8], _>((Bound::Unbounded, Bound::Excluded(k)))
+                .next_back();
+            assert_eq!(file.above(k).unwrap().map(|r| r.to_vec()).as_ref(), above);
+            assert_eq!(file.below(k).unwrap().map(|r| r.to_vec()).as_ref(), below);
+        };
+
+        for (i, k) in data.iter().enumerate() {
+            if i % 2 == 0 {
+                file.remove(k).unwrap();
+                set.remove(k);
+            }
+            check(&file, &set, k);
+            check(&file, &set, &key(&mut rng));
+        }
+        for k in set.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), b"value");
+        }
+        check(&file, &set, b"");
+        check(&file, &set, b"\xFF");
+    }
+
+    #[test]
+    fn test_codec() {
+        let json = |i: u64| {
+            let events = (0..8)
+                .map(|j| {
+                    format!(
+                        r#"{{"event": "login", "user": "user-{}", "seq": {}}}"#,
+                        i, j
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(r#"{{"id": {}, "events": [{}]}}"#, i, events).into_bytes()
+        };
+        let mut rng = StdRng::seed_from_u64(42);
+        let data = (0..1000)
+            .map(|_| {
+                let id = rng.next_u64();
+                (id.to_be_bytes().to_vec(), json(id))
+            })
+            .collect::<Vec<_>>();
+
+        let mut lens = Vec::with_capacity(2);
+        for codec in [Codec::None, Codec::Lz] {
+            let path = &temp_path(&format!("test_codec_{:?}", codec));
+
+            {
+                let opts = Options {
+                    codec,
+                    ..Options::default()
+                };
+                let mut file: File<Block> = File::make_with(path, 4096, opts).unwrap();
+                for (k, v) in data.iter() {
+                    file.insert(k, v).unwrap();
+                }
+            }
+
+            let mut file: File<Block> = File::open(path).unwrap();
+            assert_eq!(file.codec(), codec);
+            for (k, v) in data.iter() {
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+            }
+            {
+                // No change while a (decoded) value is borrowed.
+                file.create_bucket("b").unwrap();
+                let val = file.lookup(&data[0].0).unwrap().unwrap();
+                assert_eq!(file.len().unwrap(), data.len() as u64);
+                let mut bucket = file.bucket("b").unwrap();
+                assert!(bucket.is_empty().unwrap());
+                assert!(matches!(bucket.insert(b"x", b"y"), Err(Error::Busy)));
+                drop(val);
+                bucket.insert(b"x", b"y").unwrap();
+                assert_eq!(bucket.lookup(b"x").unwrap().unwrap().deref(), b"y");
+            }
+            for (k, _) in data.iter().take(500) {
+                file.remove(k).unwrap();
+            }
+            for (k, v) in data.iter().skip(500) {
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+            }
+            lens.push(fs::metadata(path).unwrap().len());
+        }
+        assert!(lens[1] * 2 < lens[0], "lens={:?}", lens);
+    }
+
+    #[test]
+    fn test_crypt() {
+        let path = &temp_path("test_crypt");
+
+        let key = [42u8; 32];
+        let opts = |key: Key| Options {
+            key: Some(key),
+            ..Options::default()
+        };
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let data = (0..1000)
+            .map(|_| {
+                let k = rng.next_u64();
+                (
+                    format!("key-{:016x}", k).into_bytes(),
+                    format!("secret-{}", k).into_bytes(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        {
+            let mut file: File<Block> = File::make_with(path, 512, opts(key)).unwrap();
+            assert_eq!(file.root().unwrap().cap(), 512 - crypt::OVERHEAD as u32);
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+        }
+
+        let raw = fs::read(path).unwrap();
+        assert!(!raw.windows(7).any(|w| w == b"secret-"));
+        assert!(!raw.windows(4).any(|w| w == b"key-"));
+
+        assert!(matches!(File::<Block>::open(path), Err(Error::KeyRequired)));
+        assert!(matches!(
+            File::<Block>::open_with(path, opts([0u8; 32])),
+            Err(Error::WrongKey)
+        ));
+        {
+            let file: File<Block> = File::open_with(path, opts(key)).unwrap();
+            for (k, v) in data.iter() {
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+            }
+        }
+
+        let new = [7u8; 32];
+        assert!(matches!(
+            File::<Block>::rekey(path, &new, &key),
+            Err(Error::WrongKey)
+        ));
+        File::<Block>::rekey(path, &key, &new).unwrap();
+        assert!(matches!(
+            File::<Block>::open_with(path, opts(key)),
+            Err(Error::WrongKey)
+        ));
+        {
+            let mut file: File<Block> = File::open_with(path, opts(new)).unwrap();
+            for (k, v) in data.iter() {
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+            }
+            for (k, _) in data.iter().take(500) {
+                file.remove(k).unwrap();
+            }
+        }
+
+        // Tampering with any page is detected: flip a byte in every page but root.
+        let mut raw = fs::read(path).unwrap();
+        for offset in (HEAD + 512 + 100..raw.len()).step_by(512) {
+            raw[offset] ^= 1;
+        }
+        fs::write(path, &raw).unwrap();
+        // The list of free pages is read on a writable open only.
+        assert!(matches!(
+            File::<Block>::open_with(path, opts(new)),
+            Err(Error::CorruptPage { .. })
+        ));
+        let read_only = Options {
+            read_only: true,
+            ..opts(new)
+        };
+        let file: File<Block> = File::open_with(path, read_only).unwrap();
+        let errors = data
+            .iter()
+            .skip(500)
+            .filter(|(k, _)| matches!(file.lookup(k), Err(Error::CorruptPage { .. })))
+            .count();
+        assert_eq!(errors, 500);
+        drop(file);
+
+        // Failed re-encryption leaves neither the original file changed, nor the temporary one.
+        assert!(matches!(
+            File::<Block>::rekey(path, &new, &key),
+            Err(Error::CorruptPage { .. })
+        ));
+        assert_eq!(fs::read(path).unwrap(), raw);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".rekey");
+        assert!(!Path::new(&tmp).exists());
+    }
+
+    #[test]
+    fn test_mmap() {
+        let path = &temp_path("test_mmap");
+
+        let opts = |codec: Codec| Options {
+            codec,
+            mmap: true,
+            ..Options::default()
+        };
+        assert!(File::<Block>::make_with(
+            path,
+            512,
+            Options {
+                key: Some([42u8; 32]),
+                ..opts(Codec::None)
+            }
+        )
+        .is_err());
+
+        let data = random_pairs(1000, 32);
+
+        {
+            let mut file: File<Block> = File::make_with(path, 512, opts(Codec::None)).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+                // Freshly written (and possibly just appended) page is visible through the mapping.
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+            }
+            for (k, v) in data.iter() {
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+            }
+        }
+
+        let mut file: File<Block> = File::open_with(path, opts(Codec::None)).unwrap();
+        let cached = file.cache.borrow().len();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+        }
+        // Pages are read in place: neither copied nor cached.
+        assert_eq!(file.cache.borrow().len(), cached);
+        assert_eq!(file.misses.get(), 0);
+        {
+            // Values of clean pages are borrowed from the mapping (no copy).
+            let (k, _) = &data[0];
+            let val = file.lookup(k).unwrap().unwrap();
+            let map = file.map.borrow();
+            let range = map.as_ref().unwrap().as_ptr_range();
+            assert!(range.contains(&val.as_ptr()));
+        }
+        {
+            // The file does not grow (and get remapped) while a value is borrowed from the mapping.
+            file.create_bucket("b").unwrap();
+            let val = file.lookup(&data[0].0).unwrap().unwrap();
+            let mut bucket = file.bucket("b").unwrap();
+            let busy = data
+                .iter()
+                .map(|(k, v)| bucket.insert(k, v))
+                .position(|r| r.is_err())
+                .unwrap();
+            assert!(matches!(
+                bucket.insert(&data[busy].0, &data[busy].1),
+                Err(Error::Busy)
+            ));
+            drop(val);
+            for (k, v) in data.iter() {
+                bucket.insert(k, v).unwrap();
+            }
+            for (k, v) in data.iter() {
+                assert_eq!(bucket.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+            }
+            assert_eq!(bucket.len().unwrap(), data.len() as u64);
+        }
+        for (k, _) in data.iter().take(500) {
+            file.remove(k).unwrap();
+        }
+        for (i, (k, v)) in data.iter().enumerate() {
+            let found = file.lookup(k).unwrap().map(|r| r.to_vec());
+            assert_eq!(found, if i < 500 { None } else { Some(v.clone()) });
+        }
+        drop(file);
+
+        let file: File<Block> = File::open(path).unwrap();
+        for (k, v) in data.iter().skip(500) {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+        }
+        drop(file);
+
+        // Pages read in place are checked as well: flip capacity of every page not cached yet.
+        let read_only = Options {
+            read_only: true,
+            ..opts(Codec::None)
+        };
+        let file: File<Block> = File::open_with(path, read_only).unwrap();
+        let mut raw = fs::OpenOptions::new().write(true).open(path).unwrap();
+        let len = raw.metadata().unwrap().len();
+        for offset in (HEAD as u64 + 4..len).step_by(512) {
+            let id = 1 + (offset - HEAD as u64) as u32 / 512;
+            if !file.cache.borrow().contains_key(&id) {
+                raw.seek(SeekFrom::Start(offset)).unwrap();
+                raw.write_all(&[1]).unwrap();
+            }
+        }
+        drop(raw);
+        for (k, _) in data.iter().skip(500) {
+            assert!(matches!(file.lookup(k), Err(Error::CorruptPage { .. })));
+        }
+    }
+
+    #[test]
+    fn test_mem() {
+        let data = random_pairs(1000, 8);
+
+        let mem = Mem::new();
+        {
+            let mut file: File<Block, Mem> =
+                File::make_in(mem.clone(), 256, Options::default()).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+        }
+        assert!(File::<Block, Mem>::make_in(mem.clone(), 256, Options::default()).is_err());
+        let opts = Options {
+            mmap: true,
+            ..Options::default()
+        };
+        assert!(matches!(
+            File::<Block, Mem>::open_in(mem.clone(), opts),
+            Err(Error::Unsupported(_))
+        ));
+
+        let mut file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+        }
+        for (k, _) in data.iter() {
+            file.remove(k).unwrap();
+        }
+        assert!(file.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut rng = StdRng::seed_from_u64(43);
+        let data = random_pairs(1000, 8);
+
+        let mem = Mem::new();
+        let mut file: File<Block, Fault<Mem>> =
+            File::make_in(Fault::new(mem.clone()), 256, Options::default()).unwrap();
+        let mut failed = 0;
+        for (i, (k, v)) in data.iter().enumerate() {
+            if i % 10 == 0 {
+                file.file
+                    .fail_write(rng.gen_range(0..4), rng.gen_range(0..256));
+            }
+            if file.insert(k, v).is_err() {
+                // Failed write (torn or not) is surfaced as an error and does not affect the
+                // in-memory state: once storage is back, retry succeeds and nothing is lost.
+                assert!(file.file.is_broken());
+                failed += 1;
+                file.file.heal();
+                file.insert(k, v).unwrap();
+            }
+        }
+        assert!(failed > 10, "failed={}", failed);
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+        }
+        drop(file);
+
+        let file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+        }
+        assert_eq!(file.len().unwrap(), data.len() as u64);
+        assert_eq!(counted(&file, ROOT), data.len() as u64);
+    }
+
+    #[test]
+    fn test_sync_fault() {
+        let data = random_pairs(200, 8);
+
+        let mem = Mem::new();
+        let mut file: File<Block, Fault<Mem>> =
+            File::make_in(Fault::new(mem.clone()), 256, Options::default()).unwrap();
+        for (i, (k, v)) in data.iter().enumerate() {
+            if i % 10 == 0 {
+                // Pages are written, but the failed sync leaves them dirty for the next flush.
+                file.file.fail_sync();
+                assert!(matches!(file.insert(k, v), Err(Error::IO(_))));
+                assert!(!file.dirty.borrow().is_empty());
+                file.file.heal();
+            }
+            file.insert(k, v).unwrap();
+            assert!(file.dirty.borrow().is_empty());
+        }
+        drop(file);
+
+        let file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+        }
+        assert_eq!(file.len().unwrap(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_reclaim() {
+        let data = random_pairs(1000, 8);
+
+        let mem = Mem::new();
+        let mut file: File<Block, Mem> =
+            File::make_in(mem.clone(), 256, Options::default()).unwrap();
+        for (k, v) in data.iter() {
             file.insert(k, v).unwrap();
         }
+        for (k, _) in data.iter().take(900) {
+            file.remove(k).unwrap();
+        }
+        let stats = file.stats(true).unwrap();
+        assert!(stats.free > 1);
+        assert_eq!(stats.walk.unwrap().unused, stats.free);
+        assert_eq!(file.reclaim().unwrap(), 0);
+        drop(file);
+
+        // Free pages are listed free on open only: lost ones are found by `reclaim`.
+        let mut file: File<Block, Mem> = File::open_in(mem.clone(), Options::default()).unwrap();
+        assert_eq!(file.stats(false).unwrap().free, stats.free);
+        file.empty.borrow_mut().clear();
+        file.chain.borrow_mut().clear();
+        assert_eq!(file.reclaim().unwrap(), stats.free);
+        assert_eq!(file.stats(false).unwrap().free, stats.free);
+        drop(file);
+
+        // A tree that cannot be walked fails the call, free pages are left as listed.
+        let mut file: File<Block, Mem> = File::open_in(mem.clone(), Options::default()).unwrap();
+        assert_eq!(file.stats(false).unwrap().free, stats.free);
+        let root = file.page(ROOT).unwrap();
+        let child = root.slot(root.size() - 1).unwrap().page;
+        drop(root);
+        assert!(!file.cache.borrow().contains_key(&child));
+        mem.write_at(&[0xFF; 64], file.offset(child) + 4).unwrap();
+        assert!(matches!(file.reclaim(), Err(Error::CorruptPage { .. })));
+        assert_eq!(file.stats(false).unwrap().free, stats.free);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut rng = StdRng::seed_from_u64(43);
+        let data = random_pairs(1000, 8);
+
+        let page_bytes = 256;
+        let mem = Mem::new();
+        {
+            let mut file: File<Block, Mem> =
+                File::make_in(mem.clone(), page_bytes, Options::default()).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+        }
+        let bytes = mem.bytes();
+        let pages = (bytes.len() - HEAD) / page_bytes as usize;
+
+        let mut errors = 0;
+        for i in 0..200 {
+            let mut copy = bytes.clone();
+            let page = rng.gen_range(0..pages);
+            let at = HEAD + page * page_bytes as usize;
+            let page_range = at..(at + page_bytes as usize);
+            match i % 4 {
+                0 => {
+                    // Flip a random bit.
+                    let pos = rng.gen_range(page_range);
+                    copy[pos] ^= 1 << rng.gen_range(0..8);
+                }
+                1 => {
+                    // Overwrite a header or slot field (sizes, offsets, lengths, page refs).
+                    let pos = at + rng.gen_range(0..64);
+                    let val = match rng.gen_range(0..3) {
+                        0 => rng.gen_range(0..(pages as u32 + 2)),
+                        1 => rng.gen_range(0..(page_bytes * 2)),
+                        _ => rng.next_u32(),
+                    };
+                    copy[pos..(pos + 4)].copy_from_slice(&val.to_be_bytes());
+                }
+                2 => {
+                    // Zero the whole page.
+                    copy[page_range].fill(0);
+                }
+                _ => {
+                    // Put a copy of another page at this page's position (cycles, dangling refs).
+                    let src = HEAD + rng.gen_range(0..pages) * page_bytes as usize;
+                    let other = copy[src..(src + page_bytes as usize)].to_vec();
+                    copy[page_range].copy_from_slice(&other);
+                }
+            }
+
+            let mem = Mem::new();
+            mem.write_at(&copy, 0).unwrap();
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                let mut failed = 0;
+                let mut file: File<Block, Mem> = match File::open_in(mem, Options::default()) {
+                    Ok(file) => file,
+                    Err(_) => return 1,
+                };
+                for (k, _) in data.iter().step_by(11) {
+                    failed += file.lookup(k).is_err() as usize;
+                    failed += file.above(k).is_err() as usize;
+                    failed += file.below(k).is_err() as usize;
+                }
+                failed += file.min().is_err() as usize;
+                failed += file.max().is_err() as usize;
+                for (k, v) in data.iter().step_by(37) {
+                    failed += file.remove(k).is_err() as usize;
+                    failed += file.insert(v, k).is_err() as usize;
+                }
+                let _ = file.dump();
+                failed
+            }));
+            match result {
+                Ok(failed) => errors += failed.min(1),
+                Err(_) => panic!("iteration {}: panic on corrupt page {}", i, page + 1),
+            }
+        }
+        assert!(errors > 50, "errors={}", errors);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mem = Mem::new();
+        let mut file: File<Block, Mem> =
+            File::make_in(mem.clone(), 256, Options::default()).unwrap();
+        let max = Block::max_entry(256);
+        assert!(matches!(
+            file.insert(b"key", &vec![0u8; max as usize]),
+            Err(Error::KeyTooLarge { size, max: m }) if size == max + 3 && m == max
+        ));
+
+        // Entries of max size are split into pages of two.
+        let val = vec![42u8; max as usize - 2];
+        for i in 0..100u16 {
+            file.insert(&i.to_be_bytes(), &val).unwrap();
+        }
+        for i in 0..100u16 {
+            assert_eq!(
+                file.lookup(&i.to_be_bytes()).unwrap().unwrap().deref(),
+                &val
+            );
+        }
+
+        assert!(matches!(
+            File::<Block, Mem>::make_in(mem.clone(), 256, Options::default()),
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::AlreadyExists
+        ));
+        {
+            let root = file.page_mut(ROOT).unwrap();
+            assert!(matches!(file.page(2), Err(Error::Busy)));
+            assert!(matches!(file.len(), Err(Error::Busy)));
+            drop(root);
+        }
+
+        let first = file.root().unwrap().key(0).to_vec();
+        file.page_mut(ROOT)
+            .unwrap()
+            .put_ref(&first, ROOT, 1, &Bytewise)
+            .unwrap();
+        assert!(matches!(
+            file.lookup(&first),
+            Err(Error::CycleDetected(ROOT))
+        ));
+        file.page_mut(ROOT)
+            .unwrap()
+            .put_ref(&first, 999, 1, &Bytewise)
+            .unwrap();
+        assert!(matches!(file.lookup(&first), Err(Error::PageNotFound(999))));
+
+        let bytes = mem.bytes();
+        let open = |bytes: &[u8]| {
+            let mem = Mem::new();
+            mem.write_at(bytes, 0).unwrap();
+            File::<Block, Mem>::open_in(mem, Options::default())
+        };
+        assert!(matches!(open(b"SQLite format 3"), Err(Error::BadMagic)));
+        let mut copy = bytes.clone();
+        copy[6..8].copy_from_slice(b"42");
+        assert!(matches!(open(&copy), Err(Error::UnsupportedVersion(v)) if v == "42"));
+        assert!(matches!(
+            open(&bytes[..(HEAD + 100)]),
+            Err(Error::PageNotFound(ROOT))
+        ));
+        let mut copy = bytes.clone();
+        copy[HEAD..(HEAD + 256)].fill(0);
+        assert!(matches!(
+            open(&copy),
+            Err(Error::CorruptPage { id: ROOT, .. })
+        ));
+    }
+
+    #[test]
+    fn test_separator_widening() {
+        let mut file: File<Block, Mem> =
+            File::make_in(Mem::new(), 256, Options::default()).unwrap();
+        let mut keys = (0..200u32)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        for key in keys.iter() {
+            file.insert(key, key).unwrap();
+        }
+        let check = |file: &File<Block, Mem>, keys: &[Vec<u8>]| {
+            assert_eq!(file.len().unwrap(), keys.len() as u64);
+            for key in keys.iter() {
+                assert!(file.lookup(key).unwrap().is_some(), "{}", hex(key));
+            }
+            let mut found = vec![file.min().unwrap().unwrap().to_vec()];
+            while let Some(next) = file.above(found.last().unwrap()).unwrap() {
+                found.push(next.to_vec());
+            }
+            assert_eq!(found, keys);
+        };
+
+        // Rejected key above all the others leaves the separators (and the tree) intact.
+        let max = Block::max_entry(256) as usize;
+        assert!(matches!(
+            file.insert(&vec![0xff; max], b""),
+            Err(Error::KeyTooLarge { .. })
+        ));
+        check(&file, &keys);
+
+        // Separators widened to keys of max size split the pages that have no room for them.
+        for i in 0..20u8 {
+            let mut key = vec![0xff; max - COUNT - 1];
+            key.push(i);
+            file.insert(&key, b"").unwrap();
+            keys.push(key);
+            check(&file, &keys);
+        }
+    }
+
+    #[test]
+    fn test_lock() {
+        let path = &temp_path("test_lock");
+        let read_only = Options {
+            read_only: true,
+            ..Options::default()
+        };
+
+        assert!(matches!(
+            File::<Block>::open(path),
+            Err(Error::IO(e)) if e.kind() == io::ErrorKind::NotFound
+        ));
+        assert!(!path.exists());
+        assert!(matches!(
+            File::<Block>::make_with(path, 256, read_only.clone()),
+            Err(Error::ReadOnly)
+        ));
+
+        let mut file: File<Block> = File::make(path, 256).unwrap();
+        assert!(matches!(File::<Block>::open(path), Err(Error::Locked)));
+        assert!(matches!(
+            File::<Block>::open_with(path, read_only.clone()),
+            Err(Error::Locked)
+        ));
+        for i in 0..100u32 {
+            file.insert(&i.to_be_bytes(), b"val").unwrap();
+        }
+        drop(file);
+
+        let mut r1: File<Block> = File::open_with(path, read_only.clone()).unwrap();
+        let r2: File<Block> = File::open_with(path, read_only).unwrap();
+        assert!(matches!(File::<Block>::open(path), Err(Error::Locked)));
+        for i in 0..100u32 {
+            assert_eq!(
+                r1.lookup(&i.to_be_bytes()).unwrap().unwrap().deref(),
+                b"val"
+            );
+            assert_eq!(
+                r2.lookup(&i.to_be_bytes()).unwrap().unwrap().deref(),
+                b"val"
+            );
+        }
+        assert!(matches!(r1.insert(b"key", b"val"), Err(Error::ReadOnly)));
+        assert!(matches!(
+            r1.remove(&0u32.to_be_bytes()),
+            Err(Error::ReadOnly)
+        ));
+        assert!(r1.lookup(&0u32.to_be_bytes()).unwrap().is_some());
+        drop(r1);
+        drop(r2);
+
+        let mut file: File<Block> = File::open(path).unwrap();
+        file.remove(&0u32.to_be_bytes()).unwrap();
+    }
+
+    /// Values of 100 KB each, more than a 64 KiB page could hold.
+    fn large_values() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut rng = StdRng::seed_from_u64(42);
+        (0..60u32)
+            .map(|i| {
+                let mut val = vec![0u8; 100 * 1000];
+                rng.fill_bytes(&mut val);
+                (i.to_be_bytes().to_vec(), val)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_large_pages() {
+        let data = large_values();
+        let mem = Mem::new();
+        {
+            let mut file: File<Block, Mem> =
+                File::make_in(mem.clone(), 256 * 1024, Options::default()).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+            assert!(file.root().unwrap().size() > 1);
+        }
+
+        let mut file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+        }
+        for (k, _) in data.iter() {
+            file.remove(k).unwrap();
+        }
+        assert!(file.is_empty().unwrap());
+
+        assert!(matches!(
+            File::<Block, Mem>::make_in(Mem::new(), 2 << 30, Options::default()),
+            Err(Error::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    #[ignore = "writes beyond 4 GiB into a sparse temp file"]
+    fn test_large_file() {
+        let path = &temp_path("test_large");
+        let data = large_values();
+
+        // New pages are allocated beyond 4 GiB.
+        {
+            let mut file: File<Block> = File::make(path, 256 * 1024).unwrap();
+            file.file.truncate(5 << 30).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+            assert!(file.root().unwrap().size() > 1);
+            for idx in 0..file.root().unwrap().size() {
+                let id = file.root().unwrap().slot(idx).unwrap().page;
+                assert!(file.offset(id) > 4 << 30);
+            }
+        }
+        assert!(fs::metadata(path).unwrap().len() > 5 << 30);
+
+        for mmap in [false, true] {
+            let opts = Options {
+                mmap,
+                read_only: true,
+                ..Options::default()
+            };
+            let file: File<Block> = File::open_with(path, opts).unwrap();
+            for (k, v) in data.iter() {
+                assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+            }
+        }
+
+        let mut file: File<Block> = File::open(path).unwrap();
+        for (k, _) in data.iter() {
+            file.remove(k).unwrap();
+        }
+        assert!(file.is_empty().unwrap());
+        drop(file);
+        fs::remove_file(path).unwrap();
+    }
+
+    /// Check that every reference carries the number of entries of its subtree, return total.
+    fn counted<P: Page, S: Io>(file: &File<P, S>, id: u32) -> u64 {
+        let copy = file.page(id).unwrap().copy();
+        copy.iter()
+            .map(|(_, val, page)| {
+                if *page == 0 {
+                    1
+                } else {
+                    let count = counted(file, *page);
+                    assert_eq!(get_count(val), count, "page={} ref={}", id, page);
+                    count
+                }
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_order() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut file: File<Block, Mem> =
+            File::make_in(Mem::new(), 256, Options::default()).unwrap();
+        assert_eq!(file.len().unwrap(), 0);
+        assert!(file.nth(0).unwrap().is_none());
+        assert_eq!(file.rank(b"any").unwrap(), 0);
+
+        let mut keys = BTreeSet::new();
+        for _ in 0..2000 {
+            let key = rng.next_u64().to_be_bytes().to_vec();
+            file.insert(&key, &key).unwrap();
+            keys.insert(key);
+        }
+        // Replacing existing entries and removing missing ones keeps the counts.
+        for key in keys.iter().step_by(7) {
+            file.insert(key, b"new").unwrap();
+            file.remove(&[key.as_slice(), b"0"].concat()).unwrap();
+        }
+        for key in keys.iter().step_by(3).cloned().collect::<Vec<_>>() {
+            file.remove(&key).unwrap();
+            keys.remove(&key);
+        }
+        let keys = keys.into_iter().collect::<Vec<_>>();
+        assert_eq!(file.len().unwrap(), keys.len() as u64);
+        assert_eq!(counted(&file, ROOT), keys.len() as u64);
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(file.rank(key).unwrap(), i as u64);
+            assert_eq!(file.nth(i as u64).unwrap().unwrap().deref(), key.as_slice());
+        }
+        assert!(file.nth(keys.len() as u64).unwrap().is_none());
+
+        for _ in 0..1000 {
+            let a = rng.next_u64().to_be_bytes().to_vec();
+            let b = keys[rng.gen_range(0..keys.len())].clone();
+            let rank = keys.iter().filter(|k| *k < &a).count() as u64;
+            assert_eq!(file.rank(&a).unwrap(), rank);
+
+            let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+            let bounds = [
+                (
+                    Bound::Included(lo.as_slice()),
+                    Bound::Included(hi.as_slice()),
+                ),
+                (
+                    Bound::Excluded(lo.as_slice()),
+                    Bound::Excluded(hi.as_slice()),
+                ),
+                (Bound::Included(lo.as_slice()), Bound::Unbounded),
+                (Bound::Unbounded, Bound::Excluded(hi.as_slice())),
+            ];
+            for bounds in bounds {
+                let expected = keys
+                    .iter()
+                    .filter(|k| RangeBounds::<[u8]>::contains(&bounds, k.as_slice()))
+                    .count() as u64;
+                assert_eq!(file.count_range(bounds).unwrap(), expected);
+            }
+        }
+        let (lo, hi) = (keys[10].as_slice(), keys[5].as_slice());
+        let bounds = (Bound::Included(lo), Bound::Included(hi));
+        assert_eq!(file.count_range(bounds).unwrap(), 0);
+
+        for key in keys.iter() {
+            file.remove(key).unwrap();
+        }
+        assert_eq!(file.len().unwrap(), 0);
+        assert!(file.is_empty().unwrap());
+    }
+
+    /// Descending byte order: a comparator that is not built-in.
+    struct Descending;
+
+    impl Comparator for Descending {
+        fn name(&self) -> &str {
+            "reverse"
+        }
+
+        fn cmp(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            b.cmp(a)
+        }
+    }
+
+    fn check_comparator<P: Page>(order: Arc<dyn Comparator>, keys: Vec<Vec<u8>>) {
+        let mem = Mem::new();
+        let opts = Options {
+            order: Some(order.clone()),
+            ..Options::default()
+        };
+        let mut file: File<P, Mem> = File::make_in(mem.clone(), 256, opts.clone()).unwrap();
+        for key in keys.iter() {
+            file.insert(key, key).unwrap();
+        }
+        let mut sorted = keys.clone();
+        sorted.sort_by(|a, b| order.cmp(a, b));
+        sorted.dedup_by(|a, b| order.cmp(a, b) == Ordering::Equal);
+        assert_eq!(file.len().unwrap(), sorted.len() as u64);
+        assert_eq!(counted(&file, ROOT), sorted.len() as u64);
+
+        let mut this = file.min().unwrap().unwrap().to_vec();
+        let mut found = vec![this.clone()];
+        while let Some(next) = file.above(&this).unwrap().map(|r| r.to_vec()) {
+            found.push(next.clone());
+            this = next;
+        }
+        let same = |a: &[Vec<u8>], b: &[Vec<u8>]| {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b.iter())
+                    .all(|(x, y)| order.cmp(x, y) == Ordering::Equal)
+        };
+        assert!(same(&found, &sorted), "order={}", order.name());
+        for (i, key) in sorted.iter().enumerate() {
+            assert!(file.lookup(key).unwrap().is_some());
+            assert_eq!(file.rank(key).unwrap(), i as u64);
+        }
+        let (lo, hi) = (sorted[10].as_slice(), sorted[20].as_slice());
+        let bounds = (Bound::Included(lo), Bound::Excluded(hi));
+        assert_eq!(file.count_range(bounds).unwrap(), 10);
+        let bounds = (Bound::Unbounded, Bound::Included(hi));
+        assert_eq!(file.count_range(bounds).unwrap(), 21);
+        drop(file);
+
+        // Built-in order is picked by the recorded name, a custom one has to be provided.
+        let reopened = File::<P, Mem>::open_in(mem.clone(), Options::default());
+        match order::builtin(order.name()) {
+            Some(_) => assert_eq!(reopened.unwrap().len().unwrap(), sorted.len() as u64),
+            None => {
+                assert!(matches!(reopened, Err(Error::WrongOrder(name)) if name == order.name()))
+            }
+        }
+        let file = File::<P, Mem>::open_in(mem.clone(), opts).unwrap();
+        for key in sorted.iter().rev() {
+            assert!(file.lookup(key).unwrap().is_some());
+        }
+        drop(file);
+        let other = Options {
+            order: Some(Arc::new(Bytewise)),
+            ..Options::default()
+        };
+        if order.name() != "bytewise" {
+            let reopened = File::<P, Mem>::open_in(mem, other);
+            assert!(matches!(reopened, Err(Error::WrongOrder(name)) if name == order.name()));
+        }
+    }
+
+    #[test]
+    fn test_comparator() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let ints = (0..1000)
+            .map(|_| {
+                (rng.gen::<i32>() >> rng.gen_range(0..31))
+                    .to_be_bytes()
+                    .to_vec()
+            })
+            .collect::<Vec<_>>();
+        let floats = (0..1000)
+            .map(|_| (rng.gen::<f64>() - 0.5) * 1e6)
+            .map(|x| x.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        let words = (0..1000)
+            .map(|_| {
+                let len = rng.gen_range(1..8);
+                (0..len)
+                    .map(|_| *b"aAbBcCzZ".choose(&mut rng).unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        for (order, keys) in [
+            (Arc::new(order::Signed) as Arc<dyn Comparator>, ints),
+            (Arc::new(order::Float), floats),
+            (Arc::new(order::NoCase), words.clone()),
+            (Arc::new(Descending), words),
+        ] {
+            check_comparator::<Block>(order.clone(), keys.clone());
+            check_comparator::<Prefixed>(order, keys);
+        }
+
+        let opts = Options {
+            order: Some(Arc::new(order::NoCase)),
+            ..Options::default()
+        };
+        let mut file: File<Block, Mem> = File::make_in(Mem::new(), 256, opts).unwrap();
+        file.insert(b"Key", b"1").unwrap();
+        file.insert(b"kEY", b"2").unwrap();
+        assert_eq!(file.len().unwrap(), 1);
+        assert_eq!(file.lookup(b"KEY").unwrap().unwrap().deref(), b"2");
+        file.remove(b"key").unwrap();
+        assert!(file.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_estimate() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut file: File<Block, Mem> =
+            File::make_in(Mem::new(), 512, Options::default()).unwrap();
+        assert_eq!(
+            file.approximate_size((Bound::Unbounded, Bound::Unbounded))
+                .unwrap(),
+            Estimate::default()
+        );
+        assert!(file.sample(&mut rng).unwrap().is_none());
+
+        // Keys below 5000 have 100-byte values, the rest have 10-byte ones.
+        let count = 10000u32;
+        for i in 0..count {
+            let val = if i < count / 2 {
+                [1u8; 100].to_vec()
+            } else {
+                [2u8; 10].to_vec()
+            };
+            file.insert(&i.to_be_bytes(), &val).unwrap();
+        }
+
+        // Estimate is exact for keys, and close for bytes if both ends of the range are alike.
+        let a = (count / 2 - 1000).to_be_bytes();
+        let b = (count / 2 + 1000).to_be_bytes();
+        let lo = file
+            .approximate_size((Bound::Unbounded, Bound::Excluded(&a)))
+            .unwrap();
+        let hi = file
+            .approximate_size((Bound::Included(&b), Bound::Unbounded))
+            .unwrap();
+        assert_eq!(lo.keys, (count / 2 - 1000) as u64);
+        assert_eq!(hi.keys, (count / 2 - 1000) as u64);
+        // Entry takes a slot (16 bytes), a key (4 bytes) and a value, pages are 50-80% full.
+        let (lo_bytes, hi_bytes) = (lo.keys * (16 + 4 + 100), hi.keys * (16 + 4 + 10));
+        assert!(lo.bytes > lo_bytes && lo.bytes < lo_bytes * 2, "{:?}", lo);
+        assert!(hi.bytes > hi_bytes && hi.bytes < hi_bytes * 2, "{:?}", hi);
+
+        let a = 1000u32.to_be_bytes();
+        let b = 1100u32.to_be_bytes();
+        let small = file
+            .approximate_size((Bound::Included(&a), Bound::Excluded(&b)))
+            .unwrap();
+        assert_eq!(small.keys, 100);
+        assert!(
+            small.bytes > 100 * 120 && small.bytes < 100 * 240,
+            "{:?}",
+            small
+        );
+
+        // Samples are spread evenly: count hits per each 100 consecutive keys.
+        let samples = 50000;
+        let mut hits = vec![0usize; count as usize / 100];
+        for _ in 0..samples {
+            let key = file.sample(&mut rng).unwrap().unwrap().to_vec();
+            let mut buf = [0u8; 4];
+            buf.copy_from_slice(&key);
+            hits[u32::from_be_bytes(buf) as usize / 100] += 1;
+        }
+        assert!(hits.iter().all(|n| *n > 350 && *n < 650), "{:?}", hits);
+    }
+
+    #[test]
+    fn test_buckets() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mem = Mem::new();
+        let mut file: File<Block, Mem> =
+            File::make_in(mem.clone(), 256, Options::default()).unwrap();
+        assert!(file.buckets().unwrap().is_empty());
+        assert!(matches!(
+            file.bucket("users"),
+            Err(Error::BucketNotFound(_))
+        ));
+
+        file.insert(b"main", b"tree").unwrap();
+        for name in ["users", "orders", "events"] {
+            file.create_bucket(name).unwrap();
+        }
+        assert!(matches!(
+            file.create_bucket("users"),
+            Err(Error::BucketExists(_))
+        ));
+        assert_eq!(file.buckets().unwrap(), vec!["events", "orders", "users"]);
+
+        let data = (0..1000)
+            .map(|_| {
+                (
+                    rng.next_u64().to_be_bytes().to_vec(),
+                    rng.next_u32().to_be_bytes().to_vec(),
+                )
+            })
+            .collect::<Vec<_>>();
+        {
+            let mut users = file.bucket("users").unwrap();
+            let mut orders = file.bucket("orders").unwrap();
+            assert_eq!(users.name(), "users");
+            for (k, v) in data.iter() {
+                users.insert(k, v).unwrap();
+                orders.insert(v, k).unwrap();
+            }
+            assert_eq!(users.len().unwrap(), data.len() as u64);
+            assert_eq!(counted(&file, users.root), data.len() as u64);
+            for (k, v) in data.iter() {
+                assert_eq!(users.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+                assert_eq!(orders.lookup(v).unwrap().unwrap().deref(), k.as_slice());
+                assert!(users.lookup(v).unwrap().is_none());
+            }
+            let events = file.bucket("events").unwrap();
+            assert!(events.is_empty().unwrap());
+            assert!(events.min().unwrap().is_none());
+        }
+        // Buckets do not affect the main tree.
+        assert_eq!(file.len().unwrap(), 1);
+        assert_eq!(file.lookup(b"main").unwrap().unwrap().deref(), b"tree");
+
+        file.rename_bucket("orders", "archive").unwrap();
+        assert!(matches!(
+            file.rename_bucket("orders", "x"),
+            Err(Error::BucketNotFound(_))
+        ));
+        assert!(matches!(
+            file.rename_bucket("archive", "users"),
+            Err(Error::BucketExists(_))
+        ));
+        assert_eq!(file.buckets().unwrap(), vec!["archive", "events", "users"]);
+
+        let stats = file.stats(true).unwrap();
+        assert_eq!(stats.buckets, 3);
+        let walk = stats.walk.unwrap();
+        assert_eq!(walk.unused, 0);
+        let export = file.export().unwrap();
+        assert_eq!(export.buckets.len(), 3);
+        assert!(export.free.is_empty());
+        drop(file);
+
+        let mut file: File<Block, Mem> = File::open_in(mem.clone(), Options::default()).unwrap();
+        assert_eq!(file.buckets().unwrap(), vec!["archive", "events", "users"]);
+        let archive = file.bucket("archive").unwrap();
+        assert_eq!(archive.len().unwrap(), data.len() as u64);
+        for (k, v) in data.iter() {
+            assert_eq!(archive.lookup(v).unwrap().unwrap().deref(), k.as_slice());
+        }
+        drop(archive);
+
+        file.drop_bucket("archive").unwrap();
+        assert!(matches!(
+            file.drop_bucket("archive"),
+            Err(Error::BucketNotFound(_))
+        ));
+        assert_eq!(file.buckets().unwrap(), vec!["events", "users"]);
+        let stats = file.stats(true).unwrap();
+        let walk = stats.walk.unwrap();
+        assert!(stats.free > 1);
+        assert_eq!(walk.unused, stats.free);
+        let users = file.bucket("users").unwrap();
+        assert_eq!(users.len().unwrap(), data.len() as u64);
+        drop(users);
+        drop(file);
+
+        // Pages of the dropped bucket are listed free on open, and reused.
+        let file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        assert_eq!(file.stats(false).unwrap().free, stats.free);
+        let pages = stats.pages;
+        let mut events = file.bucket("events").unwrap();
+        for (k, v) in data.iter() {
+            events.insert(k, v).unwrap();
+        }
+        drop(events);
+        assert_eq!(file.stats(false).unwrap().pages, pages);
+    }
 
-        data.sort();
-        let min = file.min().unwrap().unwrap().to_vec();
-        let max = file.max().unwrap().unwrap().to_vec();
-        assert_eq!(min, data[0].0.to_vec());
-        assert_eq!(max, data.last().unwrap().0.to_vec());
-        assert!(file.above(&max).unwrap().is_none());
-
-        let asc = {
-            let mut result = Vec::with_capacity(data.len());
-            let mut val = file.min().unwrap().unwrap().to_vec();
-            result.push(val.clone());
-            loop {
-                if let Some(next) = file.above(&val).unwrap() {
-                    result.push(next.to_vec());
-                    val = next.to_vec();
+    #[test]
+    fn test_multimap() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mem = Mem::new();
+        let mut file: File<Block, Mem> =
+            File::make_in(mem.clone(), 256, Options::default()).unwrap();
+        file.create_bucket("plain").unwrap();
+        let mut model: BTreeMap<Vec<u8>, BTreeSet<Vec<u8>>> = BTreeMap::new();
+        {
+            let mut tags = file.create_multimap("tags").unwrap();
+            assert_eq!(tags.name(), "tags");
+            // A hot key (with values spanning many pages), and keys being prefixes of others.
+            for _ in 0..3000 {
+                let key = match rng.gen_range(0..4) {
+                    0 => b"hot".to_vec(),
+                    1 => b"ho".to_vec(),
+                    2 => vec![0u8; rng.gen_range(0..3)],
+                    _ => rng.gen_range(0..50u8).to_be_bytes().to_vec(),
+                };
+                let val = rng.gen_range(0..300u16).to_be_bytes().to_vec();
+                if rng.gen_bool(0.8) {
+                    let added = tags.insert(&key, &val).unwrap();
+                    assert_eq!(added, model.entry(key).or_default().insert(val));
                 } else {
-                    break;
+                    let removed = tags.remove(&key, &val).unwrap();
+                    let present = model.get_mut(&key).map(|values| values.remove(&val));
+                    assert_eq!(removed, present.unwrap_or_default());
                 }
             }
-            result
+            let pairs = model.values().map(|values| values.len()).sum::<usize>();
+            assert_eq!(tags.len().unwrap(), pairs as u64);
+            assert!(model[&b"hot".to_vec()].len() > 100);
+            for (key, values) in model.iter() {
+                assert_eq!(tags.count(key).unwrap(), values.len() as u64);
+                assert_eq!(
+                    tags.get(key).unwrap(),
+                    values.iter().cloned().collect::<Vec<_>>()
+                );
+                for val in values.iter() {
+                    assert!(tags.contains(key, val).unwrap());
+                }
+            }
+            assert_eq!(tags.count(b"h").unwrap(), 0);
+            assert!(tags.get(b"hott").unwrap().is_empty());
+
+            let hot = model.remove(b"hot".as_slice()).unwrap();
+            assert_eq!(tags.remove_all(b"hot").unwrap(), hot.len() as u64);
+            assert_eq!(tags.count(b"hot").unwrap(), 0);
+            assert_eq!(
+                tags.count(b"ho").unwrap(),
+                model[&b"ho".to_vec()].len() as u64
+            );
+        }
+
+        // Mode is recorded in the catalog, and kept by rename.
+        assert!(matches!(file.bucket("tags"), Err(Error::Unsupported(_))));
+        assert!(matches!(file.multimap("plain"), Err(Error::Unsupported(_))));
+        assert!(matches!(
+            file.create_multimap("plain"),
+            Err(Error::BucketExists(_))
+        ));
+        file.rename_bucket("tags", "labels").unwrap();
+        assert_eq!(file.buckets().unwrap(), vec!["labels", "plain"]);
+        drop(file);
+
+        let file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        let labels = file.multimap("labels").unwrap();
+        let pairs = model.values().map(|values| values.len()).sum::<usize>();
+        assert_eq!(labels.len().unwrap(), pairs as u64);
+        for (key, values) in model.iter() {
+            assert_eq!(labels.count(key).unwrap(), values.len() as u64);
+        }
+        assert!(file.bucket("plain").unwrap().is_empty().unwrap());
+
+        let opts = Options {
+            order: Some(Arc::new(Descending)),
+            ..Options::default()
         };
-        assert_eq!(
-            asc,
-            data.clone().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
-        );
+        let mut file: File<Block, Mem> = File::make_in(Mem::new(), 256, opts).unwrap();
+        assert!(matches!(
+            file.create_multimap("tags"),
+            Err(Error::Unsupported(_))
+        ));
     }
 
     #[test]
-    fn test_below() {
-        let path = Path::new("target/test_below.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
+    fn test_indexes() {
+        // Index by the first byte of a value, empty values are not indexed.
+        fn group(val: &[u8]) -> Option<Vec<u8>> {
+            val.first().map(|b| vec![*b])
+        }
+        fn expected(data: &HashMap<Vec<u8>, Vec<u8>>, ikey: u8) -> Vec<Vec<u8>> {
+            let mut keys = data
+                .iter()
+                .filter(|(_, v)| v.first() == Some(&ikey))
+                .map(|(k, _)| k.clone())
+                .collect::<Vec<_>>();
+            keys.sort();
+            keys
         }
 
-        let size: u32 = 256;
-        let mut file: File<Block> = File::make(path, size).unwrap();
+        let mut rng = StdRng::seed_from_u64(42);
+        let mem = Mem::new();
+        let mut file: File<Block, Mem> =
+            File::make_in(mem.clone(), 256, Options::default()).unwrap();
+        let mut data = HashMap::new();
+        for _ in 0..300 {
+            let key = rng.next_u64().to_be_bytes().to_vec();
+            let val = vec![rng.gen_range(0..8u8), rng.gen()];
+            file.insert(&key, &val).unwrap();
+            data.insert(key, val);
+        }
+        file.insert(b"none", b"").unwrap();
+        data.insert(b"none".to_vec(), vec![]);
+
+        // Existing entries are indexed on creation.
+        file.create_index(None, "by_group", group).unwrap();
+        assert!(matches!(
+            file.create_index(None, "by_group", group),
+            Err(Error::BucketExists(_))
+        ));
+        assert!(matches!(
+            file.create_index(Some("missing"), "idx", group),
+            Err(Error::BucketNotFound(_))
+        ));
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        assert_eq!(file.index_range("by_group", all).unwrap().len(), 300);
+        assert!(matches!(
+            file.bucket("by_group"),
+            Err(Error::Unsupported(_))
+        ));
+        for ikey in 0..8u8 {
+            assert_eq!(
+                file.index_lookup("by_group", &[ikey]).unwrap(),
+                expected(&data, ikey)
+            );
+        }
 
-        let count = 10;
-        let mut data = {
-            let mut rng = StdRng::seed_from_u64(3);
-            let mut result = (0..count)
-                .into_iter()
-                .map(|i| {
-                    let b = i * count as u8;
-                    (vec![b; 8], vec![b; 8])
-                })
-                .collect::<Vec<_>>();
-            result.shuffle(&mut rng);
-            result
+        // Updates and removals keep the index in sync.
+        let keys = data.keys().cloned().collect::<Vec<_>>();
+        for key in keys.iter().take(200) {
+            if rng.gen_bool(0.3) {
+                file.remove(key).unwrap();
+                data.remove(key);
+            } else {
+                let val = vec![rng.gen_range(0..8u8)];
+                file.insert(key, &val).unwrap();
+                data.insert(key.clone(), val);
+            }
+        }
+        for i in 0..100u32 {
+            let key = i.to_be_bytes().to_vec();
+            let val = vec![rng.gen_range(0..8u8)];
+            file.insert(&key, &val).unwrap();
+            data.insert(key, val);
+        }
+        let indexed = data.values().filter(|v| !v.is_empty()).count();
+        assert_eq!(file.index_range("by_group", all).unwrap().len(), indexed);
+        for ikey in 0..8u8 {
+            assert_eq!(
+                file.index_lookup("by_group", &[ikey]).unwrap(),
+                expected(&data, ikey)
+            );
+        }
+        let range = file
+            .index_range("by_group", (Bound::Excluded(&[2]), Bound::Included(&[4])))
+            .unwrap();
+        let mut want = Vec::new();
+        for ikey in 3..=4u8 {
+            want.extend(expected(&data, ikey).into_iter().map(|k| (vec![ikey], k)));
+        }
+        assert_eq!(range, want);
+        let all = file
+            .index_range("by_group", (Bound::Unbounded, Bound::Excluded(&[8])))
+            .unwrap();
+        assert_eq!(all.len(), indexed);
+        drop(file);
+
+        // Indexes of buckets, registration after reopening: no write until then.
+        let mut file: File<Block, Mem> = File::open_in(mem.clone(), Options::default()).unwrap();
+        let key = 0u32.to_be_bytes();
+        assert!(matches!(
+            file.insert(&key, &[7]),
+            Err(Error::IndexNotRegistered(name)) if name == "by_group"
+        ));
+        assert!(matches!(
+            file.remove(&key),
+            Err(Error::IndexNotRegistered(_))
+        ));
+        assert_eq!(file.lookup(&key).unwrap().unwrap().deref(), &data[&key[..]]);
+        file.create_index(None, "by_group", group).unwrap();
+        file.create_bucket("users").unwrap();
+        file.create_bucket("plain").unwrap();
+        assert!(matches!(
+            file.create_index(Some("users"), "plain", group),
+            Err(Error::Unsupported(_))
+        ));
+        file.create_index(Some("users"), "users_by_group", group)
+            .unwrap();
+        {
+            let mut users = file.bucket("users").unwrap();
+            users.insert(b"alice", &[1, 0]).unwrap();
+            users.insert(b"bob", &[2, 0]).unwrap();
+            users.insert(b"carol", &[1, 0]).unwrap();
+            users.remove(b"bob").unwrap();
+        }
+        assert_eq!(
+            file.index_lookup("users_by_group", &[1]).unwrap(),
+            vec![b"alice".to_vec(), b"carol".to_vec()]
+        );
+        assert!(file
+            .index_lookup("users_by_group", &[2])
+            .unwrap()
+            .is_empty());
+        file.remove(&0u32.to_be_bytes()).unwrap();
+        data.remove(0u32.to_be_bytes().as_slice());
+        for ikey in 0..8u8 {
+            assert_eq!(
+                file.index_lookup("by_group", &[ikey]).unwrap(),
+                expected(&data, ikey)
+            );
+        }
+        drop(file);
+
+        // An index belongs to its tree: it is reused only for the same tree, and dropped along.
+        let mut file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        assert!(matches!(
+            file.create_index(None, "users_by_group", group),
+            Err(Error::Unsupported(_))
+        ));
+        file.drop_bucket("users").unwrap();
+        assert!(matches!(
+            file.index_lookup("users_by_group", &[1]),
+            Err(Error::BucketNotFound(_))
+        ));
+        assert_eq!(file.buckets().unwrap(), vec!["by_group", "plain"]);
+        // Only writes to a tree having an index not registered are rejected.
+        file.bucket("plain").unwrap().insert(b"k", b"v").unwrap();
+        file.create_bucket("users").unwrap();
+        file.create_index(Some("users"), "users_by_group", group)
+            .unwrap();
+        assert!(file
+            .index_lookup("users_by_group", &[1])
+            .unwrap()
+            .is_empty());
+
+        // Index keys are composite, encoded to sort bytewise.
+        let opts = Options {
+            order: Some(Arc::new(Descending)),
+            ..Options::default()
         };
+        let mut file: File<Block, Mem> = File::make_in(Mem::new(), 256, opts).unwrap();
+        assert!(matches!(
+            file.create_index(None, "idx", group),
+            Err(Error::Unsupported(_))
+        ));
+    }
 
-        for (k, v) in data.iter() {
-            debug!("insert: key={} val={}", hex(k), hex(v));
-            file.insert(k, v).unwrap();
+    #[test]
+    fn test_index_fault() {
+        /// Index entries expected from the entries of the main tree.
+        fn entries<S: Io>(file: &File<Block, S>) -> Vec<(Vec<u8>, Vec<u8>)> {
+            let mut entries = Vec::new();
+            let mut next = file.min().unwrap().map(|key| key.to_vec());
+            while let Some(key) = next {
+                let val = file.lookup(&key).unwrap().unwrap().to_vec();
+                next = file.above(&key).unwrap().map(|key| key.to_vec());
+                entries.push((val, key));
+            }
+            entries.sort();
+            entries
         }
 
-        data.sort();
-        let min = file.min().unwrap().unwrap().to_vec();
-        let max = file.max().unwrap().unwrap().to_vec();
-        assert_eq!(min, data[0].0.to_vec());
-        assert_eq!(max, data.last().unwrap().0.to_vec());
-        assert_eq!(file.below(&min).unwrap().map(|v| v.to_vec()), None);
+        let mut rng = StdRng::seed_from_u64(42);
+        let data = random_pairs(500, 8);
+        let all = (Bound::Unbounded, Bound::Unbounded);
+        let mem = Mem::new();
+        let mut file: File<Block, Fault<Mem>> =
+            File::make_in(Fault::new(mem.clone()), 256, Options::default()).unwrap();
+        file.create_index(None, "by_val", |val| Some(val.to_vec()))
+            .unwrap();
+        let (mut failed, mut undone) = (0, 0);
+        for (i, (k, v)) in data.iter().enumerate() {
+            if i % 10 == 0 {
+                file.file.fail_write(rng.gen_range(0..4), 0);
+            }
+            let result = if i % 3 == 2 {
+                file.remove(&data[i - 1].0)
+            } else {
+                file.insert(k, v)
+            };
+            if result.is_err() {
+                // A write failed within the main tree or its index: either both are changed
+                // (only the flush failed) or neither is.
+                failed += 1;
+                undone += (i % 3 != 2 && file.lookup(k).unwrap().is_none()) as usize;
+                file.file.heal();
+            }
+            assert_eq!(file.index_range("by_val", all).unwrap(), entries(&file));
+        }
+        assert!(failed > 10, "failed={}", failed);
+        assert!(undone > 0, "undone={}", undone);
+        assert_eq!(counted(&file, ROOT), file.len().unwrap());
+        file.flush().unwrap();
+        let expected = entries(&file);
+        drop(file);
+
+        let mut file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        file.create_index(None, "by_val", |val| Some(val.to_vec()))
+            .unwrap();
+        assert_eq!(entries(&file), expected);
+        assert_eq!(file.index_range("by_val", all).unwrap(), expected);
+    }
 
-        data.reverse();
-        let desc = {
-            let mut result = Vec::with_capacity(data.len());
-            let mut val = file.max().unwrap().unwrap().to_vec();
-            result.push(val.clone());
-            loop {
-                if let Some(next) = file.below(&val).unwrap() {
-                    result.push(next.to_vec());
-                    val = next.to_vec();
-                } else {
-                    break;
+    #[test]
+    fn test_read_modify_write() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for codec in [Codec::None, Codec::Lz] {
+            let opts = Options {
+                codec,
+                ..Options::default()
+            };
+            let mut file: File<Block, Mem> = File::make_in(Mem::new(), 256, opts).unwrap();
+            let mut model: HashMap<Vec<u8>, u64> = HashMap::new();
+            for _ in 0..2000 {
+                let key = rng.gen_range(0..100u32).to_be_bytes().to_vec();
+                let current = model.get(&key).map(|n| n.to_be_bytes().to_vec());
+                match rng.gen_range(0..4) {
+                    0 => {
+                        let val = file
+                            .update(&key, |old| {
+                                let n = old.map(get_count).unwrap_or_default();
+                                Some((n + 1).to_be_bytes().to_vec())
+                            })
+                            .unwrap();
+                        let n = model.entry(key).or_default();
+                        *n += 1;
+                        assert_eq!(val, Some(n.to_be_bytes().to_vec()));
+                    }
+                    1 => {
+                        let n = rng.gen_range(0..3u64);
+                        let expected = (n > 0).then(|| n.to_be_bytes().to_vec());
+                        let swapped = file
+                            .compare_and_swap(&key, expected.as_deref(), &7u64.to_be_bytes())
+                            .unwrap();
+                        assert_eq!(swapped, current == expected);
+                        if swapped {
+                            model.insert(key, 7);
+                        }
+                    }
+                    2 => {
+                        let val = file.get_or_insert(&key, &1u64.to_be_bytes()).unwrap();
+                        let n = model.entry(key).or_insert(1);
+                        assert_eq!(val, n.to_be_bytes());
+                    }
+                    _ => {
+                        let inserted = file.insert_if_absent(&key, &1u64.to_be_bytes()).unwrap();
+                        assert_eq!(inserted, current.is_none());
+                        model.entry(key).or_insert(1);
+                    }
                 }
             }
-            result
-        };
+            assert_eq!(file.len().unwrap(), model.len() as u64);
+            for (key, n) in model.iter() {
+                assert_eq!(file.lookup(key).unwrap().unwrap().deref(), n.to_be_bytes());
+            }
+
+            // Nothing is stored if the function refuses to, or the value is too large.
+            let large = (0..256).map(|_| rng.gen()).collect::<Vec<u8>>();
+            assert_eq!(file.update(b"none", |_| None).unwrap(), None);
+            assert!(matches!(
+                file.update(b"none", |_| Some(large.clone())),
+                Err(Error::KeyTooLarge { .. })
+            ));
+            assert!(file.lookup(b"none").unwrap().is_none());
+            assert_eq!(file.len().unwrap(), model.len() as u64);
+        }
+
+        // Indexes are updated, expired entries are absent.
+        let mut file: File<Block, Mem> =
+            File::make_in(Mem::new(), 256, Options::default()).unwrap();
+        file.clock = clock;
+        file.create_index(None, "by_val", |val: &[u8]| Some(val.to_vec()))
+            .unwrap();
+        file.insert_with_ttl(b"session", b"old", Duration::from_secs(1))
+            .unwrap();
+        assert!(!file.insert_if_absent(b"session", b"new").unwrap());
+        advance(1_000);
+        assert!(file.lookup(b"session").unwrap().is_none());
+        assert_eq!(file.get_or_insert(b"session", b"new").unwrap(), b"new");
+        assert!(file
+            .compare_and_swap(b"session", Some(b"new"), b"newer")
+            .unwrap());
+        assert!(file.index_lookup("by_val", b"old").unwrap().is_empty());
+        assert!(file.index_lookup("by_val", b"new").unwrap().is_empty());
         assert_eq!(
-            desc,
-            data.clone().into_iter().map(|(k, _)| k).collect::<Vec<_>>()
+            file.index_lookup("by_val", b"newer").unwrap(),
+            vec![b"session".to_vec()]
         );
+        // The deadline is dropped, as by a plain insert.
+        advance(10_000);
+        assert_eq!(file.purge_expired().unwrap(), 0);
+        assert_eq!(file.lookup(b"session").unwrap().unwrap().deref(), b"newer");
+    }
+
+    fn check_replace_take<P: Page>(codec: Codec) {
+        let mut rng = StdRng::seed_from_u64(42);
+        let opts = Options {
+            codec,
+            ..Options::default()
+        };
+        let mut file: File<P, Mem> = File::make_in(Mem::new(), 256, opts).unwrap();
+        let mut model = HashMap::new();
+        for _ in 0..5000 {
+            let key = rng.gen_range(0..500u32).to_be_bytes().to_vec();
+            if rng.gen_bool(0.6) {
+                let val = rng.gen::<u64>().to_be_bytes().to_vec();
+                let old = file.replace(&key, &val).unwrap();
+                assert_eq!(old, model.insert(key, val));
+            } else {
+                let old = file.take(&key).unwrap();
+                assert_eq!(old, model.remove(&key));
+            }
+        }
+        assert_eq!(file.len().unwrap(), model.len() as u64);
+        for (key, val) in model.iter() {
+            assert_eq!(file.lookup(key).unwrap().unwrap().deref(), val.as_slice());
+        }
+        for key in model.keys() {
+            assert!(file.take(key).unwrap().is_some());
+            assert!(file.take(key).unwrap().is_none());
+        }
+        assert!(file.is_empty().unwrap());
     }
 
     #[test]
-    fn test_1k() {
-        let mut rng = thread_rng();
+    fn test_replace_take() {
+        check_replace_take::<Block>(Codec::None);
+        check_replace_take::<Block>(Codec::Lz);
+        check_replace_take::<Prefixed>(Codec::None);
+    }
 
-        let path = Path::new("target/test_1k.tmp");
-        if path.exists() {
-            fs::remove_file(path).unwrap();
+    #[test]
+    fn test_merge_operator() {
+        let mut file: File<Block, Mem> =
+            File::make_in(Mem::new(), 256, Options::default()).unwrap();
+        assert!(matches!(
+            file.merge_value(b"key", b"val"),
+            Err(Error::Unsupported(_))
+        ));
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let mem = Mem::new();
+        let opts = Options {
+            merge: Some(Arc::new(merge::U64Add)),
+            ..Options::default()
+        };
+        let mut file: File<Block, Mem> = File::make_in(mem.clone(), 256, opts).unwrap();
+        let mut model: HashMap<_, u64> = HashMap::new();
+        for _ in 0..2000 {
+            let key = rng.gen_range(0..100u32).to_be_bytes();
+            let n = rng.gen_range(0..1000u64);
+            file.merge_value(&key, &n.to_be_bytes()).unwrap();
+            *model.entry(key).or_default() += n;
         }
+        assert_eq!(file.len().unwrap(), model.len() as u64);
+        for (key, n) in model.iter() {
+            assert_eq!(file.lookup(key).unwrap().unwrap().deref(), n.to_be_bytes());
+        }
+        // Rejected operand (or stored value) leaves the entry as is.
+        assert!(matches!(
+            file.merge_value(&0u32.to_be_bytes(), b"x"),
+            Err(Error::BadOperand(name)) if name == "u64add"
+        ));
+        file.insert(b"text", b"x").unwrap();
+        assert!(matches!(
+            file.merge_value(b"text", &1u64.to_be_bytes()),
+            Err(Error::BadOperand(_))
+        ));
+        assert_eq!(file.lookup(b"text").unwrap().unwrap().deref(), b"x");
+        drop(file);
+
+        // Operator is provided on each open.
+        let opts = Options {
+            merge: merge::builtin("append"),
+            ..Options::default()
+        };
+        let mut file: File<Block, Mem> = File::open_in(mem, opts).unwrap();
+        for word in ["a", "b", "c"] {
+            file.merge_value(b"text", word.as_bytes()).unwrap();
+        }
+        assert_eq!(file.lookup(b"text").unwrap().unwrap().deref(), b"xabc");
+    }
 
-        let size: u32 = 4096;
-        let mut file: File<Block> = File::make(path, size).unwrap();
-
-        let count = 1000;
-        let data = (0..count)
-            .into_iter()
-            .map(|_| {
-                (
-                    rng.next_u64().to_be_bytes().to_vec(),
-                    rng.next_u64().to_be_bytes().to_vec(),
-                )
-            })
-            .collect::<Vec<_>>();
+    thread_local! {
+        static CLOCK: Cell<u64> = const { Cell::new(1_000) };
+    }
 
-        for (i, (k, v)) in data.iter().enumerate() {
-            debug!("({:05}) insert: key={} val={}", i, hex(k), hex(v));
-            file.insert(k, v).unwrap();
-        }
+    fn clock() -> u64 {
+        CLOCK.with(|c| c.get())
+    }
 
-        let mut file: File<Block> = File::open(path).unwrap();
+    fn advance(millis: u64) {
+        CLOCK.with(|c| c.set(c.get() + millis));
+    }
 
-        for (k, v) in data.iter() {
-            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v);
+    fn scan<T: Tree>(tree: &T) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        let mut next = tree.min().unwrap().map(|key| key.to_vec());
+        while let Some(key) = next {
+            next = tree.above(&key).unwrap().map(|key| key.to_vec());
+            keys.push(key);
         }
+        keys
+    }
 
-        let mut sorted = data.iter().map(|(k, _)| k).cloned().collect::<Vec<_>>();
-        sorted.sort();
-        let min = file.min().unwrap().unwrap().to_vec();
-        let max = file.max().unwrap().unwrap().to_vec();
-        assert_eq!(min, sorted[0]);
-        assert_eq!(max, sorted.last().cloned().unwrap());
-        assert_eq!(file.below(&min).unwrap().map(|v| v.to_vec()), None);
-        assert_eq!(file.above(&max).unwrap().map(|v| v.to_vec()), None);
-        let asc = {
-            let mut result = Vec::with_capacity(data.len());
-            let mut this = file.min().unwrap().unwrap().to_vec();
-            result.push(this.clone());
-            loop {
-                if let Some(next) = file.above(&this).unwrap() {
-                    result.push(next.to_vec());
-                    this = next.to_vec();
-                } else {
-                    break;
-                }
+    #[test]
+    fn test_expiry() {
+        let mem = Mem::new();
+        let mut file: File<Block, Mem> =
+            File::make_in(mem.clone(), 256, Options::default()).unwrap();
+        file.clock = clock;
+        let ttl = |secs| Duration::from_secs(secs);
+
+        // Even keys expire after 10s, odd ones after 20s, the ones below 10 never expire.
+        for i in 0..200u32 {
+            let key = i.to_be_bytes();
+            if i < 10 {
+                file.insert(&key, b"forever").unwrap();
+            } else {
+                file.insert_with_ttl(&key, b"temporary", ttl(10 + 10 * (i % 2) as u64))
+                    .unwrap();
             }
-            result
-        };
-        let desc = {
-            let mut result = Vec::with_capacity(data.len());
-            let mut this = file.max().unwrap().unwrap().to_vec();
-            result.push(this.clone());
-            loop {
-                if let Some(next) = file.below(&this).unwrap() {
-                    result.push(next.to_vec());
-                    this = next.to_vec();
-                } else {
-                    break;
-                }
+        }
+        file.create_index(None, "by_val", |val: &[u8]| Some(val.to_vec()))
+            .unwrap();
+        assert_eq!(scan(&file).len(), 200);
+
+        advance(10_000);
+        let live = (0..200u32)
+            .filter(|i| *i < 10 || i % 2 == 1)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(scan(&file), live);
+        assert!(file.lookup(&10u32.to_be_bytes()).unwrap().is_none());
+        assert!(file.lookup(&11u32.to_be_bytes()).unwrap().is_some());
+        assert_eq!(file.max().unwrap().unwrap().deref(), 199u32.to_be_bytes());
+        assert_eq!(
+            file.below(&199u32.to_be_bytes()).unwrap().unwrap().deref(),
+            197u32.to_be_bytes()
+        );
+        // Expired entries are counted until removed.
+        assert_eq!(file.len().unwrap(), 200);
+
+        // Writes remove a few expired entries, a plain insert drops the deadline.
+        file.insert(&11u32.to_be_bytes(), b"kept").unwrap();
+        assert_eq!(file.len().unwrap(), 200 - SWEEP as u64);
+        assert_eq!(file.purge_expired().unwrap(), 95 - SWEEP as u64);
+        assert_eq!(file.len().unwrap(), live.len() as u64);
+        assert_eq!(file.index_lookup("by_val", b"temporary").unwrap().len(), 94);
+        assert_eq!(file.purge_expired().unwrap(), 0);
+        drop(file);
+
+        // Deadlines are persisted, expired entries are removed along with their index entries.
+        let mut file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        file.clock = clock;
+        advance(10_000);
+        assert_eq!(scan(&file).len(), 11);
+        assert!(matches!(
+            file.purge_expired(),
+            Err(Error::IndexNotRegistered(_))
+        ));
+        // Sweeping fails until the index is registered, but writes to other trees do not.
+        let mut other = file.create_bucket("other").unwrap();
+        other.insert(b"key", b"val").unwrap();
+        assert_eq!(file.len().unwrap(), live.len() as u64);
+        file.create_index(None, "by_val", |val: &[u8]| Some(val.to_vec()))
+            .unwrap();
+        assert_eq!(file.purge_expired().unwrap(), 94);
+        assert!(file
+            .index_lookup("by_val", b"temporary")
+            .unwrap()
+            .is_empty());
+        assert_eq!(file.len().unwrap(), 11);
+        assert_eq!(
+            file.lookup(&11u32.to_be_bytes()).unwrap().unwrap().deref(),
+            b"kept"
+        );
+        let expiry = file.head.expiry.get();
+        assert!(file.is_empty_in(expiry).unwrap());
+
+        // Buckets: entries of a dropped bucket leave nothing behind in the expiry tree.
+        {
+            let mut sessions = file.create_bucket("sessions").unwrap();
+            for i in 0..50u32 {
+                sessions
+                    .insert_with_ttl(&i.to_be_bytes(), b"s", ttl(5))
+                    .unwrap();
             }
-            result
+            sessions.remove(&0u32.to_be_bytes()).unwrap();
+            assert_eq!(scan(&sessions).len(), 49);
+        }
+        assert!(!file.is_empty_in(expiry).unwrap());
+        file.drop_bucket("sessions").unwrap();
+        assert!(file.is_empty_in(expiry).unwrap());
+        let export = file.export().unwrap();
+        assert_eq!(export.expiry, expiry);
+        assert!(export.pages.iter().any(|page| page.id == expiry));
+
+        // Zero TTL: expired right away.
+        file.insert_with_ttl(b"gone", b"", ttl(0)).unwrap();
+        assert!(file.lookup(b"gone").unwrap().is_none());
+        assert_eq!(file.len().unwrap(), 11);
+
+        let opts = Options {
+            order: Some(Arc::new(Descending)),
+            ..Options::default()
         };
+        let mut file: File<Block, Mem> = File::make_in(Mem::new(), 256, opts).unwrap();
+        assert!(matches!(
+            file.insert_with_ttl(b"key", b"val", ttl(1)),
+            Err(Error::Unsupported(_))
+        ));
+    }
 
-        for (i, (put, got)) in sorted.iter().zip(asc.iter()).enumerate() {
-            assert_eq!(
-                put,
-                got,
-                "ASC: index={}: expected '{}' but got '{}'",
-                i,
-                hex(put),
-                hex(got)
-            );
+    #[test]
+    fn test_export() {
+        let mem = Mem::new();
+        let mut file: File<Block, Mem> = File::make_in(mem, 256, Options::default()).unwrap();
+        let export = file.export().unwrap();
+        assert_eq!(export.pages.len(), 1);
+        assert!(export.pages[0].leaf && export.pages[0].slots.is_empty());
+        assert!(export.free.is_empty());
+
+        for i in 0..200u32 {
+            file.insert(&i.to_be_bytes(), &[0u8; 8]).unwrap();
+        }
+        for i in 0..100u32 {
+            file.remove(&i.to_be_bytes()).unwrap();
+        }
+        let stats = file.stats(true).unwrap();
+        let walk = stats.walk.unwrap();
+        let export = file.export().unwrap();
+        assert_eq!(export.root, ROOT);
+        assert_eq!(export.pages[0].id, ROOT);
+        assert_eq!(export.pages.len() as u64, walk.leaves + walk.nodes);
+        assert_eq!(export.free.len() as u64, walk.unused);
+        assert!(!export.free.is_empty());
+
+        let keys = export
+            .pages
+            .iter()
+            .filter(|p| p.leaf)
+            .flat_map(|p| p.slots.iter().map(|s| s.key.clone()))
+            .collect::<Vec<_>>();
+        let expected = (100..200u32)
+            .map(|i| hex(&i.to_be_bytes()))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(keys.iter().cloned().collect::<BTreeSet<_>>(), expected);
+        for page in export.pages.iter().filter(|p| !p.leaf) {
+            for slot in page.slots.iter() {
+                let child = slot.page.unwrap();
+                let info = export.pages.iter().find(|p| p.id == child).unwrap();
+                assert_eq!(info.parent, page.id);
+            }
         }
-        assert_eq!(asc, sorted);
 
-        sorted.reverse();
-        for (i, (put, got)) in sorted.iter().zip(desc.iter()).enumerate() {
-            assert_eq!(
-                put,
-                got,
-                "DESC: index={}: expected '{}' but got '{}'",
-                i,
-                hex(put),
-                hex(got)
-            );
+        let dot = export.to_dot();
+        assert!(dot.starts_with("digraph yakvdb {"));
+        assert!(dot.contains(&format!(
+            "p1 [label=\"{{page=1 node {}%",
+            export.pages[0].full
+        )));
+        assert!(dot.contains(&format!(
+            "p1:s0 -> p{};",
+            export.pages[0].slots[0].page.unwrap()
+        )));
+        assert!(dot.contains("free [label=\"{free|"));
+
+        let json: serde_json::Value = serde_json::from_str(&export.to_json()).unwrap();
+        assert_eq!(json["root"], 1);
+        assert_eq!(json["pages"].as_array().unwrap().len(), export.pages.len());
+        assert_eq!(json["free"].as_array().unwrap().len(), export.free.len());
+        assert!(json["pages"][0]["slots"][0]["page"].is_number());
+    }
+
+    #[test]
+    fn test_stats() {
+        let data = random_pairs(2000, 4);
+
+        let mem = Mem::new();
+        {
+            let mut file: File<Block, Mem> =
+                File::make_in(mem.clone(), 256, Options::default()).unwrap();
+            let stats = file.stats(true).unwrap();
+            assert_eq!((stats.pages, stats.keys, stats.height), (1, 0, 1));
+            assert_eq!(stats.walk.unwrap().leaves, 1);
+
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+            for (k, _) in data.iter().take(500) {
+                file.remove(k).unwrap();
+            }
+            let stats = file.stats(false).unwrap();
+            assert_eq!(stats.keys, 1500);
+            assert!(stats.height >= 3);
+            assert!(stats.free > 0);
+            assert_eq!(stats.dirty, 0);
+            assert_eq!(stats.cached, stats.pages);
+            assert!(stats.walk.is_none());
         }
-        assert_eq!(desc, sorted);
 
-        for (i, (key, _)) in data.iter().enumerate() {
-            debug!("({:05}) remove: key={}", i, hex(key));
-            file.remove(key).unwrap();
+        let file: File<Block, Mem> = File::open_in(mem, Options::default()).unwrap();
+        let stats = file.stats(true).unwrap();
+        assert_eq!(stats.cached, 1);
+        let walk = stats.walk.clone().unwrap();
+        assert_eq!(walk.leaves + walk.nodes + walk.unused, stats.pages);
+        assert!(walk.nodes > 1 && walk.leaves > walk.nodes);
+        assert!(walk.full > 30.0 && walk.full < 90.0);
+        assert_eq!(walk.key_bytes, 1500 * 8);
+        assert_eq!(walk.val_bytes, 1500 * 4);
+
+        for (k, _) in data.iter().skip(500) {
+            file.lookup(k).unwrap().unwrap();
         }
+        let cold = file.stats(false).unwrap();
+        assert_eq!(cold.cached, walk.leaves + walk.nodes);
+        assert_eq!(cold.misses, cold.cached - 1);
+        for (k, _) in data.iter().skip(500) {
+            file.lookup(k).unwrap().unwrap();
+        }
+        let warm = file.stats(false).unwrap();
+        assert_eq!(warm.misses, cold.misses);
+        assert!(warm.hit_ratio() > cold.hit_ratio());
+        assert!(format!("{}", warm).contains("keys=1500"));
+    }
 
-        for (i, (key, _)) in data.iter().enumerate() {
-            debug!("({:05}) lookup: key={}", i, hex(key));
-            let found = file
-                .lookup(key)
-                .unwrap()
-                .map(|v| v.borrow().to_vec())
-                .map(|v| hex(&v));
-            assert_eq!(found, None);
+    #[test]
+    fn test_versions() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let horizon = 300;
+        let opts = Options {
+            horizon: Some(horizon),
+            ..Options::default()
+        };
+        let mem = Mem::new();
+        let mut file: File<Block, Mem> = File::make_in(mem.clone(), 256, opts).unwrap();
+        assert_eq!(file.sequence().unwrap(), 0);
+        assert_eq!(file.lookup_at(b"key", 0).unwrap(), None);
+        assert!(file
+            .scan_at((Bound::Unbounded, Bound::Unbounded), 0)
+            .unwrap()
+            .is_empty());
+        // Writes changing nothing do not create the history tree.
+        file.update(b"key", |_| None).unwrap();
+        file.remove(b"key").unwrap();
+        assert_eq!(file.head.history.get(), 0);
+        assert_eq!(file.stats(false).unwrap().pages, 1);
+        assert_eq!(file.sequence().unwrap(), 0);
+        // Neither does a failed write: the key fits, but its version does not.
+        let key = vec![1u8; Block::max_entry(file.cap()) as usize - COUNT];
+        assert!(matches!(
+            file.insert(&key, b""),
+            Err(Error::KeyTooLarge { .. })
+        ));
+        assert_eq!(file.head.history.get(), 0);
+        let stats = file.stats(false).unwrap();
+        assert_eq!((stats.pages, stats.free), (2, 1));
+        assert_eq!(file.sequence().unwrap(), 0);
+
+        // Snapshots of the main tree after each write (by sequence number).
+        let mut snapshots = vec![BTreeMap::new()];
+        let keys = (0..60u8)
+            .map(|k| vec![k % 20; k as usize / 20 + 1])
+            .collect::<Vec<_>>();
+        for _ in 0..3000 {
+            let key = keys.choose(&mut rng).unwrap().clone();
+            let mut model: BTreeMap<Vec<u8>, Vec<u8>> = snapshots.last().unwrap().clone();
+            match rng.gen_range(0..5) {
+                0 => {
+                    file.remove(&key).unwrap();
+                    if model.remove(&key).is_none() {
+                        continue;
+                    }
+                }
+                1 => {
+                    file.update(&key, |_| None).unwrap();
+                    continue;
+                }
+                _ => {
+                    let val = rng.next_u32().to_be_bytes()[..rng.gen_range(0..4)].to_vec();
+                    file.insert(&key, &val).unwrap();
+                    model.insert(key, val);
+                }
+            }
+            snapshots.push(model);
+            assert_eq!(file.sequence().unwrap(), snapshots.len() as u64 - 1);
         }
+        // Writes to buckets are not versioned.
+        file.create_bucket("plain")
+            .unwrap()
+            .insert(b"key", b"val")
+            .unwrap();
+
+        let check = |file: &File<Block, Mem>, rng: &mut StdRng| {
+            let last = snapshots.len() as u64 - 1;
+            assert_eq!(file.sequence().unwrap(), last);
+            let cutoff = last - u64::from(horizon);
+            for _ in 0..300 {
+                let seq = rng.gen_range(cutoff..=last);
+                let key = keys.choose(rng).unwrap();
+                let val = snapshots[seq as usize].get(key).cloned();
+                assert_eq!(file.lookup_at(key, seq).unwrap(), val, "seq={}", seq);
+            }
+            for _ in 0..30 {
+                let seq = rng.gen_range(cutoff..=last);
+                let (lo, hi) = (keys.choose(rng).unwrap(), keys.choose(rng).unwrap());
+                let found = file
+                    .scan_at((Bound::Excluded(lo), Bound::Included(hi)), seq)
+                    .unwrap();
+                let model = snapshots[seq as usize]
+                    .iter()
+                    .filter(|(key, _)| *key > lo && *key <= hi)
+                    .map(|(key, val)| (key.clone(), val.clone()))
+                    .collect::<Vec<_>>();
+                assert_eq!(found, model, "seq={}", seq);
+            }
+            let all = file
+                .scan_at((Bound::Unbounded, Bound::Unbounded), last + 10)
+                .unwrap();
+            assert_eq!(
+                all,
+                snapshots[last as usize]
+                    .clone()
+                    .into_iter()
+                    .collect::<Vec<_>>()
+            );
+            assert!(matches!(
+                file.lookup_at(b"key", cutoff - 1),
+                Err(Error::Pruned(seq)) if seq == cutoff
+            ));
+            // Only versions within the horizon (and the latest ones before it) are retained.
+            let history = file.len_in(file.head.history.get()).unwrap();
+            assert!(history <= 1 + 2 * u64::from(horizon) + keys.len() as u64);
+        };
+        check(&file, &mut rng);
+        drop(file);
 
-        let copy = file.root().copy();
-        debug!("{}", file.dump());
-        assert!(copy.is_empty());
+        let opts = Options {
+            horizon: Some(1),
+            ..Options::default()
+        };
+        let file: File<Block, Mem> = File::open_in(mem, opts).unwrap();
+        check(&file, &mut rng);
+        let export = file.export().unwrap();
+        assert_eq!(export.history, file.head.history.get());
+        assert!(export.pages.iter().any(|page| page.id == export.history));
+
+        let mut file: File<Block, Mem> =
+            File::make_in(Mem::new(), 256, Options::default()).unwrap();
+        file.insert(b"key", b"val").unwrap();
+        assert_eq!(file.sequence().unwrap(), 0);
+        assert!(matches!(
+            file.lookup_at(b"key", 0),
+            Err(Error::Unsupported(_))
+        ));
+        for (horizon, order) in [
+            (0, None),
+            (1, Some(Arc::new(order::Signed) as Arc<dyn Comparator>)),
+        ] {
+            let opts = Options {
+                horizon: Some(horizon),
+                order,
+                ..Options::default()
+            };
+            assert!(matches!(
+                File::<Block, Mem>::make_in(Mem::new(), 256, opts),
+                Err(Error::Unsupported(_))
+            ));
+        }
     }
 }
@@ -0,0 +1,228 @@
+use memmap2::Mmap;
+use std::fs::{self, TryLockError};
+use std::io;
+#[cfg(unix)]
+use std::io::IoSlice;
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt;
+
+/// Max number of buffers passed to a single vectored write (POSIX guarantees at least 16,
+/// Linux allows 1024).
+#[cfg(unix)]
+const IOV_MAX: usize = 1024;
+
+/// Storage backend with positional I/O: each call carries its own offset, there is no shared
+/// cursor to seek, so all methods take `&self` and independent calls do not need to be serialised.
+/// Implementations: `fs::File`, in-memory `mem::Mem` and fault-injecting `fault::Fault` (tests).
+pub trait Io {
+    /// Fill the whole buffer with bytes starting at given offset.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Write the whole buffer starting at given offset.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Write adjacent buffers (e.g. consecutive pages) starting at given offset.
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> io::Result<()> {
+        let mut at = offset;
+        for buf in bufs {
+            self.write_at(buf, at)?;
+            at += buf.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Write the whole buffer at the end, returns the offset it was written at.
+    fn append(&self, buf: &[u8]) -> io::Result<u64> {
+        let offset = self.len()?;
+        self.write_at(buf, offset)?;
+        Ok(offset)
+    }
+
+    /// Total number of bytes available.
+    fn len(&self) -> io::Result<u64>;
+
+    /// No bytes are available.
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Shrink (or extend with zeroes) to given number of bytes.
+    fn truncate(&self, len: u64) -> io::Result<()>;
+
+    /// Make all written bytes durable.
+    fn sync(&self) -> io::Result<()>;
+
+    /// Read-only memory mapping of the whole content, `None` if not supported.
+    fn map(&self) -> io::Result<Option<Mmap>> {
+        Ok(None)
+    }
+
+    /// Take an advisory lock without blocking: exclusive for a writer, shared for a reader.
+    /// Fails with `WouldBlock` if a conflicting lock is held. The lock lasts until drop.
+    fn lock(&self, _exclusive: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Io for fs::File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.read_exact_at(buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        // `seek_read` moves the cursor, but every call here passes its own offset anyway.
+        while !buf.is_empty() {
+            match self.seek_read(buf, offset) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.write_all_at(buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn write_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.seek_write(buf, offset) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Single `pwritev` call per `IOV_MAX` buffers; other platforms write buffer by buffer.
+    #[cfg(unix)]
+    fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> io::Result<()> {
+        let mut slices = bufs.iter().map(|buf| IoSlice::new(buf)).collect::<Vec<_>>();
+        let mut slices = &mut slices[..];
+        let mut at = offset;
+        IoSlice::advance_slices(&mut slices, 0); // skip empty buffers
+        while !slices.is_empty() {
+            let n = slices.len().min(IOV_MAX);
+            // SAFETY: `IoSlice` is ABI-compatible with `iovec`, the slices outlive the call.
+            let written = unsafe {
+                libc::pwritev(
+                    self.as_raw_fd(),
+                    slices.as_ptr() as *const libc::iovec,
+                    n as libc::c_int,
+                    at as libc::off_t,
+                )
+            };
+            if written < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            if written == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            at += written as u64;
+            IoSlice::advance_slices(&mut slices, written as usize);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.sync_all()
+    }
+
+    fn map(&self) -> io::Result<Option<Mmap>> {
+        // SAFETY: the mapping is read-only and the file is modified only via `write` calls made
+        // by the owner (that are visible through the shared mapping); it is never truncated.
+        let map = unsafe { Mmap::map(self)? };
+        Ok(Some(map))
+    }
+
+    fn lock(&self, exclusive: bool) -> io::Result<()> {
+        // `File::try_lock` (Rust 1.89, hence `rust-version`) is `flock` on unix: the lock belongs
+        // to this open file (not the process).
+        let result = if exclusive {
+            self.try_lock()
+        } else {
+            self.try_lock_shared()
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(TryLockError::WouldBlock) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Io;
+    use crate::disk::testing::temp_path;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn test_positional() {
+        let path = temp_path("test_io");
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .unwrap();
+
+        file.write_at(b"0123456789", 0).unwrap();
+        file.write_at(b"abc", 3).unwrap();
+        file.write_vectored_at(&[b"X", b"", b"YZ"], 8).unwrap();
+        assert_eq!(file.len().unwrap(), 11);
+
+        let mut buf = [0u8; 11];
+        file.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"012abc67XYZ");
+
+        let mut buf = [0u8; 4];
+        file.read_at(&mut buf, 2).unwrap();
+        assert_eq!(&buf, b"2abc");
+        assert!(file.read_at(&mut buf, 9).is_err());
+
+        // More buffers than a single vectored write accepts.
+        let pages = (0..3000u32).map(|i| i.to_be_bytes()).collect::<Vec<_>>();
+        let bufs = pages.iter().map(|p| &p[..]).collect::<Vec<_>>();
+        file.write_vectored_at(&bufs, 100).unwrap();
+        let mut buf = vec![0u8; 4 * 3000];
+        file.read_at(&mut buf, 100).unwrap();
+        assert_eq!(buf, pages.concat());
+
+        assert_eq!(file.append(b"end").unwrap(), 100 + 4 * 3000);
+        file.truncate(11).unwrap();
+        assert_eq!(file.len().unwrap(), 11);
+        assert_eq!(&file.map().unwrap().unwrap()[..], b"012abc67XYZ");
+    }
+}
@@ -0,0 +1,86 @@
+use crate::disk::io::Io;
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+
+/// In-memory storage (e.g. for fast tests). Clones share the same content, thus a database
+/// can be "re-opened" from a clone of the storage it was created in.
+#[derive(Default, Clone)]
+pub struct Mem {
+    data: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Mem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy of the whole content.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.data.borrow().clone()
+    }
+}
+
+impl Io for Mem {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let data = self.data.borrow();
+        let at = offset as usize;
+        match data.get(at..(at + buf.len())) {
+            Some(src) => {
+                buf.copy_from_slice(src);
+                Ok(())
+            }
+            None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+        }
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let mut data = self.data.borrow_mut();
+        let at = offset as usize;
+        if data.len() < at + buf.len() {
+            data.resize(at + buf.len(), 0);
+        }
+        data[at..(at + buf.len())].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.borrow().len() as u64)
+    }
+
+    fn truncate(&self, len: u64) -> io::Result<()> {
+        self.data.borrow_mut().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mem() {
+        let mem = Mem::new();
+        assert_eq!(mem.len().unwrap(), 0);
+
+        mem.write_at(b"abc", 2).unwrap();
+        assert_eq!(mem.bytes(), b"\0\0abc");
+        mem.write_vectored_at(&[b"x", b"yz"], 4).unwrap();
+        assert_eq!(mem.append(b"!").unwrap(), 7);
+        assert_eq!(mem.bytes(), b"\0\0abxyz!");
+
+        let copy = mem.clone();
+        let mut buf = [0u8; 3];
+        copy.read_at(&mut buf, 3).unwrap();
+        assert_eq!(&buf, b"bxy");
+        assert!(copy.read_at(&mut buf, 6).is_err());
+
+        copy.truncate(4).unwrap();
+        assert_eq!(mem.bytes(), b"\0\0ab");
+        assert!(mem.map().unwrap().is_none());
+    }
+}
@@ -1,2 +1,12 @@
-pub(crate) mod block;
-pub(crate) mod file;
+pub mod block;
+pub mod codec;
+pub(crate) mod crypt;
+pub mod export;
+#[cfg(test)]
+pub(crate) mod fault;
+pub mod file;
+pub mod io;
+pub mod mem;
+pub mod prefix;
+#[cfg(test)]
+pub(crate) mod testing;
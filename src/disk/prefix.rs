@@ -0,0 +1,753 @@
+use crate::api::error::{Error, Result};
+use crate::api::order::Comparator;
+use crate::api::page::{Page, Slot, View, COUNT};
+use crate::util::bsearch::bsearch;
+use crate::util::key::lcp;
+use bytes::{BufMut, BytesMut};
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::mem::size_of;
+
+/// Page layout that stores a common key prefix once per page and only key suffixes per slot.
+///
+/// Layout: header (id, length, size, reserved, prefix length), prefix bytes, slots, free space,
+/// then packed (suffix, value) payloads at the end of the page. A key that does not share the
+/// current page prefix is stored in full and flagged in its slot, so adding an entry never makes
+/// other entries grow. Entries sharing the prefix are added and removed in place, as in `Block`;
+/// the whole page is re-encoded (and the prefix re-evaluated) only when a new key does not share
+/// the current prefix, or when the page is empty.
+/// The buffer is owned by a page that can be changed, or borrowed by a read-only one.
+pub struct Prefixed<B = BytesMut> {
+    buf: B,
+
+    /// Full keys decoded from the page, computed lazily on first access after a change.
+    keys: OnceCell<Vec<Vec<u8>>>,
+}
+
+impl AsMut<[u8]> for Prefixed {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.keys = OnceCell::new();
+        &mut self.buf[..]
+    }
+}
+
+impl AsRef<[u8]> for Prefixed {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..]
+    }
+}
+
+const ID_OFFSET: usize = 0;
+const CAP_OFFSET: usize = 4;
+const SIZE_OFFSET: usize = 8;
+const PLEN_OFFSET: usize = 16;
+const RESERVED: u32 = 0xC0DE1543;
+
+/// Slot `klen` flag marking a key stored in full (not sharing the page prefix).
+const FULL_KEY: u32 = 1 << 31;
+
+impl<B: AsRef<[u8]>> Prefixed<B> {
+    fn corrupt(&self, reason: String) -> Error {
+        Error::CorruptPage {
+            id: self.id(),
+            reason,
+        }
+    }
+
+    fn prefix(&self) -> &[u8] {
+        let len = get_u32(self.buf.as_ref(), PLEN_OFFSET) as usize;
+        &self.buf.as_ref()[HEAD..(HEAD + len)]
+    }
+
+    /// Slot as stored in the page, including the `FULL_KEY` flag.
+    fn raw_slot(&self, idx: u32) -> Slot {
+        let pos = HEAD + self.prefix().len() + SLOT * idx as usize;
+        let offset = get_u32(self.buf.as_ref(), pos);
+        let klen = get_u32(self.buf.as_ref(), pos + 4);
+        let vlen = get_u32(self.buf.as_ref(), pos + 8);
+        let page = get_u32(self.buf.as_ref(), pos + 12);
+        Slot::new(offset, klen, vlen, page)
+    }
+
+    fn keys(&self) -> &Vec<Vec<u8>> {
+        self.keys.get_or_init(|| {
+            let prefix = self.prefix();
+            (0..self.size())
+                .map(|idx| {
+                    let slot = self.raw_slot(idx);
+                    let at = slot.offset as usize;
+                    let to = at + (slot.klen & !FULL_KEY) as usize;
+                    if slot.klen & FULL_KEY > 0 {
+                        self.buf.as_ref()[at..to].to_vec()
+                    } else {
+                        let mut key = Vec::with_capacity(prefix.len() + to - at);
+                        key.extend_from_slice(prefix);
+                        key.extend_from_slice(&self.buf.as_ref()[at..to]);
+                        key
+                    }
+                })
+                .collect()
+        })
+    }
+}
+
+impl Prefixed {
+    fn put_entry(
+        &mut self,
+        key: &[u8],
+        val: &[u8],
+        page: u32,
+        cmp: &dyn Comparator,
+    ) -> Option<u32> {
+        if self.size() > 0 && key.starts_with(self.prefix()) {
+            // Only the suffix of the key is stored, the replaced entry (if any) frees its room.
+            let len = (key.len() - self.prefix().len() + val.len() + SLOT) as u32;
+            let found = self.find_idx(key, cmp);
+            let room = match found {
+                Ok(idx) => {
+                    let slot = self.raw_slot(idx);
+                    self.free() + (slot.klen & !FULL_KEY) + slot.vlen + SLOT as u32
+                }
+                Err(_) => self.free(),
+            };
+            if room < len {
+                return None;
+            }
+            let idx = match found {
+                Ok(idx) => {
+                    self.remove(idx);
+                    idx
+                }
+                Err(idx) => idx,
+            };
+            self.insert(idx, key, val, page);
+            return Some(idx);
+        }
+
+        let mut entries = self.copy();
+        let entry = (key.to_vec(), val.to_vec(), page);
+        let idx = match entries.binary_search_by(|(k, _, _)| cmp.cmp(k, key)) {
+            Ok(idx) => {
+                entries[idx] = entry;
+                idx
+            }
+            Err(idx) => {
+                entries.insert(idx, entry);
+                idx
+            }
+        };
+
+        let prefix = best_prefix(self.prefix(), &entries);
+        if encoded_len(&prefix, &entries) > self.cap() as usize {
+            return None;
+        }
+        self.encode(&prefix, entries);
+        Some(idx as u32)
+    }
+
+    /// Index of the key if present, otherwise index where the key must be inserted.
+    fn find_idx(&self, key: &[u8], cmp: &dyn Comparator) -> std::result::Result<u32, u32> {
+        match self.ceil(key, cmp) {
+            Some(idx) if cmp.cmp(self.key(idx), key) == Ordering::Equal => Ok(idx),
+            Some(idx) => Err(idx),
+            None => Err(self.size()),
+        }
+    }
+
+    /// Lowest payload offset: payloads are packed between it and the end of the page.
+    fn payload_offset(&self) -> usize {
+        (0..self.size())
+            .map(|idx| self.raw_slot(idx).offset as usize)
+            .min()
+            .unwrap_or(self.cap() as usize)
+    }
+
+    /// Insert an entry whose key shares the page prefix, without touching other payloads.
+    /// The caller must make sure the entry fits.
+    fn insert(&mut self, idx: u32, key: &[u8], val: &[u8], page: u32) {
+        let size = self.size() as usize;
+        let lo = HEAD + self.prefix().len();
+        let stored = &key[self.prefix().len()..];
+        let offset = self.payload_offset() - stored.len() - val.len();
+
+        let pos = lo + SLOT * idx as usize;
+        self.buf.copy_within(pos..(lo + SLOT * size), pos + SLOT);
+        put_u32(&mut self.buf, pos, offset as u32);
+        put_u32(&mut self.buf, pos + 4, stored.len() as u32);
+        put_u32(&mut self.buf, pos + 8, val.len() as u32);
+        put_u32(&mut self.buf, pos + 12, page);
+        put_u32(&mut self.buf, SIZE_OFFSET, size as u32 + 1);
+
+        put_slice(&mut self.buf, offset, stored);
+        put_slice(&mut self.buf, offset + stored.len(), val);
+
+        if let Some(keys) = self.keys.get_mut() {
+            keys.insert(idx as usize, key.to_vec());
+        }
+    }
+
+    /// Re-write the whole page content (but header id and capacity) from given sorted entries.
+    fn encode(&mut self, prefix: &[u8], entries: Vec<(Vec<u8>, Vec<u8>, u32)>) {
+        let cap = self.cap() as usize;
+        let blank = vec![0u8; cap - HEAD];
+        self.buf[HEAD..].copy_from_slice(&blank);
+
+        put_u32(&mut self.buf, SIZE_OFFSET, entries.len() as u32);
+        put_u32(&mut self.buf, PLEN_OFFSET, prefix.len() as u32);
+        put_slice(&mut self.buf, HEAD, prefix);
+
+        let total: usize = entries
+            .iter()
+            .map(|(key, val, _)| stored_len(prefix, key) + val.len())
+            .sum();
+        let mut offset = cap - total;
+        let mut pos = HEAD + prefix.len();
+        for (key, val, page) in entries.iter() {
+            let (stored, flag) = if key.starts_with(prefix) {
+                (&key[prefix.len()..], 0)
+            } else {
+                (&key[..], FULL_KEY)
+            };
+            put_u32(&mut self.buf, pos, offset as u32);
+            put_u32(&mut self.buf, pos + 4, stored.len() as u32 | flag);
+            put_u32(&mut self.buf, pos + 8, val.len() as u32);
+            put_u32(&mut self.buf, pos + 12, *page);
+            pos += SLOT;
+
+            put_slice(&mut self.buf, offset, stored);
+            put_slice(&mut self.buf, offset + stored.len(), val);
+            offset += stored.len() + val.len();
+        }
+
+        let keys = entries
+            .into_iter()
+            .map(|(key, _, _)| key)
+            .collect::<Vec<_>>();
+        self.keys = OnceCell::from(keys);
+    }
+}
+
+impl<B: AsRef<[u8]>> View for Prefixed<B> {
+    fn id(&self) -> u32 {
+        get_u32(self.buf.as_ref(), ID_OFFSET)
+    }
+
+    fn cap(&self) -> u32 {
+        get_u32(self.buf.as_ref(), CAP_OFFSET)
+    }
+
+    fn size(&self) -> u32 {
+        get_u32(self.buf.as_ref(), SIZE_OFFSET)
+    }
+
+    fn slot(&self, idx: u32) -> Option<Slot> {
+        if idx >= self.size() {
+            return None;
+        }
+        let mut slot = self.raw_slot(idx);
+        slot.klen &= !FULL_KEY;
+        Some(slot)
+    }
+
+    fn min(&self) -> &[u8] {
+        self.key(0)
+    }
+
+    fn max(&self) -> &[u8] {
+        self.key(self.size() - 1)
+    }
+
+    fn key(&self, idx: u32) -> &[u8] {
+        self.keys()
+            .get(idx as usize)
+            .map(|key| key.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn val(&self, idx: u32) -> &[u8] {
+        self.slot(idx)
+            .map(|slot| {
+                let at = slot.offset as usize + slot.klen as usize;
+                let to = at + slot.vlen as usize;
+                &self.buf.as_ref()[at..to]
+            })
+            .unwrap_or_default()
+    }
+
+    fn free(&self) -> u32 {
+        let size = self.size();
+        let lo = HEAD + self.prefix().len() + size as usize * SLOT;
+        let used: usize = (0..size)
+            .filter_map(|idx| self.slot(idx))
+            .map(|slot| (slot.klen + slot.vlen) as usize)
+            .sum();
+        (self.cap() as usize - lo - used) as u32
+    }
+
+    fn full(&self) -> u8 {
+        let len = (self.cap() - HEAD as u32) as u64;
+        ((len - self.free() as u64) * 100 / len) as u8
+    }
+
+    fn find(&self, key: &[u8], cmp: &dyn Comparator) -> Option<u32> {
+        let n = self.size();
+        if n == 0 {
+            return None;
+        }
+
+        let k = bsearch(0, n - 1, |i| cmp.cmp(key, self.key(i)));
+        if cmp.cmp(self.key(k), key) == Ordering::Equal {
+            Some(k)
+        } else {
+            None
+        }
+    }
+
+    fn ceil(&self, key: &[u8], cmp: &dyn Comparator) -> Option<u32> {
+        let n = self.size();
+        if n == 0 {
+            return None;
+        }
+
+        let k = bsearch(0, n - 1, |i| cmp.cmp(key, self.key(i)));
+        if cmp.cmp(self.key(k), key) != Ordering::Less {
+            Some(k)
+        } else {
+            None
+        }
+    }
+
+    fn copy(&self) -> Vec<(Vec<u8>, Vec<u8>, u32)> {
+        (0..self.size())
+            .filter_map(|idx| self.slot(idx).map(|slot| (idx, slot)))
+            .map(|(idx, slot)| (self.key(idx).to_vec(), self.val(idx).to_vec(), slot.page))
+            .collect::<Vec<_>>()
+    }
+
+    fn check(&self, cmp: &dyn Comparator) -> Result<()> {
+        let cap = self.cap() as u64;
+        if cap != self.buf.as_ref().len() as u64 {
+            return Err(self.corrupt(format!("Capacity mismatch: {}", cap)));
+        }
+        let plen = get_u32(self.buf.as_ref(), PLEN_OFFSET) as u64;
+        if HEAD as u64 + plen > cap {
+            return Err(self.corrupt(format!("Prefix too long: {}", plen)));
+        }
+        let lo = HEAD as u64 + plen + self.size() as u64 * SLOT as u64;
+        if lo > cap {
+            return Err(self.corrupt(format!("Too many slots: {}", self.size())));
+        }
+
+        let mut used = 0u64;
+        for idx in 0..self.size() {
+            let slot = self.raw_slot(idx);
+            let len = (slot.klen & !FULL_KEY) as u64 + slot.vlen as u64;
+            if (slot.offset as u64) < lo || slot.offset as u64 + len > cap {
+                return Err(self.corrupt(format!("Slot out of bounds: {}", idx)));
+            }
+            if slot.page > 0 && slot.vlen != COUNT as u32 {
+                return Err(self.corrupt(format!("Reference without count: {}", idx)));
+            }
+            used += len;
+        }
+        if lo + used > cap {
+            return Err(self.corrupt("Slots overlap".to_string()));
+        }
+
+        for idx in 1..self.size() {
+            if cmp.cmp(self.key(idx - 1), self.key(idx)) != Ordering::Less {
+                return Err(self.corrupt(format!("Keys out of order: {}", idx)));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Page for Prefixed {
+    type Mapped<'a> = Prefixed<&'a [u8]>;
+
+    fn reserve(capacity: u32) -> Self {
+        let mut buf = BytesMut::with_capacity(capacity as usize);
+        buf.extend_from_slice(&vec![0u8; capacity as usize]);
+        Self {
+            buf,
+            keys: OnceCell::new(),
+        }
+    }
+
+    fn create(id: u32, cap: u32) -> Self {
+        let mut buf = BytesMut::with_capacity(cap as usize);
+        buf.put_u32(id);
+        buf.put_u32(cap);
+        buf.put_u32(0);
+        buf.put_u32(RESERVED);
+        buf.put_u32(0);
+        assert_eq!(buf.len(), HEAD);
+        buf.extend_from_slice(&vec![0u8; cap as usize - HEAD]);
+        Self {
+            buf,
+            keys: OnceCell::new(),
+        }
+    }
+
+    fn map(buf: &[u8]) -> Prefixed<&[u8]> {
+        Prefixed {
+            buf,
+            keys: OnceCell::new(),
+        }
+    }
+
+    fn fits(&self, len: u32) -> bool {
+        self.free() >= len + SLOT as u32
+    }
+
+    fn max_entry(cap: u32) -> u32 {
+        (cap.saturating_sub(HEAD as u32) / 2).saturating_sub(SLOT as u32)
+    }
+
+    fn put_val(&mut self, key: &[u8], val: &[u8], cmp: &dyn Comparator) -> Option<u32> {
+        self.put_entry(key, val, 0, cmp)
+    }
+
+    fn put_ref(&mut self, key: &[u8], page: u32, count: u64, cmp: &dyn Comparator) -> Option<u32> {
+        self.put_entry(key, &count.to_be_bytes(), page, cmp)
+    }
+
+    fn remove(&mut self, idx: u32) {
+        if idx >= self.size() {
+            return;
+        }
+
+        let size = self.size() as usize;
+        let lo = HEAD + self.prefix().len();
+        let slot = self.raw_slot(idx);
+        let at = slot.offset as usize;
+        let len = (slot.klen & !FULL_KEY) as usize + slot.vlen as usize;
+
+        // Payloads stored below the removed one are moved up to keep the payload area packed.
+        let end = self.payload_offset();
+        self.buf.copy_within(end..at, end + len);
+        self.buf[end..(end + len)].fill(0);
+        for i in 0..size {
+            let pos = lo + SLOT * i;
+            let offset = get_u32(&self.buf, pos) as usize;
+            if offset < at {
+                put_u32(&mut self.buf, pos, (offset + len) as u32);
+            }
+        }
+
+        let pos = lo + SLOT * idx as usize;
+        self.buf.copy_within((pos + SLOT)..(lo + SLOT * size), pos);
+        self.buf[(lo + SLOT * (size - 1))..(lo + SLOT * size)].fill(0);
+        put_u32(&mut self.buf, SIZE_OFFSET, size as u32 - 1);
+
+        if let Some(keys) = self.keys.get_mut() {
+            keys.remove(idx as usize);
+        }
+    }
+
+    fn clear(&mut self) {
+        let len = self.cap() as usize;
+        let mut tmp = BytesMut::with_capacity(len);
+        tmp.put_u32(self.id());
+        tmp.put_u32(self.cap());
+        tmp.put_u32(0);
+        tmp.put_u32(RESERVED);
+        tmp.put_u32(0);
+        self.buf[..HEAD].copy_from_slice(tmp.as_ref());
+        let blank = vec![0xFFu8; len - HEAD];
+        self.buf[HEAD..].copy_from_slice(&blank);
+        self.keys = OnceCell::from(vec![]);
+    }
+}
+
+const U32: usize = size_of::<u32>();
+const SLOT: usize = size_of::<Slot>();
+const HEAD: usize = 5 * U32; // page header: id, length, size, reserved, prefix length
+
+/// Number of bytes needed to store the key given the page prefix.
+fn stored_len(prefix: &[u8], key: &[u8]) -> usize {
+    if key.starts_with(prefix) {
+        key.len() - prefix.len()
+    } else {
+        key.len()
+    }
+}
+
+/// Total number of bytes the page would occupy when encoded with given prefix.
+fn encoded_len(prefix: &[u8], entries: &[(Vec<u8>, Vec<u8>, u32)]) -> usize {
+    HEAD + prefix.len()
+        + entries
+            .iter()
+            .map(|(key, val, _)| SLOT + stored_len(prefix, key) + val.len())
+            .sum::<usize>()
+}
+
+/// Pick the prefix giving the smallest page: either the current one (that guarantees no entry
+/// grows) or the longest prefix shared by all entries (with a custom comparator the keys are
+/// not necessarily in byte order, thus it is not just `lcp(min, max)`).
+fn best_prefix(current: &[u8], entries: &[(Vec<u8>, Vec<u8>, u32)]) -> Vec<u8> {
+    let common = match entries.first() {
+        Some((first, _, _)) => {
+            let len = entries
+                .iter()
+                .fold(first.len(), |len, (key, _, _)| lcp(&first[..len], key));
+            &first[..len]
+        }
+        None => &[],
+    };
+    if encoded_len(common, entries) <= encoded_len(current, entries) {
+        common.to_vec()
+    } else {
+        current.to_vec()
+    }
+}
+
+fn get_u32(buf: &[u8], pos: usize) -> u32 {
+    let mut src = [0u8; U32];
+    src.copy_from_slice(&buf[pos..(pos + U32)]);
+    u32::from_be_bytes(src)
+}
+
+fn put_u32(buf: &mut BytesMut, pos: usize, val: u32) {
+    let dst = &mut buf[pos..(pos + U32)];
+    dst.copy_from_slice(&val.to_be_bytes());
+}
+
+fn put_slice(buf: &mut BytesMut, pos: usize, src: &[u8]) {
+    let dst = &mut buf[pos..(pos + src.len())];
+    dst.copy_from_slice(src);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::order::Bytewise;
+    use crate::api::tree::Tree;
+    use crate::disk::block::Block;
+    use crate::disk::file::File;
+    use crate::disk::testing::{check_map, check_page, temp_path};
+    use rand::prelude::*;
+    use std::collections::HashSet;
+    use std::ops::Deref;
+
+    fn path_key(tenant: u64, object: u64) -> Vec<u8> {
+        format!("tenant/{:08}/bucket/default/object/{:016x}", tenant, object).into_bytes()
+    }
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(SLOT, 16);
+        assert_eq!(HEAD, 20);
+    }
+
+    #[test]
+    fn test_sorted() {
+        let mut rng = thread_rng();
+
+        let size = 32;
+        let len = size * size_of::<u64>() * 5;
+
+        let mut keys = (0..size)
+            .map(|_| rng.gen::<u64>().to_be_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        let mut page = Prefixed::create(42, len as u32);
+
+        for (i, key) in keys.iter().enumerate() {
+            if i % 2 == 0 {
+                page.put_ref(key, 42, 1, &Bytewise).unwrap();
+            } else {
+                page.put_val(key, b"undefined", &Bytewise).unwrap();
+            }
+        }
+
+        keys.sort();
+
+        let read = (0..size)
+            .map(|idx| page.key(idx as u32).to_vec())
+            .collect::<Vec<_>>();
+
+        assert_eq!(read, keys);
+    }
+
+    #[test]
+    fn test_find_ceil() {
+        let mut rng = thread_rng();
+
+        let keys = (0..64)
+            .map(|_| rng.gen::<u64>() % 1000 * 100 + 100)
+            .collect::<HashSet<_>>();
+
+        let mut page = Prefixed::create(42, 4096);
+        for key in keys.iter() {
+            page.put_val(&path_key(1, *key), &key.to_be_bytes(), &Bytewise)
+                .unwrap();
+        }
+
+        for key in keys.iter() {
+            let idx = page.find(&path_key(1, *key), &Bytewise).unwrap();
+            assert_eq!(page.key(idx), path_key(1, *key).as_slice());
+            assert_eq!(page.val(idx), key.to_be_bytes());
+
+            let idx = page.ceil(&path_key(1, *key - 1), &Bytewise).unwrap();
+            assert_eq!(page.key(idx), path_key(1, *key).as_slice());
+        }
+
+        let max = keys.iter().max().cloned().unwrap();
+        assert_eq!(page.find(&path_key(1, max + 1), &Bytewise), None);
+        assert_eq!(page.ceil(&path_key(1, max + 1), &Bytewise), None);
+        assert_eq!(page.ceil(b"a", &Bytewise), Some(0));
+        assert_eq!(page.ceil(b"z", &Bytewise), None);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let mut page = Prefixed::create(42, 256);
+        assert_eq!(page.prefix(), b"");
+
+        page.put_val(b"tenant/1/x", b"1", &Bytewise).unwrap();
+        assert_eq!(page.prefix(), b"tenant/1/x");
+        page.put_val(b"tenant/1/y", b"2", &Bytewise).unwrap();
+        assert_eq!(page.prefix(), b"tenant/1/");
+        page.put_ref(b"tenant/2/z", 7, 1, &Bytewise).unwrap();
+        assert_eq!(page.prefix(), b"tenant/");
+
+        // A key sharing nothing with the rest is stored in full, without re-encoding the others.
+        page.put_val(b"a", b"3", &Bytewise).unwrap();
+        assert_eq!(page.prefix(), b"tenant/");
+        assert_eq!(
+            page.slot(0),
+            Some(Slot::new(page.raw_slot(0).offset, 1, 1, 0))
+        );
+        assert_eq!(page.raw_slot(0).klen, 1 | FULL_KEY);
+
+        assert_eq!(
+            page.copy(),
+            vec![
+                (b"a".to_vec(), b"3".to_vec(), 0),
+                (b"tenant/1/x".to_vec(), b"1".to_vec(), 0),
+                (b"tenant/1/y".to_vec(), b"2".to_vec(), 0),
+                (b"tenant/2/z".to_vec(), 1u64.to_be_bytes().to_vec(), 7),
+            ]
+        );
+
+        // Removal is done in place: the prefix is kept and other payloads are only moved.
+        page.remove(0);
+        assert_eq!(page.prefix(), b"tenant/");
+        page.remove(2);
+        assert_eq!(page.prefix(), b"tenant/");
+        assert_eq!(page.find(b"tenant/1/y", &Bytewise), Some(1));
+
+        // A key sharing the prefix is added in place as well: other slots are not re-written.
+        let slot = page.raw_slot(0);
+        page.put_val(b"tenant/0", b"4", &Bytewise).unwrap();
+        assert_eq!(page.raw_slot(1), slot);
+        assert_eq!(page.raw_slot(0).offset, slot.offset - 2);
+        page.put_val(b"tenant/0", b"5", &Bytewise).unwrap();
+        assert_eq!(page.val(0), b"5");
+        page.check(&Bytewise).unwrap();
+        page.remove(0);
+
+        // Decoded keys survive a round-trip through raw page bytes.
+        let mut copy = Prefixed::reserve(256);
+        copy.as_mut().copy_from_slice(page.as_ref());
+        assert_eq!(copy.copy(), page.copy());
+
+        page.clear();
+        assert_eq!(page.size(), 0);
+        assert_eq!(page.prefix(), b"");
+        assert_eq!(page.free(), 256 - HEAD as u32);
+    }
+
+    #[test]
+    fn test_full() {
+        // Entries sharing a long prefix fill the page up to the last byte.
+        let prefix = [b'p'; 40];
+        let key = |i: u8| [&prefix[..], &[b'a' + i]].concat();
+        let n = 10;
+        let mut page = Prefixed::create(1, (HEAD + prefix.len() + n * (SLOT + 2)) as u32);
+        for i in 0..(n as u8) {
+            assert_eq!(page.put_val(&key(i), b"v", &Bytewise), Some(i as u32));
+        }
+        assert_eq!(page.prefix(), &prefix[..]);
+        assert_eq!(page.free(), 0);
+        assert_eq!(page.put_val(&key(n as u8), b"v", &Bytewise), None);
+        assert_eq!(page.put_val(b"q", b"v", &Bytewise), None);
+
+        // Replaced entry gives its room back.
+        assert_eq!(page.put_val(&key(0), b"w", &Bytewise), Some(0));
+        assert_eq!(page.val(0), b"w");
+        assert_eq!(page.free(), 0);
+        page.check(&Bytewise).unwrap();
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut block = Block::create(1, 4096);
+        let mut page = Prefixed::create(1, 4096);
+        for i in 0..32 {
+            let key = path_key(42, i);
+            block.put_val(&key, b"value", &Bytewise).unwrap();
+            page.put_val(&key, b"value", &Bytewise).unwrap();
+        }
+        assert_eq!(block.copy(), page.copy());
+        assert!(page.free() > block.free());
+        // The prefix is stored once instead of 32 times, at the cost of a wider page header.
+        let plen = page.prefix().len() as u32;
+        assert_eq!(page.free() - block.free(), 31 * plen - 4);
+    }
+
+    #[test]
+    fn test_file() {
+        let path = &temp_path("test_prefix_file");
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let data = (0..1000)
+            .map(|_| {
+                (
+                    path_key(rng.next_u64() % 4, rng.next_u64()),
+                    rng.next_u64().to_be_bytes().to_vec(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        {
+            let mut file: File<Prefixed> = File::make(path, 512).unwrap();
+            for (k, v) in data.iter() {
+                file.insert(k, v).unwrap();
+            }
+        }
+
+        let mut file: File<Prefixed> = File::open(path).unwrap();
+        for (k, v) in data.iter() {
+            assert_eq!(file.lookup(k).unwrap().unwrap().deref(), v.as_slice());
+        }
+
+        let mut sorted = data.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+        sorted.sort();
+        assert_eq!(file.min().unwrap().unwrap().deref(), sorted[0].as_slice());
+        let mut this = sorted[0].clone();
+        for next in sorted.iter().skip(1) {
+            let found = file.above(&this).unwrap().unwrap().to_vec();
+            assert_eq!(&found, next);
+            this = found;
+        }
+
+        for (k, _) in data.iter() {
+            file.remove(k).unwrap();
+            assert!(file.lookup(k).unwrap().is_none());
+        }
+        assert!(file.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_map() {
+        check_map::<Prefixed>();
+    }
+
+    #[test]
+    fn test_check() {
+        check_page::<Prefixed>(CAP_OFFSET, SIZE_OFFSET, |page| HEAD + page.prefix().len());
+    }
+}
@@ -0,0 +1,95 @@
+//! Helpers shared by the tests of pages and files.
+use crate::api::error::Error;
+use crate::api::order::Bytewise;
+use crate::api::page::{Page, Slot, View};
+use rand::prelude::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::fs;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+
+/// Path of a temporary file under `target`, a file left there by a previous run is removed.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    let path = Path::new("target").join(format!("{}.tmp", name));
+    if path.exists() {
+        fs::remove_file(&path).unwrap();
+    }
+    path
+}
+
+/// Random (but the same for each run) entries: 8-byte keys and values of given length.
+pub(crate) fn random_pairs(count: usize, val_len: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut rng = StdRng::seed_from_u64(42);
+    (0..count)
+        .map(|_| {
+            let mut val = vec![0u8; val_len];
+            rng.fill_bytes(&mut val);
+            (rng.next_u64().to_be_bytes().to_vec(), val)
+        })
+        .collect()
+}
+
+/// Consistency check (`Page::check`) of a page layout: valid pages pass, and a corrupt size,
+/// slot offset, order of slots or capacity is detected. Slots start at `slots(page)` bytes.
+pub(crate) fn check_page<P: Page>(cap_offset: usize, size_offset: usize, slots: fn(&P) -> usize) {
+    let slot_len = size_of::<Slot>();
+    let mut page = P::create(1, 256);
+    page.check(&Bytewise).unwrap();
+    for k in [b"key-a", b"key-b", b"key-c"] {
+        page.put_val(k, b"val", &Bytewise).unwrap();
+    }
+    page.check(&Bytewise).unwrap();
+    page.clear();
+    page.check(&Bytewise).unwrap();
+    for k in [b"key-a", b"key-b", b"key-c"] {
+        page.put_val(k, b"val", &Bytewise).unwrap();
+    }
+    let slot = slots(&page);
+    let copy = |page: &P| {
+        let mut copy = P::reserve(256);
+        copy.as_mut().copy_from_slice(page.as_ref());
+        copy
+    };
+
+    let mut corrupt = copy(&page);
+    corrupt.as_mut()[size_offset..(size_offset + 4)].copy_from_slice(&100u32.to_be_bytes());
+    assert!(corrupt.check(&Bytewise).is_err());
+
+    let mut corrupt = copy(&page);
+    corrupt.as_mut()[slot..(slot + 4)].copy_from_slice(&254u32.to_be_bytes());
+    assert!(corrupt.check(&Bytewise).is_err());
+
+    // Swap first two slots: keys are no longer ordered.
+    let mut corrupt = copy(&page);
+    let (a, b) = page.as_ref()[slot..(slot + 2 * slot_len)].split_at(slot_len);
+    let swapped = [b, a].concat();
+    corrupt.as_mut()[slot..(slot + 2 * slot_len)].copy_from_slice(&swapped);
+    assert!(matches!(
+        corrupt.check(&Bytewise),
+        Err(Error::CorruptPage { id: 1, .. })
+    ));
+
+    // Zeroed (never written) page is not a valid one.
+    assert!(P::reserve(256).check(&Bytewise).is_err());
+    let mut corrupt = P::reserve(256);
+    corrupt.as_mut()[cap_offset..(cap_offset + 4)].copy_from_slice(&512u32.to_be_bytes());
+    assert!(corrupt.check(&Bytewise).is_err());
+}
+
+/// Read-only page over the buffer of a page (`Page::map`) reads the same entries, and borrows
+/// values from the buffer.
+pub(crate) fn check_map<P: Page>() {
+    let mut page = P::create(1, 512);
+    for (k, v) in random_pairs(8, 8) {
+        page.put_val(&k, &v, &Bytewise).unwrap();
+    }
+    let mapped = P::map(page.as_ref());
+    mapped.check(&Bytewise).unwrap();
+    assert_eq!(mapped.id(), 1);
+    assert_eq!(mapped.copy(), page.copy());
+    for (idx, (k, _, _)) in page.copy().iter().enumerate() {
+        assert_eq!(mapped.find(k, &Bytewise), Some(idx as u32));
+        let val = mapped.val(idx as u32);
+        assert!(page.as_ref().as_ptr_range().contains(&val.as_ptr()));
+    }
+}
@@ -1,16 +1,15 @@
-use crate::api::tree::Tree;
-use crate::disk::block::Block;
-use crate::disk::file::File;
-use crate::util::hex::hex;
 use log::{debug, error, info};
 use rand::prelude::StdRng;
 use rand::{RngCore, SeedableRng};
+use std::env;
+use std::fs;
 use std::path::Path;
 use std::time::SystemTime;
-
-pub(crate) mod api;
-pub(crate) mod disk;
-pub(crate) mod util;
+use yakvdb::api::tree::Tree;
+use yakvdb::disk::block::Block;
+use yakvdb::disk::codec::Codec;
+use yakvdb::disk::file::{File, Options};
+use yakvdb::util::hex::hex;
 
 fn setup_logger() -> Result<(), fern::InitError> {
     fern::Dispatch::new()
@@ -33,6 +32,181 @@ fn setup_logger() -> Result<(), fern::InitError> {
 fn main() {
     setup_logger().expect("logger");
 
+    match env::args().nth(1).as_deref() {
+        Some("codec") => codec(),
+        Some("mmap") => mmap(),
+        Some("stats") => stats(env::args().nth(2).as_deref()),
+        Some("export") => export(env::args().nth(2).as_deref(), env::args().nth(3).as_deref()),
+        _ => demo(),
+    }
+}
+
+/// Print statistics of an existing database (opened read-only), walking all its pages.
+fn stats(path: Option<&str>) {
+    let path = Path::new(path.unwrap_or("target/main_1M.tmp"));
+    let opts = Options {
+        read_only: true,
+        ..Options::default()
+    };
+    let file: File<Block> = match File::open_with(path, opts) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("file={:?}: {}", path, e);
+            return;
+        }
+    };
+    match file.stats(true) {
+        Ok(stats) => info!("file={:?} {}", path, stats),
+        Err(e) => error!("file={:?}: {}", path, e),
+    }
+}
+
+/// Print structure of an existing database (opened read-only) as Graphviz DOT or JSON.
+fn export(format: Option<&str>, path: Option<&str>) {
+    let path = Path::new(path.unwrap_or("target/main_1M.tmp"));
+    let opts = Options {
+        read_only: true,
+        ..Options::default()
+    };
+    let file: File<Block> = match File::open_with(path, opts) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("file={:?}: {}", path, e);
+            return;
+        }
+    };
+    match (format, file.export()) {
+        (Some("json"), Ok(export)) => println!("{}", export.to_json()),
+        (_, Ok(export)) => print!("{}", export.to_dot()),
+        (_, Err(e)) => error!("file={:?}: {}", path, e),
+    }
+}
+
+/// Compare space and throughput of value compression modes on verbose JSON values.
+fn codec() {
+    let count = 100 * 1000;
+    let size: u32 = 4096;
+    let mut rng = StdRng::seed_from_u64(42);
+    let data = (0..count)
+        .map(|_| {
+            let id = rng.next_u64();
+            let events = (0..8)
+                .map(|seq| {
+                    format!(
+                        r#"{{"event": "login", "user": "user-{:016x}", "seq": {}, "ok": true}}"#,
+                        id, seq
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let json = format!(r#"{{"id": {}, "events": [{}]}}"#, id, events);
+            (id.to_be_bytes().to_vec(), json.into_bytes())
+        })
+        .collect::<Vec<_>>();
+    let bytes = data.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>();
+    info!("codec: count={} bytes={} page={}", count, bytes, size);
+
+    for codec in [Codec::None, Codec::Lz] {
+        let path = format!("target/codec_{:?}.tmp", codec);
+        let path = Path::new(&path);
+        if path.exists() {
+            fs::remove_file(path).unwrap();
+        }
+        let opts = Options {
+            codec,
+            ..Options::default()
+        };
+        let mut file: File<Block> = File::make_with(path, size, opts).unwrap();
+
+        let now = SystemTime::now();
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+        let insert = now.elapsed().unwrap_or_default().as_millis().max(1);
+
+        let now = SystemTime::now();
+        for (k, v) in data.iter() {
+            let found = file.lookup(k).unwrap().map(|r| r.to_vec());
+            if found.as_ref() != Some(v) {
+                error!("codec={:?}: key='{}' value mismatch", codec, hex(k));
+            }
+        }
+        let lookup = now.elapsed().unwrap_or_default().as_millis().max(1);
+
+        info!("codec={:?}: {}", codec, file.stats(true).unwrap());
+        let len = fs::metadata(path).unwrap().len();
+        info!(
+            "codec={:?}: file={} bytes (ratio={:.2}) insert={} ms (rate={} op/s) lookup={} ms (rate={} op/s)",
+            codec,
+            len,
+            bytes as f64 / len as f64,
+            insert,
+            count as u128 * 1000 / insert,
+            lookup,
+            count as u128 * 1000 / lookup
+        );
+    }
+}
+
+/// Compare lookup throughput of buffered (seek + read) and memory-mapped page access.
+fn mmap() {
+    let count = 1000 * 1000;
+    let size: u32 = 4096;
+    let mut rng = StdRng::seed_from_u64(42);
+    let data = (0..count)
+        .map(|_| {
+            (
+                rng.next_u64().to_be_bytes().to_vec(),
+                rng.next_u64().to_be_bytes().repeat(4),
+            )
+        })
+        .collect::<Vec<_>>();
+    info!("mmap: count={} page={}", count, size);
+
+    let path = Path::new("target/mmap_1M.tmp");
+    if path.exists() {
+        fs::remove_file(path).unwrap();
+    }
+    {
+        let mut file: File<Block> = File::make(path, size).unwrap();
+        for (k, v) in data.iter() {
+            file.insert(k, v).unwrap();
+        }
+    }
+
+    for mmap in [false, true] {
+        let opts = Options {
+            mmap,
+            read_only: true,
+            ..Options::default()
+        };
+        let file: File<Block> = File::open_with(path, opts).unwrap();
+        // First pass populates the page cache (cold), second one is served from it (warm).
+        // Memory-mapped pages are read in place instead (neither copied nor cached).
+        for pass in ["cold", "warm"] {
+            let now = SystemTime::now();
+            let mut bytes = 0usize;
+            for (k, _) in data.iter() {
+                match file.lookup(k).unwrap() {
+                    Some(r) => bytes += r.len(),
+                    None => error!("mmap={}: key='{}' not found", mmap, hex(k)),
+                }
+            }
+            let millis = now.elapsed().unwrap_or_default().as_millis().max(1);
+            info!(
+                "mmap={} {}: lookup={} ms (rate={} op/s) bytes={}",
+                mmap,
+                pass,
+                millis,
+                count as u128 * 1000 / millis,
+                bytes
+            );
+            info!("mmap={} {}: {}", mmap, pass, file.stats(false).unwrap());
+        }
+    }
+}
+
+fn demo() {
     let path = Path::new("target/main_1M.tmp");
     let size: u32 = 4096; // TODO handle keys/values larger than (half-) page size
 
@@ -45,7 +219,6 @@ fn main() {
     let mut rng = StdRng::seed_from_u64(42);
     let count = 1000 * 1000;
     let data = (0..count)
-        .into_iter()
         .map(|_| {
             (
                 rng.next_u64().to_be_bytes().to_vec(),
@@ -66,6 +239,7 @@ fn main() {
         millis,
         count as u128 * 1000 / millis
     );
+    info!("stats: {}", file.stats(true).unwrap());
 
     now = SystemTime::now();
     let mut found = Vec::with_capacity(data.len());
@@ -185,7 +359,7 @@ fn main() {
         count as u128 * 1000 / millis
     );
 
-    if !file.is_empty() {
+    if !file.is_empty().unwrap() {
         error!("non-empty file");
     }
 }
@@ -1,11 +1,12 @@
 use std::cmp::Ordering;
 use std::fmt::Debug;
 
-pub(crate) fn bsearch<T: Ord, I: UInt, F: Fn(I) -> T>(key: T, mut lo: I, mut hi: I, f: F) -> I {
+/// Binary search within `lo..=hi`, where `f(mid)` is the order of the searched key relative
+/// to the one at `mid`. Returns index of the match, or the closest index otherwise.
+pub(crate) fn bsearch<I: UInt, F: Fn(I) -> Ordering>(mut lo: I, mut hi: I, f: F) -> I {
     while lo < hi {
         let mid = lo + (hi - lo) / I::from(2);
-        let mid_key = f(mid);
-        match Ord::cmp(&key, &mid_key) {
+        match f(mid) {
             Ordering::Less => {
                 hi = mid;
             }
@@ -0,0 +1,373 @@
+// Order-preserving key encoding: encoded values compare bytewise in the same order as the values
+// themselves, thus composite keys (tuples) can be stored in a database with the default order.
+//
+// Integers are big-endian fixed-width (signed ones with the sign bit flipped), floats are
+// big-endian IEEE 754 with the sign bit flipped for positive and all bits flipped for negative
+// values (the order of `total_cmp`). Byte strings (and strings) are self-delimiting: each zero
+// byte is escaped as `00 FF`, and the value is terminated with `00 01`, so a prefix of a string
+// goes before the string itself, and a tuple field never runs into the next one.
+
+use std::convert::TryInto;
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+/// Value with order-preserving binary representation.
+pub trait KeyPart: Sized {
+    /// Append encoded value to `dst`.
+    fn encode_to(&self, dst: &mut Vec<u8>);
+
+    /// Decode a value from the start of `src` and advance `src` past it.
+    /// Returns `None` if `src` does not start with a valid encoding.
+    fn decode_from(src: &mut &[u8]) -> Option<Self>;
+}
+
+/// Encode a value (e.g. a tuple of key fields) into a key.
+pub fn encode<T: KeyPart>(val: &T) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(32);
+    val.encode_to(&mut dst);
+    dst
+}
+
+/// Decode a key into a value, the whole key must be consumed.
+pub fn decode<T: KeyPart>(src: &[u8]) -> Option<T> {
+    let mut src = src;
+    let val = T::decode_from(&mut src)?;
+    if src.is_empty() {
+        Some(val)
+    } else {
+        None
+    }
+}
+
+fn take<'a>(src: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if src.len() < len {
+        return None;
+    }
+    let (head, tail) = src.split_at(len);
+    *src = tail;
+    Some(head)
+}
+
+macro_rules! unsigned {
+    ($($t:ty),*) => {$(
+        impl KeyPart for $t {
+            fn encode_to(&self, dst: &mut Vec<u8>) {
+                dst.extend_from_slice(&self.to_be_bytes());
+            }
+
+            fn decode_from(src: &mut &[u8]) -> Option<Self> {
+                let bytes = take(src, std::mem::size_of::<$t>())?;
+                Some(<$t>::from_be_bytes(bytes.try_into().ok()?))
+            }
+        }
+    )*};
+}
+
+macro_rules! signed {
+    ($($t:ty => $u:ty),*) => {$(
+        impl KeyPart for $t {
+            fn encode_to(&self, dst: &mut Vec<u8>) {
+                let flipped = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                dst.extend_from_slice(&flipped.to_be_bytes());
+            }
+
+            fn decode_from(src: &mut &[u8]) -> Option<Self> {
+                let flipped = <$u>::decode_from(src)?;
+                Some((flipped ^ (1 << (<$u>::BITS - 1))) as $t)
+            }
+        }
+    )*};
+}
+
+macro_rules! float {
+    ($($t:ty => $u:ty),*) => {$(
+        impl KeyPart for $t {
+            fn encode_to(&self, dst: &mut Vec<u8>) {
+                let bits = self.to_bits();
+                let sign = 1 << (<$u>::BITS - 1);
+                let flipped = if bits & sign > 0 { !bits } else { bits ^ sign };
+                dst.extend_from_slice(&flipped.to_be_bytes());
+            }
+
+            fn decode_from(src: &mut &[u8]) -> Option<Self> {
+                let flipped = <$u>::decode_from(src)?;
+                let sign = 1 << (<$u>::BITS - 1);
+                let bits = if flipped & sign > 0 { flipped ^ sign } else { !flipped };
+                Some(<$t>::from_bits(bits))
+            }
+        }
+    )*};
+}
+
+unsigned!(u8, u16, u32, u64, u128);
+signed!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+float!(f32 => u32, f64 => u64);
+
+impl KeyPart for bool {
+    fn encode_to(&self, dst: &mut Vec<u8>) {
+        dst.push(*self as u8);
+    }
+
+    fn decode_from(src: &mut &[u8]) -> Option<Self> {
+        match take(src, 1)?[0] {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+}
+
+fn encode_bytes(src: &[u8], dst: &mut Vec<u8>) {
+    dst.reserve(src.len() + 2);
+    for b in src.iter().cloned() {
+        dst.push(b);
+        if b == ESCAPE {
+            dst.push(ESCAPED_ZERO);
+        }
+    }
+    dst.push(ESCAPE);
+    dst.push(TERMINATOR);
+}
+
+impl KeyPart for Vec<u8> {
+    fn encode_to(&self, dst: &mut Vec<u8>) {
+        encode_bytes(self, dst)
+    }
+
+    fn decode_from(src: &mut &[u8]) -> Option<Self> {
+        let mut val = Vec::with_capacity(src.len());
+        let mut pos = 0;
+        loop {
+            match *src.get(pos)? {
+                ESCAPE => {
+                    match *src.get(pos + 1)? {
+                        ESCAPED_ZERO => val.push(ESCAPE),
+                        TERMINATOR => break,
+                        _ => return None,
+                    }
+                    pos += 2;
+                }
+                b => {
+                    val.push(b);
+                    pos += 1;
+                }
+            }
+        }
+        *src = &src[(pos + 2)..];
+        Some(val)
+    }
+}
+
+impl KeyPart for String {
+    fn encode_to(&self, dst: &mut Vec<u8>) {
+        // UTF-8 byte order is the same as the order of code points (that `str` comparison uses).
+        encode_bytes(self.as_bytes(), dst)
+    }
+
+    fn decode_from(src: &mut &[u8]) -> Option<Self> {
+        String::from_utf8(Vec::<u8>::decode_from(src)?).ok()
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident),+) => {
+        impl<$($name: KeyPart),+> KeyPart for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, dst: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(dst);)+
+            }
+
+            fn decode_from(src: &mut &[u8]) -> Option<Self> {
+                Some(($($name::decode_from(src)?,)+))
+            }
+        }
+    };
+}
+
+tuple!(A);
+tuple!(A, B);
+tuple!(A, B, C);
+tuple!(A, B, C, D);
+tuple!(A, B, C, D, E);
+tuple!(A, B, C, D, E, F);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::tree::Tree;
+    use crate::disk::block::Block;
+    use crate::disk::file::{File, Options};
+    use crate::disk::mem::Mem;
+    use rand::prelude::*;
+    use std::cmp::Ordering;
+    use std::fmt::Debug;
+
+    /// Check that encoding preserves the order, and decoding restores the values.
+    fn check<T: KeyPart + Debug + PartialEq>(values: &[T], cmp: impl Fn(&T, &T) -> Ordering) {
+        let keys = values.iter().map(encode).collect::<Vec<_>>();
+        for (val, key) in values.iter().zip(keys.iter()) {
+            assert_eq!(decode::<T>(key).as_ref(), Some(val));
+        }
+        for (i, j) in (0..values.len()).zip((0..values.len()).rev()) {
+            let (a, b) = (&values[i], &values[j]);
+            assert_eq!(keys[i].cmp(&keys[j]), cmp(a, b), "a={:?} b={:?}", a, b);
+        }
+        for pair in values.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            assert_eq!(encode(a).cmp(&encode(b)), cmp(a, b), "a={:?} b={:?}", a, b);
+        }
+    }
+
+    fn word(rng: &mut StdRng) -> Vec<u8> {
+        let len = rng.gen_range(0..6);
+        (0..len)
+            .map(|_| *[0u8, 1, 0xFF, b'a', b'b'].choose(rng).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut u = (0..1000)
+            .map(|_| rng.gen::<u64>() >> rng.gen_range(0..64))
+            .collect::<Vec<_>>();
+        u.extend([0, 1, u64::MAX]);
+        check(&u, Ord::cmp);
+
+        let mut i = (0..1000)
+            .map(|_| rng.gen::<i64>() >> rng.gen_range(0..64))
+            .collect::<Vec<_>>();
+        i.extend([i64::MIN, -1, 0, 1, i64::MAX]);
+        check(&i, Ord::cmp);
+
+        let small = (i8::MIN..=i8::MAX).collect::<Vec<_>>();
+        check(&small, Ord::cmp);
+        let wide = (0..1000)
+            .map(|_| rng.gen::<i128>() >> rng.gen_range(0..128))
+            .collect::<Vec<_>>();
+        check(&wide, Ord::cmp);
+    }
+
+    #[test]
+    fn test_floats() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut f = (0..1000)
+            .map(|_| (rng.gen::<f64>() - 0.5) * 10f64.powi(rng.gen_range(-300..300)))
+            .collect::<Vec<_>>();
+        f.extend([
+            f64::NEG_INFINITY,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            f64::INFINITY,
+        ]);
+        let bits = |x: &Vec<f64>| x.iter().map(|x| x.to_bits()).collect::<Vec<_>>();
+        let keys = f.iter().map(encode).collect::<Vec<_>>();
+        for (i, j) in (0..f.len()).zip((0..f.len()).rev()) {
+            assert_eq!(keys[i].cmp(&keys[j]), f[i].total_cmp(&f[j]));
+        }
+        let decoded = keys
+            .iter()
+            .map(|k| decode::<f64>(k).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(bits(&decoded), bits(&f));
+        assert!(decode::<f64>(&encode(&f64::NAN)).unwrap().is_nan());
+        assert!(encode(&f64::INFINITY) < encode(&f64::NAN));
+        assert!(encode(&-1.5f32) < encode(&-0.0f32));
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut bytes = (0..1000).map(|_| word(&mut rng)).collect::<Vec<_>>();
+        bytes.extend([vec![], vec![0], vec![0, 0], vec![0, 1], vec![0xFF]]);
+        check(&bytes, Ord::cmp);
+
+        let strings = ["", "a", "a\0", "ab", "b", "é", "\u{10FFFF}"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        check(&strings, Ord::cmp);
+        assert!(decode::<String>(&encode(&vec![0xC0u8, 0x00])).is_none());
+    }
+
+    #[test]
+    fn test_tuples() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let values = (0..2000)
+            .map(|_| {
+                (
+                    rng.gen_range(0..4u64),
+                    rng.gen_range(-3..3i64),
+                    String::from_utf8_lossy(&word(&mut rng)).into_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        check(&values, Ord::cmp);
+
+        let values = (0..1000)
+            .map(|_| (word(&mut rng), rng.gen::<bool>(), rng.gen_range(0..3u8)))
+            .collect::<Vec<_>>();
+        check(&values, Ord::cmp);
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(decode::<u32>(&[1, 2, 3]).is_none());
+        assert!(decode::<u32>(&[1, 2, 3, 4, 5]).is_none());
+        assert!(decode::<bool>(&[2]).is_none());
+        assert!(decode::<Vec<u8>>(b"abc").is_none());
+        assert!(decode::<Vec<u8>>(&[b'a', 0, 2]).is_none());
+        assert!(decode::<Vec<u8>>(&[b'a', 0]).is_none());
+
+        let key = encode(&(7u64, String::from("x")));
+        for len in 0..key.len() {
+            assert!(decode::<(u64, String)>(&key[..len]).is_none());
+        }
+        assert_eq!(decode(&key), Some((7u64, String::from("x"))));
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut values = (0..3000)
+            .map(|_| {
+                (
+                    rng.gen_range(0..8u64),
+                    rng.gen::<i64>() >> rng.gen_range(0..64),
+                    String::from_utf8_lossy(&word(&mut rng)).into_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut file: File<Block, Mem> =
+            File::make_in(Mem::new(), 256, Options::default()).unwrap();
+        for val in values.iter() {
+            file.insert(&encode(val), b"").unwrap();
+        }
+        values.sort();
+        values.dedup();
+
+        let mut found = Vec::with_capacity(values.len());
+        let mut key = file.min().unwrap().map(|r| r.to_vec());
+        while let Some(this) = key {
+            found.push(decode::<(u64, i64, String)>(&this).unwrap());
+            key = file.above(&this).unwrap().map(|r| r.to_vec());
+        }
+        assert_eq!(found, values);
+
+        // All entries of a tenant form a contiguous range of keys.
+        let tenant = |id: u64| encode(&(id,));
+        let lo = tenant(3);
+        let hi = tenant(4);
+        let expected = values.iter().filter(|(id, _, _)| *id == 3).count() as u64;
+        let bounds = (
+            std::ops::Bound::Included(lo.as_slice()),
+            std::ops::Bound::Excluded(hi.as_slice()),
+        );
+        assert_eq!(file.count_range(bounds).unwrap(), expected);
+    }
+}
@@ -1,5 +1,5 @@
-pub(crate) fn hex(src: &[u8]) -> String {
-    src.into_iter()
+pub fn hex(src: &[u8]) -> String {
+    src.iter()
         .cloned()
         .map(|x| format!("{:02x}", x))
         .collect::<Vec<_>>()
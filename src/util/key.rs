@@ -0,0 +1,82 @@
+use crate::api::order::Comparator;
+use std::cmp::Ordering;
+
+/// Length of the longest common prefix of two byte strings.
+pub(crate) fn lcp(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count()
+}
+
+/// Find the shortest key `s` such that `lo <= s < hi` in given order (requires `lo < hi`).
+/// Used as a separator between two adjacent pages: all keys of the lower page are `<= s`,
+/// and all keys of the upper page are `> s`. Candidates are derived in byte order, with other
+/// comparators a candidate not between `lo` and `hi` is skipped (`lo` itself always fits).
+pub(crate) fn separator(lo: &[u8], hi: &[u8], cmp: &dyn Comparator) -> Vec<u8> {
+    let n = lcp(lo, hi);
+    for len in n..lo.len().saturating_sub(1) {
+        if lo[len] < 0xFF {
+            let mut sep = lo[..=len].to_vec();
+            sep[len] += 1;
+            if cmp.cmp(lo, &sep) != Ordering::Greater && cmp.cmp(&sep, hi) == Ordering::Less {
+                return sep;
+            }
+        }
+    }
+    lo.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::order::Bytewise;
+    use rand::prelude::*;
+
+    #[test]
+    fn test_lcp() {
+        assert_eq!(lcp(b"", b""), 0);
+        assert_eq!(lcp(b"abc", b""), 0);
+        assert_eq!(lcp(b"abc", b"abd"), 2);
+        assert_eq!(lcp(b"abc", b"abcd"), 3);
+    }
+
+    #[test]
+    fn test_separator() {
+        assert_eq!(separator(b"abc", b"abd", &Bytewise), b"abc");
+        assert_eq!(separator(b"abc", b"abcd", &Bytewise), b"abc");
+        assert_eq!(separator(b"abcd", b"abd", &Bytewise), b"abcd");
+        assert_eq!(separator(b"abcd", b"abe", &Bytewise), b"abd");
+        assert_eq!(separator(b"abcd", b"abdz", &Bytewise), b"abd");
+        assert_eq!(separator(b"ab\xFFxy", b"ac", &Bytewise), b"ab\xFFy");
+        assert_eq!(
+            separator(
+                b"tenant/0001/object/zzz",
+                b"tenant/0002/object/aaa",
+                &Bytewise
+            ),
+            b"tenant/0002"
+        );
+    }
+
+    #[test]
+    fn test_separator_random() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..10000 {
+            let mut a = vec![0u8; rng.gen_range(1..8)];
+            let mut b = vec![0u8; rng.gen_range(1..8)];
+            a.iter_mut().for_each(|x| *x = rng.gen_range(0xFD..=0xFF));
+            b.iter_mut().for_each(|x| *x = rng.gen_range(0xFD..=0xFF));
+            if a == b {
+                continue;
+            }
+            let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+            let sep = separator(&lo, &hi, &Bytewise);
+            assert!(
+                lo <= sep && sep < hi,
+                "lo={:?} sep={:?} hi={:?}",
+                lo,
+                sep,
+                hi
+            );
+            assert!(sep.len() <= lo.len());
+        }
+    }
+}
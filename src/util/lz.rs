@@ -0,0 +1,178 @@
+// Minimal LZ77 codec with LZ4-like block layout. Compressed data is a sequence of:
+// token (high nibble: literals length, low nibble: match length - MIN_MATCH),
+// optional literals length extension (bytes of 255 terminated by a smaller byte), literals,
+// match offset (u16 LE), optional match length extension. The last sequence has literals only.
+
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 12;
+const MAX_OFFSET: usize = u16::MAX as usize;
+
+pub(crate) fn compress(src: &[u8]) -> Vec<u8> {
+    let mut dst = Vec::with_capacity(src.len() / 2 + 16);
+    let mut table = vec![0usize; 1 << HASH_BITS]; // position + 1, zero means empty
+    let mut anchor = 0;
+    let mut pos = 0;
+
+    while pos + MIN_MATCH <= src.len() {
+        let seq = get_u32(src, pos);
+        let hash = (seq.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[hash];
+        table[hash] = pos + 1;
+
+        if candidate > 0 {
+            let at = candidate - 1;
+            if pos - at <= MAX_OFFSET && get_u32(src, at) == seq {
+                let mut len = MIN_MATCH;
+                while pos + len < src.len() && src[at + len] == src[pos + len] {
+                    len += 1;
+                }
+                put_sequence(&mut dst, &src[anchor..pos], Some((pos - at, len)));
+                pos += len;
+                anchor = pos;
+                continue;
+            }
+        }
+        pos += 1;
+    }
+
+    put_sequence(&mut dst, &src[anchor..], None);
+    dst
+}
+
+/// Decompress the data produced by `compress`, returns `None` if input is malformed.
+pub(crate) fn decompress(src: &[u8]) -> Option<Vec<u8>> {
+    let mut dst = Vec::with_capacity(src.len() * 2);
+    let mut pos = 0;
+    while pos < src.len() {
+        let token = src[pos];
+        pos += 1;
+
+        let lits = get_len(src, &mut pos, (token >> 4) as usize)?;
+        dst.extend_from_slice(src.get(pos..(pos + lits))?);
+        pos += lits;
+        if pos == src.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes([*src.get(pos)?, *src.get(pos + 1)?]) as usize;
+        pos += 2;
+        if offset == 0 || offset > dst.len() {
+            return None;
+        }
+        let len = get_len(src, &mut pos, (token & 0x0F) as usize)? + MIN_MATCH;
+        // Byte-by-byte copy: the match is allowed to overlap with the bytes being produced.
+        for _ in 0..len {
+            dst.push(dst[dst.len() - offset]);
+        }
+    }
+    Some(dst)
+}
+
+fn get_u32(src: &[u8], pos: usize) -> u32 {
+    let mut buf = [0u8; 4];
+    buf.copy_from_slice(&src[pos..(pos + 4)]);
+    u32::from_le_bytes(buf)
+}
+
+fn put_sequence(dst: &mut Vec<u8>, lits: &[u8], the_match: Option<(usize, usize)>) {
+    let lits_nibble = lits.len().min(15);
+    let match_nibble = the_match
+        .map(|(_, len)| (len - MIN_MATCH).min(15))
+        .unwrap_or_default();
+    dst.push(((lits_nibble << 4) | match_nibble) as u8);
+    put_len(dst, lits.len(), lits_nibble);
+    dst.extend_from_slice(lits);
+    if let Some((offset, len)) = the_match {
+        dst.extend_from_slice(&(offset as u16).to_le_bytes());
+        put_len(dst, len - MIN_MATCH, match_nibble);
+    }
+}
+
+fn put_len(dst: &mut Vec<u8>, len: usize, nibble: usize) {
+    if nibble < 15 {
+        return;
+    }
+    let mut rem = len - 15;
+    while rem >= 255 {
+        dst.push(255);
+        rem -= 255;
+    }
+    dst.push(rem as u8);
+}
+
+fn get_len(src: &[u8], pos: &mut usize, nibble: usize) -> Option<usize> {
+    let mut len = nibble;
+    if nibble == 15 {
+        loop {
+            let byte = *src.get(*pos)?;
+            *pos += 1;
+            len += byte as usize;
+            if byte < 255 {
+                break;
+            }
+        }
+    }
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+
+    fn check(src: &[u8]) -> usize {
+        let packed = compress(src);
+        assert_eq!(decompress(&packed).unwrap(), src);
+        packed.len()
+    }
+
+    #[test]
+    fn test_small() {
+        assert_eq!(check(b""), 1);
+        assert_eq!(check(b"a"), 2);
+        assert_eq!(check(b"abcd"), 5);
+        assert!(check(&[b'x'; 1000]) < 20);
+        assert!(check(b"abcabcabcabcabcabcabcabcabcabc") < 16);
+    }
+
+    #[test]
+    fn test_json() {
+        let json = (0..100)
+            .map(|i| {
+                format!(
+                    r#"{{"id": {}, "name": "user-{}", "email": "user-{}@example.com", "active": true}}"#,
+                    i, i, i
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let len = check(json.as_bytes());
+        assert!(len * 4 < json.len(), "len={} json={}", len, json.len());
+    }
+
+    #[test]
+    fn test_random() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..1000 {
+            let len = rng.gen_range(0..2000);
+            let alphabet = rng.gen_range(1..=255u8);
+            let src = (0..len)
+                .map(|_| rng.gen_range(0..alphabet))
+                .collect::<Vec<_>>();
+            let packed = check(&src);
+            assert!(packed <= src.len() + src.len() / 255 + 16);
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let packed = compress(b"abcabcabcabcabcabcabcabc");
+        for len in 1..packed.len() {
+            // Truncated input must never panic (though can be decoded into shorter data).
+            let _ = decompress(&packed[..len]);
+        }
+        assert_eq!(decompress(&[0x01, b'a']), None);
+        assert_eq!(decompress(&[0x10, b'a', 0x05, 0x00]), None);
+        assert_eq!(decompress(&[0xF0]), None);
+    }
+}
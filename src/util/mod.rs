@@ -1,2 +1,5 @@
 pub(crate) mod bsearch;
-pub(crate) mod hex;
+pub mod encoding;
+pub mod hex;
+pub(crate) mod key;
+pub(crate) mod lz;